@@ -6,20 +6,67 @@ psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" <<-E
     CREATE EXTENSION IF NOT EXISTS timescaledb CASCADE;
 
     -- Create tables
+    CREATE TABLE IF NOT EXISTS topics (
+        id BIGSERIAL PRIMARY KEY,
+        parent_id BIGINT REFERENCES topics (id),
+        segment TEXT NOT NULL,
+        UNIQUE NULLS NOT DISTINCT (parent_id, segment)
+    );
+
+    CREATE TABLE IF NOT EXISTS device_groups (
+        device_id TEXT PRIMARY KEY,
+        group_id TEXT NOT NULL
+    );
+
     CREATE TABLE IF NOT EXISTS sensor_readings (
         timestamp TIMESTAMPTZ NOT NULL,
         id SERIAL NOT NULL,
         device_id TEXT NOT NULL,
         topic TEXT NOT NULL,
         value DOUBLE PRECISION NOT NULL,
+        int_value BIGINT,
+        quality TEXT,
+        sensor_type TEXT,
+        unit TEXT,
+        accuracy DOUBLE PRECISION,
+        tags JSONB,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id BIGINT REFERENCES topics (id),
+        group_id TEXT,
         PRIMARY KEY (timestamp, id)
     );
 
+    -- COPY target for insert_mode = "staging", drained into sensor_readings
+    CREATE UNLOGGED TABLE IF NOT EXISTS sensor_readings_staging (
+        timestamp TIMESTAMPTZ NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        value DOUBLE PRECISION NOT NULL,
+        int_value BIGINT,
+        quality TEXT,
+        sensor_type TEXT,
+        unit TEXT,
+        accuracy DOUBLE PRECISION,
+        tags JSONB,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id BIGINT,
+        group_id TEXT
+    );
+
     CREATE TABLE IF NOT EXISTS socket_reads (
         timestamp TIMESTAMPTZ NOT NULL,
         id SERIAL NOT NULL,
         topic TEXT NOT NULL,
         payload TEXT NOT NULL,
+        parse_error TEXT,
+        qos SMALLINT NOT NULL DEFAULT 0,
+        retained BOOLEAN NOT NULL DEFAULT FALSE,
+        tags JSONB,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id BIGINT REFERENCES topics (id),
         PRIMARY KEY (timestamp, id)
     );
 
@@ -30,6 +77,10 @@ psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" <<-E
         level TEXT NOT NULL,
         message TEXT NOT NULL,
         topic TEXT NOT NULL,
+        tags JSONB,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id BIGINT REFERENCES topics (id),
         PRIMARY KEY (timestamp, id)
     );
 
@@ -42,6 +93,74 @@ psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" <<-E
         secondary_state INTEGER,
         alerts JSONB,
         rssi INTEGER,
+        state_reason TEXT,
+        tags JSONB,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id BIGINT REFERENCES topics (id),
+        PRIMARY KEY (timestamp, id)
+    );
+
+    -- Latest state per device, kept when device_states_current = true
+    CREATE TABLE IF NOT EXISTS device_states_current (
+        device_id TEXT PRIMARY KEY,
+        timestamp TIMESTAMPTZ NOT NULL,
+        topic TEXT NOT NULL,
+        main_state INTEGER,
+        secondary_state INTEGER,
+        alerts JSONB,
+        rssi INTEGER,
+        state_reason TEXT,
+        tags JSONB,
+        schema_version TEXT,
+        topic_id BIGINT REFERENCES topics (id)
+    );
+
+    -- Latest state and health per device, kept when device_snapshots = true
+    CREATE TABLE IF NOT EXISTS device_snapshots (
+        device_id TEXT PRIMARY KEY,
+        timestamp TIMESTAMPTZ NOT NULL,  -- latest state or health
+        topic TEXT NOT NULL,
+        main_state INTEGER,
+        secondary_state INTEGER,
+        alerts JSONB,
+        rssi INTEGER,
+        state_reason TEXT,
+        wifi_ssid TEXT,
+        free_heap_size BIGINT,
+        min_heap_size BIGINT,
+        unexpected_reset_counter INTEGER,
+        last_reset_reason TEXT,
+        wifi_connect_counter INTEGER,
+        cloud_connect_counter INTEGER,
+        last_wifi_connection_at TIMESTAMPTZ,
+        last_cloud_connection_at TIMESTAMPTZ,
+        clock_offset_seconds DOUBLE PRECISION,
+        topic_id BIGINT REFERENCES topics (id)
+    );
+
+    -- One row per device and announced capability, refreshed on each announcement
+    CREATE TABLE IF NOT EXISTS device_capabilities (
+        device_id TEXT NOT NULL,
+        capability TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        timestamp TIMESTAMPTZ NOT NULL,
+        PRIMARY KEY (device_id, capability)
+    );
+
+    CREATE TABLE IF NOT EXISTS device_alerts (
+        timestamp TIMESTAMPTZ NOT NULL,
+        id SERIAL NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        alert_key TEXT NOT NULL,
+        alert_value JSONB,
+        threshold DOUBLE PRECISION,
+        current DOUBLE PRECISION,
+        tags JSONB,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id BIGINT REFERENCES topics (id),
         PRIMARY KEY (timestamp, id)
     );
 
@@ -59,6 +178,70 @@ psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" <<-E
         cloud_connect_counter INTEGER,
         last_wifi_connection_ts BIGINT,
         last_cloud_connection_ts BIGINT,
+        last_wifi_connection_at TIMESTAMPTZ,
+        last_cloud_connection_at TIMESTAMPTZ,
+        raw_health JSONB,
+        clock_offset_seconds DOUBLE PRECISION,
+        tags JSONB,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id BIGINT REFERENCES topics (id),
+        PRIMARY KEY (timestamp, id)
+    );
+
+    CREATE TABLE IF NOT EXISTS task_health (
+        timestamp TIMESTAMPTZ NOT NULL,
+        id SERIAL NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        task_name TEXT NOT NULL,
+        stack_free BIGINT,
+        state TEXT,
+        tags JSONB,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id BIGINT REFERENCES topics (id),
+        PRIMARY KEY (timestamp, id)
+    );
+
+    CREATE TABLE IF NOT EXISTS offline_events (
+        timestamp TIMESTAMPTZ NOT NULL,
+        id SERIAL NOT NULL,
+        device_id TEXT NOT NULL,
+        last_seen TIMESTAMPTZ NOT NULL,
+        tags JSONB,
+        retention_class TEXT,
+        PRIMARY KEY (timestamp, id)
+    );
+
+    CREATE TABLE IF NOT EXISTS dead_letters (
+        timestamp TIMESTAMPTZ NOT NULL,
+        id SERIAL NOT NULL,
+        topic TEXT,
+        record TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        PRIMARY KEY (timestamp, id)
+    );
+
+    CREATE TABLE IF NOT EXISTS parse_errors (
+        timestamp TIMESTAMPTZ NOT NULL,
+        id SERIAL NOT NULL,
+        topic TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        PRIMARY KEY (timestamp, id)
+    );
+
+    CREATE TABLE IF NOT EXISTS reading_rollups (
+        timestamp TIMESTAMPTZ NOT NULL,
+        id SERIAL NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        window_secs INTEGER NOT NULL,
+        count BIGINT NOT NULL,
+        min DOUBLE PRECISION NOT NULL,
+        max DOUBLE PRECISION NOT NULL,
+        avg DOUBLE PRECISION NOT NULL,
         PRIMARY KEY (timestamp, id)
     );
 
@@ -67,7 +250,13 @@ psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" <<-E
     SELECT create_hypertable('socket_reads', 'timestamp', if_not_exists => TRUE);
     SELECT create_hypertable('device_logs', 'timestamp', if_not_exists => TRUE);
     SELECT create_hypertable('device_states', 'timestamp', if_not_exists => TRUE);
+    SELECT create_hypertable('device_alerts', 'timestamp', if_not_exists => TRUE);
     SELECT create_hypertable('device_health', 'timestamp', if_not_exists => TRUE);
+    SELECT create_hypertable('task_health', 'timestamp', if_not_exists => TRUE);
+    SELECT create_hypertable('offline_events', 'timestamp', if_not_exists => TRUE);
+    SELECT create_hypertable('dead_letters', 'timestamp', if_not_exists => TRUE);
+    SELECT create_hypertable('parse_errors', 'timestamp', if_not_exists => TRUE);
+    SELECT create_hypertable('reading_rollups', 'timestamp', if_not_exists => TRUE);
 
     -- Create indexes
     CREATE INDEX IF NOT EXISTS idx_sensor_readings_device_id ON sensor_readings (device_id);
@@ -75,7 +264,11 @@ psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" <<-E
     CREATE INDEX IF NOT EXISTS idx_device_logs_device_id ON device_logs (device_id);
     CREATE INDEX IF NOT EXISTS idx_device_logs_level ON device_logs (level);
     CREATE INDEX IF NOT EXISTS idx_device_states_device_id ON device_states (device_id);
+    CREATE INDEX IF NOT EXISTS idx_device_alerts_device_id ON device_alerts (device_id);
     CREATE INDEX IF NOT EXISTS idx_device_health_device_id ON device_health (device_id);
+    CREATE INDEX IF NOT EXISTS idx_task_health_device_id ON task_health (device_id);
+    CREATE INDEX IF NOT EXISTS idx_offline_events_device_id ON offline_events (device_id);
+    CREATE INDEX IF NOT EXISTS idx_reading_rollups_device_id ON reading_rollups (device_id);
 
     -- Configure proper authentication
     ALTER USER admin WITH PASSWORD 'admin';
@@ -1,48 +1,574 @@
-use anyhow::{Context, Result};
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
-use tokio_postgres::{Client, NoTls};
-use tracing::{debug, error};
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Runtime, Timeouts};
+use serde::{Serialize, Serializer};
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, Connection, NoTls};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::{debug, error, info, warn};
+
+use crate::config::DatabaseConfig;
+use crate::metrics::METRICS;
+use crate::parser::ParsedMessage;
+
+mod router;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+mod tls;
+
+pub use router::DbRouter;
+use tls::SslMode;
+
+/// Rows deleted per statement when pruning, to keep locks short
+const PRUNE_BATCH_SIZE: i64 = 10_000;
+
+/// How often the maintenance task applies retention
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Delay before the second reconnect attempt, doubled after each failure
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest delay between reconnect attempts
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 pub async fn connect(database_url: &str) -> Result<Client> {
-    let (client, connection) = tokio_postgres::connect(database_url, NoTls)
-        .await
-        .with_context(|| "Failed to connect to database")?;
+    open(database_url, SslMode::Disable, None).await
+}
+
+/// Connect over TLS per the connection string's `sslmode` (`disable`, `prefer`,
+/// `require` or `verify-full`), verifying the server against `ca_cert` instead
+/// of the public roots when given. Without `sslmode`, a `ca_cert` means
+/// `verify-full` and otherwise `prefer`.
+pub async fn connect_tls(database_url: &str, ca_cert: Option<&Path>) -> Result<Client> {
+    let (database_url, mode) = tls::take_sslmode(database_url)?;
+    open(&database_url, mode.unwrap_or(SslMode::default_for(ca_cert)), ca_cert).await
+}
+
+/// Connect over TLS when the URL sets `sslmode` or a CA bundle is given,
+/// otherwise in plain text
+async fn connect_url(database_url: &str, ca_cert: Option<&Path>) -> Result<Client> {
+    if ca_cert.is_some() || database_url.contains("sslmode=") {
+        connect_tls(database_url, ca_cert).await
+    } else {
+        connect(database_url).await
+    }
+}
+
+/// Open a pool of up to `max_size` connections, over TLS under the same rules
+/// as a single connection. Closed connections are replaced on checkout, and
+/// waiting for, opening or checking a connection fails after `timeout`.
+pub fn connect_pool(database_url: &str, ca_cert: Option<&Path>, max_size: usize, timeout: Duration) -> Result<Pool> {
+    let (database_url, mode) = if ca_cert.is_some() || database_url.contains("sslmode=") {
+        let (url, mode) = tls::take_sslmode(database_url)?;
+        (url, mode.unwrap_or(SslMode::default_for(ca_cert)))
+    } else {
+        (database_url.to_string(), SslMode::Disable)
+    };
+    let mut pg_config: tokio_postgres::Config = database_url.parse().with_context(|| "Invalid database URL")?;
+    let manager_config = ManagerConfig {
+        recycling_method: RecyclingMethod::Fast,
+    };
+
+    let manager = match mode {
+        SslMode::Disable => Manager::from_config(pg_config, NoTls, manager_config),
+        mode => {
+            pg_config.ssl_mode(if mode == SslMode::Prefer {
+                tokio_postgres::config::SslMode::Prefer
+            } else {
+                tokio_postgres::config::SslMode::Require
+            });
+            let tls = MakeRustlsConnect::new(tls::client_config(mode, ca_cert)?);
+            Manager::from_config(pg_config, tls, manager_config)
+        }
+    };
+
+    Pool::builder(manager)
+        .max_size(max_size.max(1))
+        .runtime(Runtime::Tokio1)
+        .timeouts(Timeouts {
+            wait: Some(timeout),
+            create: Some(timeout),
+            recycle: Some(timeout),
+        })
+        .build()
+        .with_context(|| "Failed to build database connection pool")
+}
+
+/// Runs statements on a raw client or a pooled connection, so records can be
+/// inserted through either
+pub trait Executor: Sync {
+    fn execute(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> impl Future<Output = Result<u64, tokio_postgres::Error>> + Send;
+}
+
+impl Executor for Client {
+    fn execute(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> impl Future<Output = Result<u64, tokio_postgres::Error>> + Send {
+        Client::execute(self, statement, params)
+    }
+}
+
+impl Executor for deadpool_postgres::Object {
+    fn execute(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> impl Future<Output = Result<u64, tokio_postgres::Error>> + Send {
+        Client::execute(self, statement, params)
+    }
+}
+
+/// A database client that re-establishes its connection once the connection
+/// task has exited, instead of failing every later query.
+///
+/// Reconnects wait 100ms doubling up to 30s between attempts, giving up after
+/// `max_retries` attempts until the next query tries again. With `pool_size`
+/// set, a connection pool for concurrent inserts is opened alongside.
+pub struct ReconnectingClient {
+    database_url: String,
+    ca_cert: Option<PathBuf>,
+    max_retries: u32,
+    client: Mutex<Arc<Client>>,
+    pool: Option<Pool>,
+}
+
+impl ReconnectingClient {
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self> {
+        Self::connect_to(&config.url, config).await
+    }
 
-    // Spawn the connection handler
+    /// Connect to another database with the configured CA bundle and reconnect limit
+    pub async fn connect_to(database_url: &str, config: &DatabaseConfig) -> Result<Self> {
+        let pool = match config.pool_size {
+            Some(max_size) => Some(connect_pool(
+                database_url,
+                config.ca_cert.as_deref(),
+                max_size,
+                Duration::from_millis(config.pool_timeout_ms),
+            )?),
+            None => None,
+        };
+
+        Ok(Self {
+            database_url: database_url.to_string(),
+            ca_cert: config.ca_cert.clone(),
+            max_retries: config.reconnect_max_retries,
+            client: Mutex::new(Arc::new(connect_url(database_url, config.ca_cert.as_deref()).await?)),
+            pool,
+        })
+    }
+
+    /// The connection pool for inserts, when one is configured
+    pub fn pool(&self) -> Option<&Pool> {
+        self.pool.as_ref()
+    }
+
+    /// The current client, reconnecting first if its connection has closed
+    pub async fn client(&self) -> Result<Arc<Client>> {
+        // Held across the reconnect so concurrent callers wait for one attempt
+        let mut current = self.client.lock().await;
+        if !current.is_closed() {
+            return Ok(current.clone());
+        }
+
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        for attempt in 1..=self.max_retries {
+            warn!(
+                "Database connection lost, reconnecting (attempt {}/{})",
+                attempt, self.max_retries
+            );
+
+            match connect_url(&self.database_url, self.ca_cert.as_deref()).await {
+                Ok(client) => {
+                    info!("Reconnected to database");
+                    *current = Arc::new(client);
+                    return Ok(current.clone());
+                }
+                Err(e) => warn!("Reconnect attempt {} failed: {:#}", attempt, e),
+            }
+
+            if attempt < self.max_retries {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+            }
+        }
+
+        bail!("Database connection lost; gave up after {} reconnect attempts", self.max_retries)
+    }
+
+    /// Run a query, retrying it once on a fresh connection if the connection
+    /// closed underneath it
+    pub async fn with_retry<T, F, Fut>(&self, query: F) -> Result<T>
+    where
+        F: Fn(Arc<Client>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let client = self.client().await?;
+        match query(client.clone()).await {
+            Err(e) if client.is_closed() => {
+                warn!("Database connection closed during query, retrying: {:#}", e);
+                query(self.client().await?).await
+            }
+            result => result,
+        }
+    }
+}
+
+async fn open(database_url: &str, mode: SslMode, ca_cert: Option<&Path>) -> Result<Client> {
+    let mut pg_config: tokio_postgres::Config = database_url.parse().with_context(|| "Invalid database URL")?;
+
+    let client = match mode {
+        SslMode::Disable => {
+            let (client, connection) = pg_config
+                .connect(NoTls)
+                .await
+                .with_context(|| "Failed to connect to database")?;
+            spawn_connection(connection);
+            client
+        }
+        mode => {
+            pg_config.ssl_mode(if mode == SslMode::Prefer {
+                tokio_postgres::config::SslMode::Prefer
+            } else {
+                tokio_postgres::config::SslMode::Require
+            });
+            let tls = MakeRustlsConnect::new(tls::client_config(mode, ca_cert)?);
+            let (client, connection) = pg_config
+                .connect(tls)
+                .await
+                .with_context(|| format!("Failed to connect to database with sslmode {:?}", mode))?;
+            spawn_connection(connection);
+            client
+        }
+    };
+
+    Ok(client)
+}
+
+/// Drive the connection in the background until it closes
+fn spawn_connection<S, T>(connection: Connection<S, T>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     tokio::spawn(async move {
         if let Err(e) = connection.await {
             error!("Database connection error: {}", e);
         }
     });
+}
 
-    Ok(client)
+/// Delete socket_reads rows older than the cutoff, returning the number deleted
+pub async fn prune_socket_reads(client: &Client, older_than: Duration) -> Result<u64> {
+    prune_older_than(client, "socket_reads", older_than).await
+}
+
+/// Delete dead letters older than the cutoff, returning the number deleted
+pub async fn prune_dead_letters(client: &Client, older_than: Duration) -> Result<u64> {
+    prune_older_than(client, "dead_letters", older_than).await
+}
+
+/// Drop the oldest dead letters beyond `max_rows`, returning the number deleted
+pub async fn cap_dead_letters(client: &Client, max_rows: u64) -> Result<u64> {
+    let max_rows = i64::try_from(max_rows).unwrap_or(i64::MAX);
+
+    let deleted = client
+        .execute(
+            "DELETE FROM dead_letters WHERE (timestamp, id) IN (SELECT timestamp, id FROM dead_letters ORDER BY timestamp DESC, id DESC OFFSET $1)",
+            &[&max_rows],
+        )
+        .await
+        .with_context(|| "Failed to cap dead_letters")?;
+
+    debug!("Dropped {} dead letters beyond the {} row cap", deleted, max_rows);
+
+    Ok(deleted)
+}
+
+/// Delete rows older than the cutoff in batches to avoid long-held locks
+async fn prune_older_than(client: &Client, table: &str, older_than: Duration) -> Result<u64> {
+    let cutoff = Utc::now()
+        - chrono::Duration::from_std(older_than).with_context(|| "Retention period out of range")?;
+
+    // Hypertable rows are addressed by their (timestamp, id) primary key
+    let statement = format!(
+        "DELETE FROM {table} WHERE (timestamp, id) IN (SELECT timestamp, id FROM {table} WHERE timestamp < $1 LIMIT $2)"
+    );
+
+    let mut total = 0;
+    loop {
+        let deleted = client
+            .execute(&statement, &[&cutoff, &PRUNE_BATCH_SIZE])
+            .await
+            .with_context(|| format!("Failed to prune {}", table))?;
+
+        total += deleted;
+        if deleted < PRUNE_BATCH_SIZE as u64 {
+            break;
+        }
+    }
+
+    debug!("Pruned {} rows from {} older than {}", total, table, cutoff);
+
+    Ok(total)
+}
+
+/// Retention applied by the maintenance task; unset entries are skipped
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    pub socket_reads: Option<Duration>,
+    pub dead_letters: Option<Duration>,
+    pub dead_letters_max_rows: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn from_config(config: &DatabaseConfig) -> Self {
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+
+        Self {
+            socket_reads: config.socket_reads_retention_hours.map(hours),
+            dead_letters: config.dead_letter_retention_hours.map(hours),
+            dead_letters_max_rows: config.dead_letter_max_rows,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.socket_reads.is_none() && self.dead_letters.is_none() && self.dead_letters_max_rows.is_none()
+    }
+}
+
+/// Periodically apply socket_reads and dead letter retention
+pub async fn run_maintenance(db: ReconnectingClient, retention: RetentionPolicy) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Some(older_than) = retention.socket_reads {
+            match db.with_retry(|client| async move { prune_socket_reads(&client, older_than).await }).await {
+                Ok(deleted) => info!("Pruned {} old socket reads", deleted),
+                Err(e) => error!("Socket reads maintenance failed: {}", e),
+            }
+        }
+
+        if let Some(older_than) = retention.dead_letters {
+            match db.with_retry(|client| async move { prune_dead_letters(&client, older_than).await }).await {
+                Ok(deleted) => info!("Pruned {} old dead letters", deleted),
+                Err(e) => error!("Dead letter maintenance failed: {}", e),
+            }
+        }
+
+        if let Some(max_rows) = retention.dead_letters_max_rows {
+            match db.with_retry(|client| async move { cap_dead_letters(&client, max_rows).await }).await {
+                Ok(deleted) => info!("Dropped {} dead letters over the cap", deleted),
+                Err(e) => error!("Dead letter maintenance failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Table name for a record, prefixed with the device class when routed per class
+/// (e.g., "pump" + "sensor_readings" → "pump_sensor_readings")
+pub fn class_table(base: &str, class: Option<&str>) -> String {
+    match class {
+        Some(class) => format!("{}_{}", sanitize_identifier(class), base),
+        None => base.to_string(),
+    }
+}
+
+/// Restrict a class name to a safe lowercase SQL identifier prefix
+fn sanitize_identifier(name: &str) -> String {
+    let sanitized: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    if sanitized.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        sanitized
+    } else {
+        format!("c_{}", sanitized)
+    }
+}
+
+/// Create a per-class copy of a base hypertable if it does not exist yet
+pub async fn ensure_class_table(client: &Client, base: &str, class: &str) -> Result<()> {
+    let table = class_table(base, Some(class));
+
+    client
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {table} (LIKE {base} INCLUDING ALL); \
+             SELECT create_hypertable('{table}', 'timestamp', if_not_exists => TRUE);"
+        ))
+        .await
+        .with_context(|| format!("Failed to create class table {}", table))?;
+
+    info!("Ensured class table {}", table);
+
+    Ok(())
+}
+
+/// Group of a device in the device_groups registry, None for unregistered devices
+pub async fn lookup_device_group(client: &Client, device_id: &str) -> Result<Option<String>> {
+    let row = client
+        .query_opt("SELECT group_id FROM device_groups WHERE device_id = $1", &[&device_id])
+        .await
+        .with_context(|| format!("Failed to look up the group of device {}", device_id))?;
+
+    Ok(row.and_then(|row| row.get(0)))
 }
 
-#[derive(Debug)]
+/// Upsert each segment of a topic into the topics hierarchy and return the id
+/// of the leaf, e.g. `a/b/c` links `c` under `b` under the root `a`
+pub async fn intern_topic(client: &Client, topic: &str) -> Result<i64> {
+    let mut parent_id: Option<i64> = None;
+
+    for segment in topic.split('/') {
+        // The no-op update makes RETURNING yield the id of an existing row too
+        let row = client
+            .query_one(
+                "INSERT INTO topics (parent_id, segment) VALUES ($1, $2) \
+                 ON CONFLICT (parent_id, segment) DO UPDATE SET segment = EXCLUDED.segment \
+                 RETURNING id",
+                &[&parent_id, &segment],
+            )
+            .await
+            .with_context(|| format!("Failed to intern topic {}", topic))?;
+        parent_id = Some(row.get(0));
+    }
+
+    parent_id.with_context(|| format!("Topic {} has no segments", topic))
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct SensorReading {
     pub device_id: String,
     pub topic: String,
     pub value: f64,
+    /// Exact value of readings the device declared as integers
+    pub int_value: Option<i64>,
+    pub quality: Option<String>,
+    /// Taxonomy class of the metric (e.g., "temperature")
+    pub sensor_type: Option<String>,
+    /// Unit the device reported the value in (e.g., "celsius")
+    pub unit: Option<String>,
+    /// Accuracy or confidence the device reported with the value (e.g., GPS hdop)
+    pub accuracy: Option<f64>,
+    pub tags: Option<serde_json::Value>,
+    pub retention_class: Option<String>,
+    /// Payload schema version the record was decoded from (e.g., "v2")
+    pub schema_version: Option<String>,
+    /// Leaf of the topics hierarchy the record was received on
+    pub topic_id: Option<i64>,
+    /// Group of the device in the device_groups registry
+    pub group_id: Option<String>,
+    /// Device class for per-class table routing (not stored as a column)
+    pub device_class: Option<String>,
     pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Debug)]
+impl SensorReading {
+    /// A reading with no optional metadata; set it with the `with_*` methods
+    pub fn new(device_id: String, topic: String, value: f64, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            device_id,
+            topic,
+            value,
+            int_value: None,
+            quality: None,
+            sensor_type: None,
+            unit: None,
+            accuracy: None,
+            tags: None,
+            retention_class: None,
+            schema_version: None,
+            topic_id: None,
+            group_id: None,
+            device_class: None,
+            timestamp,
+        }
+    }
+
+    pub fn with_int_value(mut self, int_value: Option<i64>) -> Self {
+        self.int_value = int_value;
+        self
+    }
+
+    pub fn with_quality(mut self, quality: Option<String>) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    pub fn with_unit(mut self, unit: Option<String>) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    pub fn with_accuracy(mut self, accuracy: Option<f64>) -> Self {
+        self.accuracy = accuracy;
+        self
+    }
+
+    pub fn with_tags(mut self, tags: Option<serde_json::Value>) -> Self {
+        self.tags = tags;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct SocketRead {
     pub topic: String,
     pub payload: String,
+    /// Why the payload could not be decoded, when that was recorded
+    pub parse_error: Option<String>,
+    /// MQTT delivery QoS level (0, 1 or 2)
+    pub qos: i16,
+    /// Whether the broker delivered a retained message rather than a live publish
+    pub retained: bool,
+    pub tags: Option<serde_json::Value>,
+    pub retention_class: Option<String>,
+    /// Payload schema version the record was decoded from (e.g., "v2")
+    pub schema_version: Option<String>,
+    /// Leaf of the topics hierarchy the record was received on
+    pub topic_id: Option<i64>,
     pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DeviceLog {
     pub device_id: String,
     pub level: String,
     pub message: String,
     pub topic: String,
+    pub tags: Option<serde_json::Value>,
+    pub retention_class: Option<String>,
+    /// Payload schema version the record was decoded from (e.g., "v2")
+    pub schema_version: Option<String>,
+    /// Leaf of the topics hierarchy the record was received on
+    pub topic_id: Option<i64>,
+    /// Device class for per-class table routing (not stored as a column)
+    pub device_class: Option<String>,
     pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DeviceState {
     pub device_id: String,
     pub topic: String,
@@ -50,10 +576,41 @@ pub struct DeviceState {
     pub secondary_state: Option<i32>,
     pub alerts: Option<serde_json::Value>,
     pub rssi: Option<i32>,
+    pub state_reason: Option<String>,
+    pub tags: Option<serde_json::Value>,
+    pub retention_class: Option<String>,
+    /// Payload schema version the record was decoded from (e.g., "v2")
+    pub schema_version: Option<String>,
+    /// Leaf of the topics hierarchy the record was received on
+    pub topic_id: Option<i64>,
+    /// Device class for per-class table routing (not stored as a column)
+    pub device_class: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// One active alert from a device state's `alerts`, with detail fields typed
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceAlert {
+    pub device_id: String,
+    pub topic: String,
+    /// Alert name (e.g., "overheat")
+    pub alert_key: String,
+    /// Alert value as reported, objects included
+    pub alert_value: serde_json::Value,
+    pub threshold: Option<f64>,
+    pub current: Option<f64>,
+    pub tags: Option<serde_json::Value>,
+    pub retention_class: Option<String>,
+    /// Payload schema version the record was decoded from (e.g., "v2")
+    pub schema_version: Option<String>,
+    /// Leaf of the topics hierarchy the record was received on
+    pub topic_id: Option<i64>,
+    /// Device class for per-class table routing (not stored as a column)
+    pub device_class: Option<String>,
     pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DeviceHealth {
     pub device_id: String,
     pub topic: String,
@@ -66,94 +623,652 @@ pub struct DeviceHealth {
     pub cloud_connect_counter: Option<i32>,
     pub last_wifi_connection_ts: Option<i64>,
     pub last_cloud_connection_ts: Option<i64>,
+    /// Absolute forms of the raw timestamps, for devices with a configured epoch
+    pub last_wifi_connection_at: Option<DateTime<Utc>>,
+    pub last_cloud_connection_at: Option<DateTime<Utc>>,
+    /// The full decoded health document, including sections and fields not
+    /// extracted above
+    pub raw_health: Option<serde_json::Value>,
+    /// Device-reported timestamp minus the time the message was received, for
+    /// spotting clock drift (positive when the device clock runs ahead)
+    pub clock_offset_seconds: Option<f64>,
+    pub tags: Option<serde_json::Value>,
+    pub retention_class: Option<String>,
+    /// Payload schema version the record was decoded from (e.g., "v2")
+    pub schema_version: Option<String>,
+    /// Leaf of the topics hierarchy the record was received on
+    pub topic_id: Option<i64>,
+    /// Device class for per-class table routing (not stored as a column)
+    pub device_class: Option<String>,
     pub timestamp: DateTime<Utc>,
 }
 
-impl SensorReading {
-    pub async fn insert(&self, client: &Client) -> Result<()> {
-        client
-            .execute(
-                "INSERT INTO sensor_readings (timestamp, device_id, topic, value) VALUES ($1, $2, $3, $4)",
-                &[&self.timestamp, &self.device_id, &self.topic, &self.value],
-            )
-            .await
-            .with_context(|| "Failed to insert sensor reading")?;
+/// A device's latest state and health combined into one row of
+/// device_snapshots, built from either record with the other's fields left empty
+#[derive(Debug, Clone)]
+pub struct DeviceSnapshot<'a> {
+    pub device_id: &'a str,
+    pub topic: &'a str,
+    pub main_state: Option<i32>,
+    pub secondary_state: Option<i32>,
+    pub alerts: Option<&'a serde_json::Value>,
+    pub rssi: Option<i32>,
+    pub state_reason: Option<&'a str>,
+    pub wifi_ssid: Option<&'a str>,
+    pub free_heap_size: Option<i64>,
+    pub min_heap_size: Option<i64>,
+    pub unexpected_reset_counter: Option<i32>,
+    pub last_reset_reason: Option<&'a str>,
+    pub wifi_connect_counter: Option<i32>,
+    pub cloud_connect_counter: Option<i32>,
+    pub last_wifi_connection_at: Option<DateTime<Utc>>,
+    pub last_cloud_connection_at: Option<DateTime<Utc>>,
+    pub clock_offset_seconds: Option<f64>,
+    pub topic_id: Option<i64>,
+    pub timestamp: DateTime<Utc>,
+}
 
-        debug!(
-            "Inserted sensor reading: device={}, topic={}, value={}",
-            self.device_id, self.topic, self.value
-        );
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskHealth {
+    pub device_id: String,
+    pub topic: String,
+    pub task_name: String,
+    pub stack_free: Option<i64>,
+    pub state: Option<String>,
+    pub tags: Option<serde_json::Value>,
+    pub retention_class: Option<String>,
+    /// Payload schema version the record was decoded from (e.g., "v2")
+    pub schema_version: Option<String>,
+    /// Leaf of the topics hierarchy the record was received on
+    pub topic_id: Option<i64>,
+    /// Device class for per-class table routing (not stored as a column)
+    pub device_class: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
 
-        Ok(())
+/// Emitted once when a device stops reporting for longer than the offline timeout
+#[derive(Debug, Clone, Serialize)]
+pub struct OfflineEvent {
+    pub device_id: String,
+    pub last_seen: DateTime<Utc>,
+    pub tags: Option<serde_json::Value>,
+    pub retention_class: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Statistics of one reading series over an aggregation window
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadingRollup {
+    pub device_id: String,
+    pub topic: String,
+    pub window_secs: i32,
+    pub count: i64,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    /// Start of the window
+    pub timestamp: DateTime<Utc>,
+}
+
+/// One capability a device announced (e.g., "relay" from `{"capabilities": ["temp", "relay"]}`)
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceCapability {
+    pub device_id: String,
+    pub capability: String,
+    pub topic: String,
+    /// When the capability was last announced
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Selected payload fields stored as typed columns of a configured table
+#[derive(Debug, Clone, Serialize)]
+pub struct FlattenedRow {
+    pub table: String,
+    pub device_id: String,
+    pub topic: String,
+    /// Column name and value, NULL when the source path was missing
+    pub columns: Vec<(String, ColumnValue)>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A payload that could not be decoded at all, kept for auditing what devices send
+#[derive(Debug, Clone, Serialize)]
+pub struct MalformedPayload {
+    pub topic: String,
+    /// Hex-encoded raw payload
+    pub payload: String,
+    pub reason: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Typed value for a flattened column
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum ColumnValue {
+    Float(Option<f64>),
+    Integer(Option<i64>),
+    Text(Option<String>),
+    Boolean(Option<bool>),
+    Json(Option<serde_json::Value>),
+}
+
+impl ColumnValue {
+    fn as_sql(&self) -> &dyn SqlParam {
+        match self {
+            ColumnValue::Float(value) => value,
+            ColumnValue::Integer(value) => value,
+            ColumnValue::Text(value) => value,
+            ColumnValue::Boolean(value) => value,
+            ColumnValue::Json(value) => value,
+        }
     }
 }
 
-impl SocketRead {
-    pub async fn insert(&self, client: &Client) -> Result<()> {
-        client
-            .execute(
-                "INSERT INTO socket_reads (timestamp, topic, payload) VALUES ($1, $2, $3)",
-                &[&self.timestamp, &self.topic, &self.payload],
-            )
-            .await
-            .with_context(|| "Failed to insert socket read")?;
+/// A record that could not be stored, or a payload that could not be parsed,
+/// kept for inspection
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub topic: Option<String>,
+    /// JSON of the rejected record, or the rejected payload
+    pub record: String,
+    pub reason: DeadLetterReason,
+    pub timestamp: DateTime<Utc>,
+}
 
-        debug!("Inserted socket read: topic={}", self.topic);
+impl DeadLetter {
+    /// A dead letter stamped now, counted under its reason
+    pub fn new(topic: Option<String>, record: String, reason: DeadLetterReason) -> Self {
+        METRICS.dead_letters.with_label_values(&[reason.name()]).inc();
+        Self {
+            topic,
+            record,
+            reason,
+            timestamp: Utc::now(),
+        }
+    }
+}
 
-        Ok(())
+/// Why a record or payload was dead-lettered. Stored in the reason column as
+/// its snake_case name, followed by `: <detail>` where there is one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeadLetterReason {
+    /// Payload is neither UTF-8 nor a configured binary format
+    InvalidUtf8,
+    /// Payload looked like JSON but failed to decode at this position
+    JsonError { line: usize, column: usize },
+    /// Payload decoded but yielded no records
+    NoMatch,
+    /// Payload rejected by a validation limit
+    ValidationFailed(String),
+    /// The device could not be identified under the `error` policy
+    MissingDeviceId,
+    /// The database rejected the record
+    InsertFailed(String),
+    /// The writer buffer was full under the `dead_letter` overflow policy
+    BufferFull,
+}
+
+impl DeadLetterReason {
+    /// snake_case name of the reason, without its detail
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeadLetterReason::InvalidUtf8 => "invalid_utf8",
+            DeadLetterReason::JsonError { .. } => "json_error",
+            DeadLetterReason::NoMatch => "no_match",
+            DeadLetterReason::ValidationFailed(_) => "validation_failed",
+            DeadLetterReason::MissingDeviceId => "missing_device_id",
+            DeadLetterReason::InsertFailed(_) => "insert_failed",
+            DeadLetterReason::BufferFull => "buffer_full",
+        }
     }
 }
 
-impl DeviceLog {
-    pub async fn insert(&self, client: &Client) -> Result<()> {
-        client
-            .execute(
-                "INSERT INTO device_logs (timestamp, device_id, level, message, topic) VALUES ($1, $2, $3, $4, $5)",
-                &[&self.timestamp, &self.device_id, &self.level, &self.message, &self.topic],
-            )
-            .await
-            .with_context(|| "Failed to insert device log")?;
+impl fmt::Display for DeadLetterReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())?;
+        match self {
+            DeadLetterReason::JsonError { line, column } => write!(f, ": line {}, column {}", line, column),
+            DeadLetterReason::ValidationFailed(detail) | DeadLetterReason::InsertFailed(detail) => {
+                write!(f, ": {}", detail)
+            }
+            _ => Ok(()),
+        }
+    }
+}
 
-        debug!(
-            "Inserted device log: device={}, level={}, message={}",
-            self.device_id, self.level, self.message
-        );
+impl Serialize for DeadLetterReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
-        Ok(())
+impl ToSql for DeadLetterReason {
+    fn to_sql(&self, ty: &Type, out: &mut bytes::BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.to_string().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <String as ToSql>::accepts(ty)
     }
+
+    to_sql_checked!();
 }
 
-impl DeviceState {
-    pub async fn insert(&self, client: &Client) -> Result<()> {
-        // Convert alerts to JSONB - use proper JSONB format
-        let alerts_json: Option<serde_json::Value> = self.alerts.clone();
+#[cfg(feature = "sqlite")]
+impl rusqlite::ToSql for DeadLetterReason {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(self.to_string().into())
+    }
+}
 
-        client
-            .execute(
-                "INSERT INTO device_states (timestamp, device_id, topic, main_state, secondary_state, alerts, rssi) VALUES ($1, $2, $3, $4, $5, $6::jsonb, $7)",
-                &[&self.timestamp, &self.device_id, &self.topic, &self.main_state, &self.secondary_state, &alerts_json, &self.rssi],
-            )
-            .await
-            .with_context(|| format!("Failed to insert device state for device {} - timestamp: {}, main_state: {:?}, secondary_state: {:?}", self.device_id, self.timestamp, self.main_state, self.secondary_state))?;
+/// Bind parameters per statement allowed by the PostgreSQL protocol
+const MAX_PARAMS: usize = 65535;
 
-        debug!(
-            "Inserted device state: device={}, main_state={:?}, rssi={:?}",
-            self.device_id, self.main_state, self.rssi
-        );
+/// A value bound into an insert, in the form each storage backend takes
+trait SqlParam: Sync {
+    fn postgres(&self) -> &(dyn ToSql + Sync);
 
-        Ok(())
+    #[cfg(feature = "sqlite")]
+    fn sqlite(&self) -> &dyn rusqlite::ToSql;
+}
+
+#[cfg(not(feature = "sqlite"))]
+impl<T: ToSql + Sync> SqlParam for T {
+    fn postgres(&self) -> &(dyn ToSql + Sync) {
+        self
     }
 }
 
-impl DeviceHealth {
-    pub async fn insert(&self, client: &Client) -> Result<()> {
-        client
+#[cfg(feature = "sqlite")]
+impl<T: ToSql + rusqlite::ToSql + Sync> SqlParam for T {
+    fn postgres(&self) -> &(dyn ToSql + Sync) {
+        self
+    }
+
+    fn sqlite(&self) -> &dyn rusqlite::ToSql {
+        self
+    }
+}
+
+/// A record's target table, column list and bound values
+struct Row<'a> {
+    table: String,
+    columns: Vec<String>,
+    params: Vec<&'a dyn SqlParam>,
+    /// `ON CONFLICT` clause making the insert an upsert
+    on_conflict: Option<&'static str>,
+}
+
+fn column_names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+/// An optional column and its value, or None when the value is unset so the
+/// column can be left out of the insert
+fn present<'a, T>(column: &str, value: &'a Option<T>) -> Option<(String, &'a dyn SqlParam)>
+where
+    Option<T>: SqlParam,
+{
+    value.is_some().then(|| (column.to_string(), value as &dyn SqlParam))
+}
+
+/// Insert rows sharing a table and column list with multi-row `INSERT` statements,
+/// chunked to stay under the parameter limit. Returns the number of rows written.
+async fn insert_rows(client: &impl Executor, rows: &[Row<'_>]) -> Result<u64> {
+    let Some(first) = rows.first() else {
+        return Ok(0);
+    };
+    let width = first.columns.len().max(1);
+    // An upsert may not touch the same row twice in one statement
+    let chunk_size = match first.on_conflict {
+        Some(_) => 1,
+        None => (MAX_PARAMS / width).max(1),
+    };
+
+    let mut total = 0;
+    for chunk in rows.chunks(chunk_size) {
+        let values: Vec<String> = (0..chunk.len())
+            .map(|row| {
+                let placeholders: Vec<String> = (1..=width).map(|i| format!("${}", row * width + i)).collect();
+                format!("({})", placeholders.join(", "))
+            })
+            .collect();
+        let params: Vec<&(dyn ToSql + Sync)> = chunk
+            .iter()
+            .flat_map(|row| row.params.iter().map(|param| param.postgres()))
+            .collect();
+
+        total += client
             .execute(
-                "INSERT INTO device_health (timestamp, device_id, topic, wifi_ssid, free_heap_size, min_heap_size, unexpected_reset_counter, last_reset_reason, wifi_connect_counter, cloud_connect_counter, last_wifi_connection_ts, last_cloud_connection_ts) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
-                &[&self.timestamp, &self.device_id, &self.topic, &self.wifi_ssid, &self.free_heap_size, &self.min_heap_size, &self.unexpected_reset_counter, &self.last_reset_reason, &self.wifi_connect_counter, &self.cloud_connect_counter, &self.last_wifi_connection_ts, &self.last_cloud_connection_ts],
+                &format!(
+                    "INSERT INTO {} ({}) VALUES {} {}",
+                    first.table,
+                    first.columns.join(", "),
+                    values.join(", "),
+                    first.on_conflict.unwrap_or_default()
+                ),
+                &params,
             )
             .await
-            .with_context(|| "Failed to insert device health")?;
+            .inspect_err(|_| METRICS.insert_failures.with_label_values(&[&first.table]).inc())
+            .with_context(|| format!("Failed to insert {} rows into {}", chunk.len(), first.table))?;
+    }
+
+    Ok(total)
+}
+
+/// Insert a mixed batch of records, one multi-row statement per target table.
+/// Returns the total number of rows written.
+pub async fn insert_batch(client: &impl Executor, records: &[ParsedMessage]) -> Result<u64> {
+    // Group by table and column list, keeping first-seen order
+    let mut groups: Vec<Vec<Row>> = Vec::new();
+    for row in records.iter().map(message_row) {
+        match groups
+            .iter_mut()
+            .find(|group| group[0].table == row.table && group[0].columns == row.columns)
+        {
+            Some(group) => group.push(row),
+            None => groups.push(vec![row]),
+        }
+    }
+
+    let mut total = 0;
+    for group in &groups {
+        total += insert_rows(client, group).await?;
+    }
+
+    debug!("Inserted batch of {} rows into {} tables", total, groups.len());
+
+    Ok(total)
+}
+
+/// Unlogged table sensor readings are copied into in staging mode
+const STAGING_TABLE: &str = "sensor_readings_staging";
+
+/// Columns shared by sensor_readings and its staging table, with their types
+const STAGED_COLUMNS: &[(&str, Type)] = &[
+    ("timestamp", Type::TIMESTAMPTZ),
+    ("device_id", Type::TEXT),
+    ("topic", Type::TEXT),
+    ("value", Type::FLOAT8),
+    ("int_value", Type::INT8),
+    ("quality", Type::TEXT),
+    ("sensor_type", Type::TEXT),
+    ("unit", Type::TEXT),
+    ("accuracy", Type::FLOAT8),
+    ("tags", Type::JSONB),
+    ("retention_class", Type::TEXT),
+    ("schema_version", Type::TEXT),
+    ("topic_id", Type::INT8),
+    ("group_id", Type::TEXT),
+];
+
+fn staged_column_list() -> String {
+    STAGED_COLUMNS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+}
+
+/// COPY the batch's sensor readings into the unlogged staging table, skipping
+/// other records. Returns the number of rows copied.
+pub async fn copy_to_staging(client: &Client, records: &[ParsedMessage]) -> Result<u64> {
+    let sink = client
+        .copy_in(&format!("COPY {} ({}) FROM STDIN BINARY", STAGING_TABLE, staged_column_list()))
+        .await
+        .with_context(|| format!("Failed to start COPY into {}", STAGING_TABLE))?;
+    let types: Vec<Type> = STAGED_COLUMNS.iter().map(|(_, ty)| ty.clone()).collect();
+    let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+
+    for message in records {
+        let ParsedMessage::SensorReading(r) = message else {
+            continue;
+        };
+        writer
+            .as_mut()
+            .write(&[&r.timestamp, &r.device_id, &r.topic, &r.value, &r.int_value, &r.quality, &r.sensor_type, &r.unit, &r.accuracy, &r.tags, &r.retention_class, &r.schema_version, &r.topic_id, &r.group_id])
+            .await
+            .with_context(|| format!("Failed to copy sensor reading into {}", STAGING_TABLE))?;
+    }
+
+    let copied = writer
+        .finish()
+        .await
+        .inspect_err(|_| METRICS.insert_failures.with_label_values(&[STAGING_TABLE]).inc())
+        .with_context(|| format!("Failed to finish COPY into {}", STAGING_TABLE))?;
+    debug!("Copied {} sensor readings into {}", copied, STAGING_TABLE);
+
+    Ok(copied)
+}
+
+/// Move staged readings into sensor_readings and empty the staging table. The
+/// exclusive lock holds off COPYs until the move commits, so no row is
+/// truncated without having been moved.
+pub async fn flush_staging(client: &Client) -> Result<()> {
+    let columns = staged_column_list();
+    let statements = format!(
+        "BEGIN;
+         LOCK TABLE {staging} IN ACCESS EXCLUSIVE MODE;
+         INSERT INTO sensor_readings ({columns}) SELECT {columns} FROM {staging};
+         TRUNCATE {staging};
+         COMMIT;",
+        staging = STAGING_TABLE,
+        columns = columns
+    );
+
+    if let Err(e) = client.batch_execute(&statements).await {
+        // A failed statement leaves the transaction open; end it so the connection stays usable
+        let _ = client.batch_execute("ROLLBACK").await;
+        METRICS.insert_failures.with_label_values(&["sensor_readings"]).inc();
+        return Err(e).with_context(|| format!("Failed to move staged readings out of {}", STAGING_TABLE));
+    }
+
+    Ok(())
+}
+
+fn message_row(message: &ParsedMessage) -> Row<'_> {
+    match message {
+        ParsedMessage::SensorReading(reading) => reading.row(),
+        ParsedMessage::SocketRead(read) => read.row(),
+        ParsedMessage::DeviceLog(log) => log.row(),
+        ParsedMessage::DeviceState(state) => state.row(),
+        ParsedMessage::DeviceAlert(alert) => alert.row(),
+        ParsedMessage::DeviceHealth(health) => health.row(),
+        ParsedMessage::TaskHealth(task) => task.row(),
+        ParsedMessage::OfflineEvent(event) => event.row(),
+        ParsedMessage::FlattenedRow(row) => row.row(),
+        ParsedMessage::ParseError(error) => error.row(),
+        ParsedMessage::DeadLetter(letter) => letter.row(),
+        ParsedMessage::ReadingRollup(rollup) => rollup.row(),
+        ParsedMessage::DeviceCapability(capability) => capability.row(),
+    }
+}
+
+impl SensorReading {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: class_table("sensor_readings", self.device_class.as_deref()),
+            columns: column_names(&["timestamp", "device_id", "topic", "value", "int_value", "quality", "sensor_type", "unit", "accuracy", "tags", "retention_class", "schema_version", "topic_id", "group_id"]),
+            params: vec![&self.timestamp, &self.device_id, &self.topic, &self.value, &self.int_value, &self.quality, &self.sensor_type, &self.unit, &self.accuracy, &self.tags, &self.retention_class, &self.schema_version, &self.topic_id, &self.group_id],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert sensor reading")?;
+
+        debug!(
+            "Inserted sensor reading: device={}, topic={}, value={}",
+            self.device_id, self.topic, self.value
+        );
+
+        Ok(())
+    }
+}
+
+impl SocketRead {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: "socket_reads".to_string(),
+            columns: column_names(&["timestamp", "topic", "payload", "parse_error", "qos", "retained", "tags", "retention_class", "schema_version", "topic_id"]),
+            params: vec![&self.timestamp, &self.topic, &self.payload, &self.parse_error, &self.qos, &self.retained, &self.tags, &self.retention_class, &self.schema_version, &self.topic_id],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert socket read")?;
+
+        debug!("Inserted socket read: topic={}", self.topic);
+
+        Ok(())
+    }
+}
+
+impl DeviceLog {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: class_table("device_logs", self.device_class.as_deref()),
+            columns: column_names(&["timestamp", "device_id", "level", "message", "topic", "tags", "retention_class", "schema_version", "topic_id"]),
+            params: vec![&self.timestamp, &self.device_id, &self.level, &self.message, &self.topic, &self.tags, &self.retention_class, &self.schema_version, &self.topic_id],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert device log")?;
+
+        debug!(
+            "Inserted device log: device={}, level={}, message={}",
+            self.device_id, self.level, self.message
+        );
+
+        Ok(())
+    }
+}
+
+impl DeviceState {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: class_table("device_states", self.device_class.as_deref()),
+            columns: column_names(&["timestamp", "device_id", "topic", "main_state", "secondary_state", "alerts", "rssi", "state_reason", "tags", "retention_class", "schema_version", "topic_id"]),
+            params: vec![&self.timestamp, &self.device_id, &self.topic, &self.main_state, &self.secondary_state, &self.alerts, &self.rssi, &self.state_reason, &self.tags, &self.retention_class, &self.schema_version, &self.topic_id],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| format!("Failed to insert device state for device {} - timestamp: {}, main_state: {:?}, secondary_state: {:?}", self.device_id, self.timestamp, self.main_state, self.secondary_state))?;
+
+        debug!(
+            "Inserted device state: device={}, main_state={:?}, rssi={:?}, reason={:?}",
+            self.device_id, self.main_state, self.rssi, self.state_reason
+        );
+
+        Ok(())
+    }
+
+    /// Make this the device's row in device_states_current unless a newer state
+    /// is already stored there, so late messages cannot overwrite fresher state
+    pub async fn upsert_current(&self, client: &Client) -> Result<()> {
+        client
+            .execute(
+                "INSERT INTO device_states_current (device_id, timestamp, topic, main_state, secondary_state, alerts, rssi, state_reason, tags, schema_version, topic_id)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                 ON CONFLICT (device_id) DO UPDATE SET
+                     timestamp = EXCLUDED.timestamp,
+                     topic = EXCLUDED.topic,
+                     main_state = EXCLUDED.main_state,
+                     secondary_state = EXCLUDED.secondary_state,
+                     alerts = EXCLUDED.alerts,
+                     rssi = EXCLUDED.rssi,
+                     state_reason = EXCLUDED.state_reason,
+                     tags = EXCLUDED.tags,
+                     schema_version = EXCLUDED.schema_version,
+                     topic_id = EXCLUDED.topic_id
+                 WHERE device_states_current.timestamp < EXCLUDED.timestamp",
+                &[&self.device_id, &self.timestamp, &self.topic, &self.main_state, &self.secondary_state, &self.alerts, &self.rssi, &self.state_reason, &self.tags, &self.schema_version, &self.topic_id],
+            )
+            .await
+            .inspect_err(|_| METRICS.insert_failures.with_label_values(&["device_states_current"]).inc())
+            .with_context(|| format!("Failed to upsert current state for device {}", self.device_id))?;
+
+        Ok(())
+    }
+}
+
+impl DeviceAlert {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: class_table("device_alerts", self.device_class.as_deref()),
+            columns: column_names(&["timestamp", "device_id", "topic", "alert_key", "alert_value", "threshold", "current", "tags", "retention_class", "schema_version", "topic_id"]),
+            params: vec![&self.timestamp, &self.device_id, &self.topic, &self.alert_key, &self.alert_value, &self.threshold, &self.current, &self.tags, &self.retention_class, &self.schema_version, &self.topic_id],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert device alert")?;
+
+        debug!(
+            "Inserted device alert: device={}, key={}, current={:?}, threshold={:?}",
+            self.device_id, self.alert_key, self.current, self.threshold
+        );
+
+        Ok(())
+    }
+}
+
+impl DeviceHealth {
+    /// Only the fields that are set become columns, so a health table that gained
+    /// or lost optional columns keeps accepting rows that do not use them
+    fn row(&self) -> Row<'_> {
+        let mut columns = column_names(&["timestamp", "device_id", "topic"]);
+        let mut params: Vec<&dyn SqlParam> = vec![&self.timestamp, &self.device_id, &self.topic];
+
+        let optional = [
+            present("wifi_ssid", &self.wifi_ssid),
+            present("free_heap_size", &self.free_heap_size),
+            present("min_heap_size", &self.min_heap_size),
+            present("unexpected_reset_counter", &self.unexpected_reset_counter),
+            present("last_reset_reason", &self.last_reset_reason),
+            present("wifi_connect_counter", &self.wifi_connect_counter),
+            present("cloud_connect_counter", &self.cloud_connect_counter),
+            present("last_wifi_connection_ts", &self.last_wifi_connection_ts),
+            present("last_cloud_connection_ts", &self.last_cloud_connection_ts),
+            present("last_wifi_connection_at", &self.last_wifi_connection_at),
+            present("last_cloud_connection_at", &self.last_cloud_connection_at),
+            present("raw_health", &self.raw_health),
+            present("clock_offset_seconds", &self.clock_offset_seconds),
+            present("tags", &self.tags),
+            present("retention_class", &self.retention_class),
+            present("schema_version", &self.schema_version),
+            present("topic_id", &self.topic_id),
+        ];
+        for (column, param) in optional.into_iter().flatten() {
+            columns.push(column);
+            params.push(param);
+        }
+
+        Row {
+            table: class_table("device_health", self.device_class.as_deref()),
+            columns,
+            params,
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert device health")?;
 
         debug!(
             "Inserted device health: device={}, free_heap={:?}, reset_counter={:?}",
@@ -163,3 +1278,722 @@ impl DeviceHealth {
         Ok(())
     }
 }
+
+impl<'a> From<&'a DeviceState> for DeviceSnapshot<'a> {
+    fn from(state: &'a DeviceState) -> Self {
+        Self {
+            device_id: &state.device_id,
+            topic: &state.topic,
+            main_state: state.main_state,
+            secondary_state: state.secondary_state,
+            alerts: state.alerts.as_ref(),
+            rssi: state.rssi,
+            state_reason: state.state_reason.as_deref(),
+            wifi_ssid: None,
+            free_heap_size: None,
+            min_heap_size: None,
+            unexpected_reset_counter: None,
+            last_reset_reason: None,
+            wifi_connect_counter: None,
+            cloud_connect_counter: None,
+            last_wifi_connection_at: None,
+            last_cloud_connection_at: None,
+            clock_offset_seconds: None,
+            topic_id: state.topic_id,
+            timestamp: state.timestamp,
+        }
+    }
+}
+
+impl<'a> From<&'a DeviceHealth> for DeviceSnapshot<'a> {
+    fn from(health: &'a DeviceHealth) -> Self {
+        Self {
+            device_id: &health.device_id,
+            topic: &health.topic,
+            main_state: None,
+            secondary_state: None,
+            alerts: None,
+            rssi: None,
+            state_reason: None,
+            wifi_ssid: health.wifi_ssid.as_deref(),
+            free_heap_size: health.free_heap_size,
+            min_heap_size: health.min_heap_size,
+            unexpected_reset_counter: health.unexpected_reset_counter,
+            last_reset_reason: health.last_reset_reason.as_deref(),
+            wifi_connect_counter: health.wifi_connect_counter,
+            cloud_connect_counter: health.cloud_connect_counter,
+            last_wifi_connection_at: health.last_wifi_connection_at,
+            last_cloud_connection_at: health.last_cloud_connection_at,
+            clock_offset_seconds: health.clock_offset_seconds,
+            topic_id: health.topic_id,
+            timestamp: health.timestamp,
+        }
+    }
+}
+
+impl DeviceSnapshot<'_> {
+    /// Merge into the device's row in device_snapshots. Only the fields this
+    /// snapshot carries are overwritten, so a state message keeps the stored
+    /// health fields and the other way round; snapshots older than the stored
+    /// row are ignored.
+    pub async fn upsert(&self, client: &Client) -> Result<()> {
+        client
+            .execute(
+                "INSERT INTO device_snapshots (device_id, timestamp, topic, main_state, secondary_state, alerts, rssi, state_reason, wifi_ssid, free_heap_size, min_heap_size, unexpected_reset_counter, last_reset_reason, wifi_connect_counter, cloud_connect_counter, last_wifi_connection_at, last_cloud_connection_at, clock_offset_seconds, topic_id)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)
+                 ON CONFLICT (device_id) DO UPDATE SET
+                     timestamp = EXCLUDED.timestamp,
+                     topic = EXCLUDED.topic,
+                     main_state = COALESCE(EXCLUDED.main_state, device_snapshots.main_state),
+                     secondary_state = COALESCE(EXCLUDED.secondary_state, device_snapshots.secondary_state),
+                     alerts = COALESCE(EXCLUDED.alerts, device_snapshots.alerts),
+                     rssi = COALESCE(EXCLUDED.rssi, device_snapshots.rssi),
+                     state_reason = COALESCE(EXCLUDED.state_reason, device_snapshots.state_reason),
+                     wifi_ssid = COALESCE(EXCLUDED.wifi_ssid, device_snapshots.wifi_ssid),
+                     free_heap_size = COALESCE(EXCLUDED.free_heap_size, device_snapshots.free_heap_size),
+                     min_heap_size = COALESCE(EXCLUDED.min_heap_size, device_snapshots.min_heap_size),
+                     unexpected_reset_counter = COALESCE(EXCLUDED.unexpected_reset_counter, device_snapshots.unexpected_reset_counter),
+                     last_reset_reason = COALESCE(EXCLUDED.last_reset_reason, device_snapshots.last_reset_reason),
+                     wifi_connect_counter = COALESCE(EXCLUDED.wifi_connect_counter, device_snapshots.wifi_connect_counter),
+                     cloud_connect_counter = COALESCE(EXCLUDED.cloud_connect_counter, device_snapshots.cloud_connect_counter),
+                     last_wifi_connection_at = COALESCE(EXCLUDED.last_wifi_connection_at, device_snapshots.last_wifi_connection_at),
+                     last_cloud_connection_at = COALESCE(EXCLUDED.last_cloud_connection_at, device_snapshots.last_cloud_connection_at),
+                     clock_offset_seconds = COALESCE(EXCLUDED.clock_offset_seconds, device_snapshots.clock_offset_seconds),
+                     topic_id = COALESCE(EXCLUDED.topic_id, device_snapshots.topic_id)
+                 WHERE EXCLUDED.timestamp >= device_snapshots.timestamp",
+                &[&self.device_id, &self.timestamp, &self.topic, &self.main_state, &self.secondary_state, &self.alerts, &self.rssi, &self.state_reason, &self.wifi_ssid, &self.free_heap_size, &self.min_heap_size, &self.unexpected_reset_counter, &self.last_reset_reason, &self.wifi_connect_counter, &self.cloud_connect_counter, &self.last_wifi_connection_at, &self.last_cloud_connection_at, &self.clock_offset_seconds, &self.topic_id],
+            )
+            .await
+            .inspect_err(|_| METRICS.insert_failures.with_label_values(&["device_snapshots"]).inc())
+            .with_context(|| format!("Failed to upsert snapshot for device {}", self.device_id))?;
+
+        Ok(())
+    }
+}
+
+impl TaskHealth {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: class_table("task_health", self.device_class.as_deref()),
+            columns: column_names(&["timestamp", "device_id", "topic", "task_name", "stack_free", "state", "tags", "retention_class", "schema_version", "topic_id"]),
+            params: vec![&self.timestamp, &self.device_id, &self.topic, &self.task_name, &self.stack_free, &self.state, &self.tags, &self.retention_class, &self.schema_version, &self.topic_id],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert task health")?;
+
+        debug!(
+            "Inserted task health: device={}, task={}, stack_free={:?}",
+            self.device_id, self.task_name, self.stack_free
+        );
+
+        Ok(())
+    }
+}
+
+impl OfflineEvent {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: "offline_events".to_string(),
+            columns: column_names(&["timestamp", "device_id", "last_seen", "tags", "retention_class"]),
+            params: vec![&self.timestamp, &self.device_id, &self.last_seen, &self.tags, &self.retention_class],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert offline event")?;
+
+        debug!(
+            "Inserted offline event: device={}, last_seen={}",
+            self.device_id, self.last_seen
+        );
+
+        Ok(())
+    }
+}
+
+impl ReadingRollup {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: "reading_rollups".to_string(),
+            columns: column_names(&["timestamp", "device_id", "topic", "window_secs", "count", "min", "max", "avg"]),
+            params: vec![&self.timestamp, &self.device_id, &self.topic, &self.window_secs, &self.count, &self.min, &self.max, &self.avg],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert reading rollup")?;
+
+        debug!(
+            "Inserted reading rollup: device={}, topic={}, window={}, count={}",
+            self.device_id, self.topic, self.timestamp, self.count
+        );
+
+        Ok(())
+    }
+}
+
+impl DeviceCapability {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: "device_capabilities".to_string(),
+            columns: column_names(&["timestamp", "device_id", "capability", "topic"]),
+            params: vec![&self.timestamp, &self.device_id, &self.capability, &self.topic],
+            on_conflict: Some(
+                "ON CONFLICT (device_id, capability) DO UPDATE SET timestamp = EXCLUDED.timestamp, topic = EXCLUDED.topic
+                 WHERE device_capabilities.timestamp < EXCLUDED.timestamp",
+            ),
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to upsert device capability")?;
+
+        debug!("Upserted device capability: device={}, capability={}", self.device_id, self.capability);
+
+        Ok(())
+    }
+}
+
+impl FlattenedRow {
+    fn row(&self) -> Row<'_> {
+        let mut columns = column_names(&["timestamp", "device_id", "topic"]);
+        columns.extend(self.columns.iter().map(|(column, _)| quote_identifier(column)));
+
+        let mut params: Vec<&dyn SqlParam> = vec![&self.timestamp, &self.device_id, &self.topic];
+        params.extend(self.columns.iter().map(|(_, value)| value.as_sql()));
+
+        Row {
+            table: quote_identifier(&self.table),
+            columns,
+            params,
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| format!("Failed to insert flattened row into {}", self.table))?;
+
+        debug!(
+            "Inserted flattened row: table={}, device={}, topic={}",
+            self.table, self.device_id, self.topic
+        );
+
+        Ok(())
+    }
+}
+
+impl MalformedPayload {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: "parse_errors".to_string(),
+            columns: column_names(&["timestamp", "topic", "payload", "reason"]),
+            params: vec![&self.timestamp, &self.topic, &self.payload, &self.reason],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert parse error")?;
+
+        debug!("Inserted parse error: topic={}, reason={}", self.topic, self.reason);
+
+        Ok(())
+    }
+}
+
+/// Quote a configured table or column name as a SQL identifier
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+impl DeadLetter {
+    fn row(&self) -> Row<'_> {
+        Row {
+            table: "dead_letters".to_string(),
+            columns: column_names(&["timestamp", "topic", "record", "reason"]),
+            params: vec![&self.timestamp, &self.topic, &self.record, &self.reason],
+            on_conflict: None,
+        }
+    }
+
+    pub async fn insert(&self, client: &impl Executor) -> Result<()> {
+        insert_rows(client, &[self.row()])
+            .await
+            .with_context(|| "Failed to insert dead letter")?;
+
+        debug!("Inserted dead letter: topic={:?}, reason={}", self.topic, self.reason);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Docker init script whose heredoc holds the bridge schema
+    const INIT_SCRIPT: &str = include_str!("../../docker/postgres-init/init-db.sh");
+
+    /// Connect to `DESMO_TEST_DATABASE_URL` with a fresh `schema` on the search path
+    /// holding the bridge tables as plain tables (TimescaleDB and the Docker role
+    /// are not required)
+    async fn test_client(schema: &str) -> Client {
+        let url = std::env::var("DESMO_TEST_DATABASE_URL").expect("DESMO_TEST_DATABASE_URL is set");
+        let client = connect(&url).await.expect("test database is reachable");
+
+        let tables = INIT_SCRIPT
+            .split("<<-EOSQL")
+            .nth(1)
+            .and_then(|rest| rest.split("\nEOSQL").next())
+            .expect("init script holds the schema")
+            .lines()
+            .filter(|line| !["timescaledb", "create_hypertable", "ALTER USER"].iter().any(|skip| line.contains(skip)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}; SET search_path TO {schema}"
+            ))
+            .await
+            .expect("test schema is created");
+        client.batch_execute(&tables).await.expect("bridge tables are created");
+
+        client
+    }
+
+    async fn count(client: &Client, query: &str) -> i64 {
+        client.query_one(query, &[]).await.expect("count query succeeds").get(0)
+    }
+
+    fn socket_read(payload: &str, timestamp: DateTime<Utc>) -> SocketRead {
+        SocketRead {
+            topic: "test/prune".to_string(),
+            payload: payload.to_string(),
+            parse_error: None,
+            qos: 0,
+            retained: false,
+            tags: None,
+            retention_class: None,
+            schema_version: None,
+            topic_id: None,
+            timestamp,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn prune_socket_reads_deletes_only_old_rows() {
+        let client = test_client("desmo_test_prune_socket_reads").await;
+        socket_read("old", Utc::now() - chrono::Duration::days(10)).insert(&client).await.unwrap();
+        socket_read("new", Utc::now()).insert(&client).await.unwrap();
+
+        let deleted = prune_socket_reads(&client, Duration::from_secs(86_400)).await.unwrap();
+
+        assert_eq!(deleted, 1);
+        assert_eq!(count(&client, "SELECT count(*) FROM socket_reads WHERE payload = 'new'").await, 1);
+        assert_eq!(count(&client, "SELECT count(*) FROM socket_reads").await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn reading_tags_are_stored() {
+        let client = test_client("desmo_test_reading_tags").await;
+        let tags = serde_json::json!({"region": "eu-west", "env": "prod"});
+        SensorReading::new("d1".to_string(), "sensors/d1/temp".to_string(), 21.5, Utc::now())
+            .with_tags(Some(tags.clone()))
+            .insert(&client)
+            .await
+            .unwrap();
+
+        let row = client.query_one("SELECT tags FROM sensor_readings", &[]).await.unwrap();
+        assert_eq!(row.get::<_, Option<serde_json::Value>>(0), Some(tags));
+    }
+
+    fn dead_letter(record: &str, timestamp: DateTime<Utc>) -> DeadLetter {
+        DeadLetter {
+            timestamp,
+            ..DeadLetter::new(Some("test/dead".to_string()), record.to_string(), DeadLetterReason::NoMatch)
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn old_dead_letters_are_pruned() {
+        let client = test_client("desmo_test_prune_dead_letters").await;
+        dead_letter("old", Utc::now() - chrono::Duration::days(10)).insert(&client).await.unwrap();
+        dead_letter("new", Utc::now()).insert(&client).await.unwrap();
+
+        let deleted = prune_dead_letters(&client, Duration::from_secs(86_400)).await.unwrap();
+
+        assert_eq!(deleted, 1);
+        assert_eq!(count(&client, "SELECT count(*) FROM dead_letters WHERE record = 'new'").await, 1);
+        assert_eq!(count(&client, "SELECT count(*) FROM dead_letters").await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn dead_letter_cap_drops_the_oldest() {
+        let client = test_client("desmo_test_cap_dead_letters").await;
+        for (record, age) in [("oldest", 3), ("older", 2), ("newest", 1)] {
+            dead_letter(record, Utc::now() - chrono::Duration::hours(age)).insert(&client).await.unwrap();
+        }
+
+        let deleted = cap_dead_letters(&client, 2).await.unwrap();
+
+        assert_eq!(deleted, 1);
+        assert_eq!(count(&client, "SELECT count(*) FROM dead_letters WHERE record = 'oldest'").await, 0);
+        assert_eq!(count(&client, "SELECT count(*) FROM dead_letters").await, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn int_typed_reading_is_stored_in_the_integer_column() {
+        let client = test_client("desmo_test_int_readings").await;
+        SensorReading::new("d1".to_string(), "sensors/d1/count".to_string(), 42.0, Utc::now())
+            .with_int_value(Some(42))
+            .insert(&client)
+            .await
+            .unwrap();
+
+        let row = client.query_one("SELECT int_value FROM sensor_readings", &[]).await.unwrap();
+        assert_eq!(row.get::<_, Option<i64>>(0), Some(42));
+    }
+
+    fn reading(device_id: &str, value: f64, timestamp: DateTime<Utc>) -> SensorReading {
+        SensorReading::new(device_id.to_string(), format!("sensors/{}/temp", device_id), value, timestamp)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn mixed_batch_is_inserted_into_each_table() {
+        let client = test_client("desmo_test_insert_batch").await;
+        let now = Utc::now();
+        let batch = vec![
+            ParsedMessage::SensorReading(reading("d1", 21.5, now)),
+            ParsedMessage::SocketRead(socket_read("raw", now)),
+            ParsedMessage::SensorReading(reading("d2", 22.5, now)),
+        ];
+
+        let written = insert_batch(&client, &batch).await.unwrap();
+
+        assert_eq!(written, 3);
+        assert_eq!(count(&client, "SELECT count(*) FROM sensor_readings").await, 2);
+        assert_eq!(count(&client, "SELECT count(*) FROM socket_reads").await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn topic_segments_are_interned_as_linked_rows() {
+        let client = test_client("desmo_test_intern_topic").await;
+
+        let leaf = intern_topic(&client, "a/b/c").await.unwrap();
+
+        let path: String = client
+            .query_one(
+                "WITH RECURSIVE path AS (SELECT id, parent_id, segment FROM topics WHERE id = $1 \
+                 UNION ALL SELECT t.id, t.parent_id, t.segment FROM topics t JOIN path p ON t.id = p.parent_id) \
+                 SELECT string_agg(segment, '/' ORDER BY id) FROM path",
+                &[&leaf],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(path, "a/b/c");
+        assert_eq!(count(&client, "SELECT count(*) FROM topics").await, 3);
+
+        assert_eq!(intern_topic(&client, "a/b/c").await.unwrap(), leaf);
+        intern_topic(&client, "a/b/d").await.unwrap();
+        assert_eq!(count(&client, "SELECT count(*) FROM topics").await, 4);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn dropped_connection_is_reconnected() {
+        let url = std::env::var("DESMO_TEST_DATABASE_URL").expect("DESMO_TEST_DATABASE_URL is set");
+        let config: DatabaseConfig = toml::from_str(&format!("url = {:?}", url)).unwrap();
+        let db = ReconnectingClient::connect(&config).await.unwrap();
+        let backend_pid = || async {
+            db.with_retry(|client| async move {
+                let row = client.query_one("SELECT pg_backend_pid()", &[]).await?;
+                Ok(row.get::<_, i32>(0))
+            })
+            .await
+        };
+        let first = backend_pid().await.unwrap();
+
+        let admin = connect(&url).await.unwrap();
+        admin.execute("SELECT pg_terminate_backend($1)", &[&first]).await.unwrap();
+
+        let second = backend_pid().await.unwrap();
+        assert_ne!(first, second);
+    }
+
+    fn sparse_health() -> DeviceHealth {
+        DeviceHealth {
+            device_id: "d1".to_string(),
+            topic: "devices/d1/state".to_string(),
+            wifi_ssid: None,
+            free_heap_size: Some(57_940),
+            min_heap_size: None,
+            unexpected_reset_counter: Some(2),
+            last_reset_reason: None,
+            wifi_connect_counter: None,
+            cloud_connect_counter: None,
+            last_wifi_connection_ts: None,
+            last_cloud_connection_ts: None,
+            last_wifi_connection_at: None,
+            last_cloud_connection_at: None,
+            raw_health: None,
+            clock_offset_seconds: None,
+            tags: None,
+            retention_class: None,
+            schema_version: None,
+            topic_id: None,
+            device_class: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn health_insert_names_only_the_fields_that_are_set() {
+        let health = sparse_health();
+
+        let row = health.row();
+
+        assert_eq!(row.columns, ["timestamp", "device_id", "topic", "free_heap_size", "unexpected_reset_counter"]);
+        assert_eq!(row.params.len(), row.columns.len());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn sparse_health_inserts_without_the_unset_columns() {
+        let client = test_client("desmo_test_sparse_health").await;
+        // An older schema lacking columns this row leaves unset still accepts it
+        client
+            .batch_execute("ALTER TABLE device_health DROP COLUMN raw_health, DROP COLUMN clock_offset_seconds")
+            .await
+            .unwrap();
+
+        sparse_health().insert(&client).await.unwrap();
+
+        let row = client
+            .query_one("SELECT free_heap_size, unexpected_reset_counter, wifi_ssid FROM device_health", &[])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, Option<i64>>(0), Some(57_940));
+        assert_eq!(row.get::<_, Option<i32>>(1), Some(2));
+        assert_eq!(row.get::<_, Option<String>>(2), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn device_alert_is_stored_with_its_key_and_value() {
+        let client = test_client("desmo_test_device_alerts").await;
+        let alert = DeviceAlert {
+            device_id: "d1".to_string(),
+            topic: "devices/d1/state".to_string(),
+            alert_key: "overheat".to_string(),
+            alert_value: serde_json::json!({"threshold": 80, "current": 92}),
+            threshold: Some(80.0),
+            current: Some(92.0),
+            tags: None,
+            retention_class: None,
+            schema_version: None,
+            topic_id: None,
+            device_class: None,
+            timestamp: Utc::now(),
+        };
+        alert.insert(&client).await.unwrap();
+
+        let row = client.query_one("SELECT alert_key, alert_value FROM device_alerts", &[]).await.unwrap();
+        assert_eq!(row.get::<_, String>(0), "overheat");
+        assert_eq!(row.get::<_, serde_json::Value>(1), alert.alert_value);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn known_device_readings_carry_the_group_id() {
+        let client = test_client("desmo_test_device_groups").await;
+        client
+            .batch_execute("INSERT INTO device_groups (device_id, group_id) VALUES ('d1', 'fleet-a')")
+            .await
+            .unwrap();
+
+        let group_id = lookup_device_group(&client, "d1").await.unwrap();
+        assert_eq!(group_id.as_deref(), Some("fleet-a"));
+        assert_eq!(lookup_device_group(&client, "unknown").await.unwrap(), None);
+
+        let mut reading = SensorReading::new("d1".to_string(), "sensors/d1/temp".to_string(), 21.5, Utc::now());
+        reading.group_id = group_id;
+        reading.insert(&client).await.unwrap();
+
+        let row = client.query_one("SELECT group_id FROM sensor_readings", &[]).await.unwrap();
+        assert_eq!(row.get::<_, Option<String>>(0).as_deref(), Some("fleet-a"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn tenant_readings_are_routed_to_their_databases() {
+        let url = std::env::var("DESMO_TEST_DATABASE_URL").expect("DESMO_TEST_DATABASE_URL is set");
+        let tenant_a = test_client("desmo_test_tenant_a").await;
+        let tenant_b = test_client("desmo_test_tenant_b").await;
+
+        // Each route is the same server with a different schema on its search path
+        let config: DatabaseConfig = toml::from_str(&format!(
+            r#"
+            url = {url:?}
+
+            [[routes]]
+            name = "tenant-a"
+            url = "{url}?options=-csearch_path%3Ddesmo_test_tenant_a"
+            topics = ["tenants/a/#"]
+
+            [[routes]]
+            name = "tenant-b"
+            url = "{url}?options=-csearch_path%3Ddesmo_test_tenant_b"
+            topics = ["tenants/b/#"]
+            "#
+        ))
+        .unwrap();
+        let router = DbRouter::connect(&config).await.unwrap();
+
+        for topic in ["tenants/a/d1/temp", "tenants/b/d2/temp", "tenants/b/d3/temp"] {
+            let route = router.route(Some(topic));
+            let reading = SensorReading::new("d1".to_string(), topic.to_string(), 21.5, Utc::now());
+            let reading = &reading;
+            router
+                .client(route)
+                .with_retry(|client| async move { reading.insert(&*client).await })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(router.name(router.route(Some("tenants/a/d1/temp"))), "tenant-a");
+        assert_eq!(router.name(router.route(Some("other/d1/temp"))), "default");
+        assert_eq!(count(&tenant_a, "SELECT count(*) FROM sensor_readings").await, 1);
+        assert_eq!(count(&tenant_b, "SELECT count(*) FROM sensor_readings").await, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn staged_readings_move_to_sensor_readings_on_flush() {
+        let client = test_client("desmo_test_staging").await;
+        let records: Vec<_> = [21.5, 22.0]
+            .into_iter()
+            .map(|value| {
+                let reading = SensorReading::new("d1".to_string(), "sensors/d1/temp".to_string(), value, Utc::now());
+                ParsedMessage::SensorReading(reading)
+            })
+            .collect();
+
+        assert_eq!(copy_to_staging(&client, &records).await.unwrap(), 2);
+        assert_eq!(count(&client, "SELECT count(*) FROM sensor_readings_staging").await, 2);
+        assert_eq!(count(&client, "SELECT count(*) FROM sensor_readings").await, 0);
+
+        flush_staging(&client).await.unwrap();
+
+        assert_eq!(count(&client, "SELECT count(*) FROM sensor_readings_staging").await, 0);
+        assert_eq!(count(&client, "SELECT count(*) FROM sensor_readings").await, 2);
+    }
+
+    fn device_state(main_state: i32, timestamp: DateTime<Utc>) -> DeviceState {
+        DeviceState {
+            device_id: "d1".to_string(),
+            topic: "devices/d1/state".to_string(),
+            main_state: Some(main_state),
+            secondary_state: None,
+            alerts: None,
+            rssi: None,
+            state_reason: None,
+            tags: None,
+            retention_class: None,
+            schema_version: None,
+            topic_id: None,
+            device_class: None,
+            timestamp,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn current_state_is_only_replaced_by_newer_states() {
+        let client = test_client("desmo_test_states_current").await;
+        let now = Utc::now();
+
+        device_state(1, now).upsert_current(&client).await.unwrap();
+        device_state(2, now - chrono::Duration::minutes(5)).upsert_current(&client).await.unwrap();
+        let current = || async { client.query_one("SELECT main_state FROM device_states_current", &[]).await.unwrap() };
+        assert_eq!(current().await.get::<_, Option<i32>>(0), Some(1));
+
+        device_state(3, now + chrono::Duration::minutes(5)).upsert_current(&client).await.unwrap();
+        assert_eq!(current().await.get::<_, Option<i32>>(0), Some(3));
+        assert_eq!(count(&client, "SELECT count(*) FROM device_states_current").await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn pooled_connections_insert_concurrently_and_time_out_when_exhausted() {
+        let url = std::env::var("DESMO_TEST_DATABASE_URL").expect("DESMO_TEST_DATABASE_URL is set");
+        let client = test_client("desmo_test_pool").await;
+        let url = format!("{url}?options=-csearch_path%3Ddesmo_test_pool");
+        let pool = connect_pool(&url, None, 2, Duration::from_millis(500)).unwrap();
+
+        let inserts: Vec<_> = (0..4)
+            .map(|i| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let connection = pool.get().await.unwrap();
+                    SensorReading::new(format!("d{}", i), "sensors/temp".to_string(), 21.5, Utc::now())
+                        .insert(&connection)
+                        .await
+                })
+            })
+            .collect();
+        for insert in inserts {
+            insert.await.unwrap().unwrap();
+        }
+        assert_eq!(count(&client, "SELECT count(*) FROM sensor_readings").await, 4);
+        assert!(pool.status().size <= 2);
+
+        // With every connection checked out, waiting for another fails after the timeout
+        let _held = (pool.get().await.unwrap(), pool.get().await.unwrap());
+        assert!(pool.get().await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in DESMO_TEST_DATABASE_URL"]
+    async fn state_only_snapshot_keeps_the_stored_health_fields() {
+        let client = test_client("desmo_test_snapshots").await;
+        let health = sparse_health();
+        DeviceSnapshot::from(&health).upsert(&client).await.unwrap();
+
+        let state = device_state(3, health.timestamp + chrono::Duration::minutes(1));
+        DeviceSnapshot::from(&state).upsert(&client).await.unwrap();
+
+        let row = client
+            .query_one("SELECT main_state, free_heap_size, unexpected_reset_counter FROM device_snapshots", &[])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, Option<i32>>(0), Some(3));
+        assert_eq!(row.get::<_, Option<i64>>(1), Some(57_940));
+        assert_eq!(row.get::<_, Option<i32>>(2), Some(2));
+        assert_eq!(count(&client, "SELECT count(*) FROM device_snapshots").await, 1);
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::sync::Semaphore;
+use tracing::info;
+
+use super::ReconnectingClient;
+use crate::config::DatabaseConfig;
+use crate::topic::TopicFilter;
+
+/// Name of the database at `database.url`, receiving records no route claims
+const DEFAULT_DATABASE: &str = "default";
+
+struct Database {
+    name: String,
+    topics: Vec<TopicFilter>,
+    client: Arc<ReconnectingClient>,
+    /// Batches being written to this database, up to `max_in_flight`
+    in_flight: Arc<Semaphore>,
+}
+
+/// Connections to the default database and each configured route, choosing
+/// where a record is written from its topic
+pub struct DbRouter {
+    /// The default database first, then routes in configuration order
+    databases: Vec<Database>,
+}
+
+impl DbRouter {
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self> {
+        let mut databases = vec![Database {
+            name: DEFAULT_DATABASE.to_string(),
+            topics: Vec::new(),
+            client: Arc::new(ReconnectingClient::connect(config).await?),
+            in_flight: Arc::new(Semaphore::new(config.max_in_flight.max(1))),
+        }];
+
+        for route in &config.routes {
+            let client = ReconnectingClient::connect_to(&route.url, config)
+                .await
+                .with_context(|| format!("Failed to connect to database route {}", route.name))?;
+            info!("Connected to database route {}", route.name);
+            databases.push(Database {
+                name: route.name.clone(),
+                topics: route.topics.clone(),
+                client: Arc::new(client),
+                in_flight: Arc::new(Semaphore::new(config.max_in_flight.max(1))),
+            });
+        }
+
+        Ok(Self { databases })
+    }
+
+    /// Index of the first database whose filters match the topic (ignoring any
+    /// query string), or of the default database
+    pub fn route(&self, topic: Option<&str>) -> usize {
+        let Some(topic) = topic else {
+            return 0;
+        };
+        let path = topic.split_once('?').map_or(topic, |(path, _)| path);
+
+        self.databases
+            .iter()
+            .position(|database| database.topics.iter().any(|filter| filter.matches(path)))
+            .unwrap_or(0)
+    }
+
+    pub fn client(&self, index: usize) -> &Arc<ReconnectingClient> {
+        &self.databases[index].client
+    }
+
+    /// Every database's client, the default first
+    pub fn clients(&self) -> impl Iterator<Item = &Arc<ReconnectingClient>> {
+        self.databases.iter().map(|database| &database.client)
+    }
+
+    /// Slots for batches written concurrently to the database, so a slow
+    /// database cannot hold up the others
+    pub fn in_flight(&self, index: usize) -> &Arc<Semaphore> {
+        &self.databases[index].in_flight
+    }
+
+    /// Every database's in-flight slots, the default first
+    pub fn in_flights(&self) -> impl Iterator<Item = &Arc<Semaphore>> {
+        self.databases.iter().map(|database| &database.in_flight)
+    }
+
+    pub fn name(&self, index: usize) -> &str {
+        &self.databases[index].name
+    }
+}
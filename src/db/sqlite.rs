@@ -0,0 +1,376 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
+use tracing::{debug, info};
+
+use super::{class_table, message_row, quote_identifier};
+use crate::config::{ColumnType, DatabaseConfig, FlattenedTable, InsertMode};
+use crate::metrics::METRICS;
+use crate::parser::ParsedMessage;
+
+/// The PostgreSQL tables records are written to, with JSONB and timestamps
+/// stored as TEXT and an integer row id in place of the hypertable key
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS sensor_readings (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        value REAL NOT NULL,
+        int_value INTEGER,
+        quality TEXT,
+        sensor_type TEXT,
+        unit TEXT,
+        accuracy REAL,
+        tags TEXT,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id INTEGER,
+        group_id TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS socket_reads (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        parse_error TEXT,
+        qos INTEGER NOT NULL DEFAULT 0,
+        retained INTEGER NOT NULL DEFAULT 0,
+        tags TEXT,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS device_logs (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        level TEXT NOT NULL,
+        message TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        tags TEXT,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS device_states (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        main_state INTEGER,
+        secondary_state INTEGER,
+        alerts TEXT,
+        rssi INTEGER,
+        state_reason TEXT,
+        tags TEXT,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS device_capabilities (
+        device_id TEXT NOT NULL,
+        capability TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        PRIMARY KEY (device_id, capability)
+    );
+
+    CREATE TABLE IF NOT EXISTS device_alerts (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        alert_key TEXT NOT NULL,
+        alert_value TEXT,
+        threshold REAL,
+        current REAL,
+        tags TEXT,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS device_health (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        wifi_ssid TEXT,
+        free_heap_size INTEGER,
+        min_heap_size INTEGER,
+        unexpected_reset_counter INTEGER,
+        last_reset_reason TEXT,
+        wifi_connect_counter INTEGER,
+        cloud_connect_counter INTEGER,
+        last_wifi_connection_ts INTEGER,
+        last_cloud_connection_ts INTEGER,
+        last_wifi_connection_at TEXT,
+        last_cloud_connection_at TEXT,
+        raw_health TEXT,
+        clock_offset_seconds REAL,
+        tags TEXT,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS task_health (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        task_name TEXT NOT NULL,
+        stack_free INTEGER,
+        state TEXT,
+        tags TEXT,
+        retention_class TEXT,
+        schema_version TEXT,
+        topic_id INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS offline_events (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        tags TEXT,
+        retention_class TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS dead_letters (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        topic TEXT,
+        record TEXT NOT NULL,
+        reason TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS parse_errors (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        reason TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS reading_rollups (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        window_secs INTEGER NOT NULL,
+        count INTEGER NOT NULL,
+        min REAL NOT NULL,
+        max REAL NOT NULL,
+        avg REAL NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_sensor_readings_device_id ON sensor_readings (device_id);
+    CREATE INDEX IF NOT EXISTS idx_socket_reads_topic ON socket_reads (topic);
+    CREATE INDEX IF NOT EXISTS idx_device_logs_device_id ON device_logs (device_id);
+    CREATE INDEX IF NOT EXISTS idx_device_logs_level ON device_logs (level);
+    CREATE INDEX IF NOT EXISTS idx_device_states_device_id ON device_states (device_id);
+    CREATE INDEX IF NOT EXISTS idx_device_alerts_device_id ON device_alerts (device_id);
+    CREATE INDEX IF NOT EXISTS idx_device_health_device_id ON device_health (device_id);
+    CREATE INDEX IF NOT EXISTS idx_task_health_device_id ON task_health (device_id);
+    CREATE INDEX IF NOT EXISTS idx_offline_events_device_id ON offline_events (device_id);
+";
+
+/// Fail on database options only the PostgreSQL writer implements, rather
+/// than silently storing less than configured
+pub fn check_options(config: &DatabaseConfig) -> Result<()> {
+    let unsupported: Vec<&str> = [
+        ("routes", !config.routes.is_empty()),
+        ("topic_hierarchy", config.topic_hierarchy),
+        ("device_groups", config.device_groups),
+        ("device_states_current", config.device_states_current),
+        ("device_snapshots", config.device_snapshots),
+        ("insert_mode = \"staging\"", config.insert_mode == InsertMode::Staging),
+    ]
+    .into_iter()
+    .filter_map(|(option, set)| set.then_some(option))
+    .collect();
+
+    if !unsupported.is_empty() {
+        bail!(
+            "The sqlite sink does not support these [database] options: {}",
+            unsupported.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Open (creating if needed) a SQLite database, its tables and the configured
+/// flattened tables. WAL mode lets another process read the buffered records
+/// while the bridge keeps writing.
+pub fn open(path: &Path, flattened_tables: &[FlattenedTable]) -> Result<Connection> {
+    let connection =
+        Connection::open(path).with_context(|| format!("Failed to open SQLite database {}", path.display()))?;
+    connection
+        .pragma_update(None, "journal_mode", "WAL")
+        .with_context(|| format!("Failed to enable WAL on {}", path.display()))?;
+    connection
+        .execute_batch(SCHEMA)
+        .with_context(|| format!("Failed to create tables in {}", path.display()))?;
+    for table in flattened_tables {
+        connection
+            .execute_batch(&flattened_table_sql(table))
+            .with_context(|| format!("Failed to create flattened table {} in {}", table.table, path.display()))?;
+    }
+
+    Ok(connection)
+}
+
+/// Table for a flattened-fields config, with the columns FlattenedRow writes
+fn flattened_table_sql(table: &FlattenedTable) -> String {
+    let columns: String = table
+        .columns
+        .iter()
+        .map(|column| {
+            let sql_type = match column.column_type {
+                ColumnType::Float => "REAL",
+                ColumnType::Integer | ColumnType::Boolean => "INTEGER",
+                ColumnType::Text | ColumnType::Json => "TEXT",
+            };
+            format!(", {} {}", quote_identifier(&column.column), sql_type)
+        })
+        .collect();
+
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, timestamp TEXT NOT NULL, device_id TEXT NOT NULL, topic TEXT NOT NULL{});",
+        quote_identifier(&table.table),
+        columns
+    )
+}
+
+/// Create a per-class copy of a base table if it does not exist yet
+fn ensure_class_table(connection: &Connection, base: &str, class: &str) -> Result<()> {
+    let table = class_table(base, Some(class));
+    let table_sql = |name: &str| {
+        connection
+            .prepare_cached("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1")?
+            .query_row([name], |row| row.get::<_, String>(0))
+            .optional()
+    };
+
+    if table_sql(&table)?.is_some() {
+        return Ok(());
+    }
+    let base_sql = table_sql(base)?.with_context(|| format!("No base table {} for class table {}", base, table))?;
+    let Some(columns) = base_sql.find('(').map(|start| &base_sql[start..]) else {
+        bail!("Unexpected definition of table {}", base);
+    };
+
+    connection
+        .execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {table} {columns}; \
+             CREATE INDEX IF NOT EXISTS idx_{table}_device_id ON {table} (device_id);"
+        ))
+        .with_context(|| format!("Failed to create class table {}", table))?;
+
+    info!("Created class table {}", table);
+
+    Ok(())
+}
+
+/// Insert a mixed batch of records in one transaction, so a failed record
+/// leaves none of the batch behind; per-class tables are created on first
+/// use. Returns the number of rows written.
+pub fn insert_batch(connection: &mut Connection, records: &[ParsedMessage]) -> Result<u64> {
+    let transaction = connection
+        .transaction()
+        .with_context(|| "Failed to begin SQLite transaction")?;
+
+    let mut total = 0;
+    for record in records {
+        if let Some((base, class)) = record.class_route() {
+            ensure_class_table(&transaction, base, class)?;
+        }
+
+        let row = message_row(record);
+        let placeholders: Vec<String> = (1..=row.columns.len()).map(|i| format!("?{}", i)).collect();
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({}) {}",
+            row.table,
+            row.columns.join(", "),
+            placeholders.join(", "),
+            row.on_conflict.unwrap_or_default()
+        );
+
+        let mut statement = transaction
+            .prepare_cached(&sql)
+            .with_context(|| format!("Failed to prepare insert into {}", row.table))?;
+        total += statement
+            .execute(params_from_iter(row.params.iter().map(|param| param.sqlite())))
+            .inspect_err(|_| METRICS.insert_failures.with_label_values(&[&row.table]).inc())
+            .with_context(|| format!("Failed to insert row into {}", row.table))? as u64;
+    }
+
+    transaction
+        .commit()
+        .with_context(|| "Failed to commit SQLite transaction")?;
+    debug!("Inserted batch of {} rows into SQLite", total);
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::db::SensorReading;
+
+    #[test]
+    fn records_are_buffered_with_json_as_text() {
+        let path = std::env::temp_dir().join(format!("desmo-sqlite-{}.db", std::process::id()));
+        let mut connection = open(&path, &[]).unwrap();
+        let tags = serde_json::json!({"site": "roof"});
+        let records: Vec<_> = [21.5, 22.0]
+            .into_iter()
+            .map(|value| {
+                let reading = SensorReading::new("d1".to_string(), "sensors/d1/temp".to_string(), value, Utc::now());
+                ParsedMessage::SensorReading(reading.with_tags(Some(tags.clone())))
+            })
+            .collect();
+        let payload = br#"{"device_id": "d1", "main_state": 1, "alerts": {"overheat": true}}"#;
+        let state = crate::parser::parse_message(
+            "devices/d1/state",
+            payload,
+            0,
+            false,
+            &Default::default(),
+            &mut Default::default(),
+        )
+        .unwrap();
+
+        insert_batch(&mut connection, &records).unwrap();
+        insert_batch(&mut connection, &state).unwrap();
+
+        let count = |table: &str| -> i64 {
+            connection.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(count("sensor_readings"), 2);
+        assert_eq!(count("device_states"), 1);
+        let stored: String =
+            connection.query_row("SELECT tags FROM sensor_readings LIMIT 1", [], |row| row.get(0)).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&stored).unwrap(), tags);
+
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn postgres_only_options_are_rejected() {
+        let config: DatabaseConfig = toml::from_str("url = \"sqlite\"\ndevice_groups = true").unwrap();
+
+        assert!(check_options(&config).is_err());
+        assert!(check_options(&toml::from_str("url = \"sqlite\"").unwrap()).is_ok());
+    }
+}
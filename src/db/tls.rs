@@ -0,0 +1,211 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+/// Encryption requirement from a connection string's `sslmode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SslMode {
+    /// Plain TCP only
+    Disable,
+    /// TLS when the server offers it, without verifying the certificate
+    Prefer,
+    /// TLS without verifying the certificate
+    Require,
+    /// TLS with the certificate chain and host name verified
+    VerifyFull,
+}
+
+impl SslMode {
+    fn parse(value: &str) -> Result<Self> {
+        Ok(match value {
+            "disable" => SslMode::Disable,
+            "allow" | "prefer" => SslMode::Prefer,
+            "require" => SslMode::Require,
+            "verify-full" => SslMode::VerifyFull,
+            "verify-ca" => bail!(
+                "sslmode=verify-ca is not supported; use verify-full to check the CA and host name, or require to only encrypt"
+            ),
+            other => bail!("Unsupported sslmode {:?}", other),
+        })
+    }
+
+    /// Mode for a connection string without `sslmode`: verify against a given
+    /// CA bundle, else encrypt when the server offers it
+    pub(super) fn default_for(ca_cert: Option<&Path>) -> Self {
+        if ca_cert.is_some() {
+            SslMode::VerifyFull
+        } else {
+            SslMode::Prefer
+        }
+    }
+}
+
+/// Remove `sslmode` from a URL (`postgresql://...?sslmode=require`) or key/value
+/// (`host=... sslmode=require`) connection string. tokio-postgres only parses the
+/// modes it implements itself, so verification modes are handled here instead.
+pub(super) fn take_sslmode(database_url: &str) -> Result<(String, Option<SslMode>)> {
+    let (base, params, separator) = match database_url.split_once("://") {
+        Some(_) => match database_url.split_once('?') {
+            Some((base, query)) => (format!("{}?", base), query.split('&').collect::<Vec<_>>(), "&"),
+            None => return Ok((database_url.to_string(), None)),
+        },
+        None => (String::new(), database_url.split_whitespace().collect(), " "),
+    };
+
+    let mut mode = None;
+    let mut kept = Vec::new();
+    for param in params {
+        match param.strip_prefix("sslmode=") {
+            Some(value) => mode = Some(SslMode::parse(value)?),
+            None => kept.push(param),
+        }
+    }
+    if mode.is_none() {
+        return Ok((database_url.to_string(), None));
+    }
+
+    let rest = kept.join(separator);
+    let url = if rest.is_empty() {
+        base.trim_end_matches('?').to_string()
+    } else {
+        format!("{}{}", base, rest)
+    };
+
+    Ok((url, mode))
+}
+
+/// rustls client configuration for the mode. `verify-full` trusts the CA bundle
+/// when one is given, else the Mozilla root store; `prefer` and `require` only
+/// encrypt, like libpq.
+pub(super) fn client_config(mode: SslMode, ca_cert: Option<&Path>) -> Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .with_context(|| "Failed to configure TLS")?;
+
+    if mode != SslMode::VerifyFull {
+        return Ok(builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(EncryptOnly(provider)))
+            .with_no_client_auth());
+    }
+
+    let mut roots = RootCertStore::empty();
+    match ca_cert {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open CA bundle {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read CA bundle {}", path.display()))?;
+
+    if certs.is_empty() {
+        bail!("No certificates found in CA bundle {}", path.display());
+    }
+
+    Ok(certs)
+}
+
+/// Accepts any server certificate while still checking handshake signatures,
+/// giving an encrypted but unauthenticated connection
+#[derive(Debug)]
+struct EncryptOnly(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for EncryptOnly {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sslmode_is_taken_from_url_query() {
+        let url = "postgresql://admin@db:5432/metrics?sslmode=require&application_name=desmo";
+        let (url, mode) = take_sslmode(url).unwrap();
+        assert_eq!(url, "postgresql://admin@db:5432/metrics?application_name=desmo");
+        assert_eq!(mode, Some(SslMode::Require));
+
+        let (url, mode) = take_sslmode("postgresql://admin@db:5432/metrics?sslmode=verify-full").unwrap();
+        assert_eq!(url, "postgresql://admin@db:5432/metrics");
+        assert_eq!(mode, Some(SslMode::VerifyFull));
+    }
+
+    #[test]
+    fn sslmode_is_taken_from_key_value_string() {
+        let (url, mode) = take_sslmode("host=db user=admin sslmode=disable dbname=metrics").unwrap();
+        assert_eq!(url, "host=db user=admin dbname=metrics");
+        assert_eq!(mode, Some(SslMode::Disable));
+    }
+
+    #[test]
+    fn connection_string_without_sslmode_is_unchanged() {
+        let url = "postgresql://admin@db:5432/metrics?application_name=desmo";
+        assert_eq!(take_sslmode(url).unwrap(), (url.to_string(), None));
+        assert_eq!(SslMode::default_for(None), SslMode::Prefer);
+        assert_eq!(SslMode::default_for(Some(Path::new("ca.pem"))), SslMode::VerifyFull);
+    }
+
+    #[test]
+    fn verify_ca_is_rejected() {
+        let error = take_sslmode("postgresql://db/metrics?sslmode=verify-ca").unwrap_err();
+        assert!(error.to_string().contains("verify-full"));
+        assert!(take_sslmode("postgresql://db/metrics?sslmode=bogus").is_err());
+    }
+
+    #[test]
+    fn missing_ca_bundle_is_an_error() {
+        assert!(client_config(SslMode::VerifyFull, Some(Path::new("/nonexistent/ca.pem"))).is_err());
+        assert!(client_config(SslMode::Require, None).is_ok());
+    }
+}
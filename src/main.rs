@@ -4,10 +4,20 @@ use colored::Colorize;
 
 mod config;
 mod db;
+mod metrics;
 mod mqtt;
 mod parser;
+mod pipeline;
+mod replay;
+mod sink;
+mod stats;
+mod topic;
+mod writer;
 
-use config::Config;
+use config::{Config, SinkKind};
+use parser::ParsedMessage;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
 #[derive(Parser)]
 #[command(name = "desmo")]
@@ -39,6 +49,20 @@ enum Commands {
         db_url: Option<String>,
     },
 
+    /// Replay a JSON-lines capture into the database, for load testing the writer
+    Replay {
+        /// Capture file with one `{"timestamp", "topic", "payload"}` object per line
+        capture: String,
+
+        /// Multiple of real time to replay at ("inf" for as fast as possible)
+        #[arg(short, long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// Path to configuration file
+        #[arg(short, long, default_value = "desmo.toml")]
+        config: String,
+    },
+
     /// Generate a sample configuration file
     Config {
         /// Output path for configuration file
@@ -68,6 +92,9 @@ async fn main() -> Result<()> {
         } => {
             start_bridge(config, mqtt_host, mqtt_port, db_url).await?;
         }
+        Commands::Replay { capture, speed, config } => {
+            replay_capture(&capture, speed, &config).await?;
+        }
         Commands::Config { output } => {
             generate_config(&output)?;
         }
@@ -115,12 +142,62 @@ async fn start_bridge(
     }
     println!();
 
-    // Initialize database connection
-    let db_client = db::connect(&config.database.url).await?;
-    println!("{}", "✓ Connected to TimescaleDB".green());
+    // Start the record writer; custom transform stages are registered here
+    let stages: Vec<Box<dyn pipeline::Stage>> = Vec::new();
+    let load = writer::WriterLoad::new(config.database.max_buffered, config.mqtt.ack_below_load.unwrap_or(1.0));
+    let (writer_tx, writer_task) = spawn_sink(&config, pipeline::Pipeline::new(stages), load.clone()).await?;
+    match config.sink {
+        SinkKind::Postgres => println!("{}", "✓ Connected to TimescaleDB".green()),
+        SinkKind::Stdout => println!("{}", "✓ Writing records to stdout".green()),
+        SinkKind::Null => println!("{}", "✓ Discarding records".green()),
+        SinkKind::Sqlite => println!(
+            "{} {}",
+            "✓ Writing records to".green(),
+            config.sqlite.path.display().to_string().yellow()
+        ),
+    }
+
+    // Start retention maintenance on its own connection
+    let retention = db::RetentionPolicy::from_config(&config.database);
+    if config.sink == SinkKind::Postgres && !retention.is_empty() {
+        let maintenance_client = db::ReconnectingClient::connect(&config.database).await?;
+        tokio::spawn(db::run_maintenance(maintenance_client, retention));
+
+        if let Some(hours) = config.database.socket_reads_retention_hours {
+            println!(
+                "{} {}",
+                "✓ Pruning socket reads older than".green(),
+                format!("{}h", hours).yellow()
+            );
+        }
+        if let Some(hours) = config.database.dead_letter_retention_hours {
+            println!(
+                "{} {}",
+                "✓ Pruning dead letters older than".green(),
+                format!("{}h", hours).yellow()
+            );
+        }
+        if let Some(rows) = config.database.dead_letter_max_rows {
+            println!(
+                "{} {}",
+                "✓ Capping dead letters at".green(),
+                format!("{} rows", rows).yellow()
+            );
+        }
+    }
+
+    // Expose Prometheus counters when a listen address is configured
+    if let Some(addr) = config.metrics.listen {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve_metrics(addr).await {
+                tracing::error!("Metrics endpoint stopped: {:#}", e);
+            }
+        });
+        println!("{} {}", "✓ Serving metrics on".green(), format!("http://{}/metrics", addr).yellow());
+    }
 
     // Initialize MQTT client
-    let mqtt_bridge = mqtt::MqttBridge::new(config.mqtt.clone(), db_client).await?;
+    let mqtt_bridge = mqtt::MqttBridge::new(&config, writer_tx, load).await?;
     println!("{}", "✓ Connected to MQTT broker".green());
     println!();
 
@@ -134,9 +211,79 @@ async fn start_bridge(
     mqtt_bridge.run().await?;
 
     println!("{}", "\nShutting down...".yellow());
+
+    // The bridge dropped its sender; let the writer flush queued records
+    writer_task.await?;
     Ok(())
 }
 
+async fn replay_capture(capture: &str, speed: f64, config_path: &str) -> Result<()> {
+    let config = Config::load(config_path)?;
+
+    let stages: Vec<Box<dyn pipeline::Stage>> = Vec::new();
+    let load = writer::WriterLoad::new(config.database.max_buffered, 1.0);
+    let (writer_tx, writer_task) = spawn_sink(&config, pipeline::Pipeline::new(stages), load).await?;
+
+    println!(
+        "{} {} {}",
+        "Replaying".bright_green(),
+        capture.cyan(),
+        format!("at {}x", speed).yellow()
+    );
+
+    let started = std::time::Instant::now();
+    let summary = replay::replay(std::path::Path::new(capture), speed, &config.parser, &writer_tx).await?;
+
+    // Wait for the writer to flush everything replayed
+    drop(writer_tx);
+    writer_task.await?;
+
+    let elapsed = started.elapsed().as_secs_f64();
+    println!(
+        "{} {} messages, {} records ({} skipped) in {:.2}s, {:.0} records/s",
+        "✓ Replayed".green(),
+        summary.messages,
+        summary.records,
+        summary.skipped,
+        elapsed,
+        summary.records as f64 / elapsed.max(f64::EPSILON)
+    );
+
+    Ok(())
+}
+
+/// Start the task writing parsed records to the configured sink; the
+/// PostgreSQL writer connects to every routed database first and reports its
+/// buffer to `load`
+async fn spawn_sink(
+    config: &Config,
+    pipeline: pipeline::Pipeline,
+    load: writer::WriterLoad,
+) -> Result<(mpsc::Sender<ParsedMessage>, JoinHandle<()>)> {
+    Ok(match config.sink {
+        SinkKind::Postgres => {
+            let db_router = db::DbRouter::connect(&config.database).await?;
+            writer::spawn(db_router, &config.database, pipeline, load)
+        }
+        SinkKind::Stdout => sink::spawn(sink::StdoutJsonSink, config.database.batch_size, pipeline),
+        SinkKind::Null => sink::spawn(sink::NullSink, config.database.batch_size, pipeline),
+        #[cfg(feature = "sqlite")]
+        SinkKind::Sqlite => {
+            db::sqlite::check_options(&config.database)?;
+            sink::spawn(
+                sink::SqliteSink::open(&config.sqlite.path, &config.parser.flattened_tables)?,
+                config.database.batch_size,
+                pipeline,
+            )
+        }
+        #[cfg(not(feature = "sqlite"))]
+        SinkKind::Sqlite => anyhow::bail!(
+            "Cannot write to {}: desmo was built without the sqlite feature",
+            config.sqlite.path.display()
+        ),
+    })
+}
+
 fn generate_config(output_path: &str) -> Result<()> {
     let default_config = Config::default();
     let toml_string = toml::to_string_pretty(&default_config)?;
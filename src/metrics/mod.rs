@@ -0,0 +1,221 @@
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+
+use anyhow::{Context, Result};
+use prometheus::{Encoder, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Longest request head read before answering
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Prometheus counters for throughput and error rates
+pub struct Metrics {
+    registry: Registry,
+    pub messages_received: IntCounter,
+    /// Records produced by the parser, labelled by ParsedMessage variant
+    pub records_parsed: IntCounterVec,
+    pub utf8_errors: IntCounter,
+    pub json_errors: IntCounter,
+    /// Failed insert statements, labelled by target table
+    pub insert_failures: IntCounterVec,
+    /// Dead letters, labelled by reason (e.g., "json_error")
+    pub dead_letters: IntCounterVec,
+    /// Payloads dropped as repeats within the dedup window
+    pub duplicate_payloads: IntCounter,
+    /// Redelivered retained messages whose raw read was skipped
+    pub duplicate_retained_reads: IntCounter,
+    /// Dead letters dropped because the writer's dead-letter queue was full
+    pub dropped_dead_letters: IntCounter,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::register);
+
+impl Metrics {
+    fn register() -> Self {
+        let registry = Registry::new();
+
+        let messages_received = IntCounter::new("desmo_messages_received_total", "MQTT messages handed to the parser")
+            .expect("valid metric");
+        let records_parsed = IntCounterVec::new(
+            Opts::new("desmo_records_parsed_total", "Records produced by the parser"),
+            &["variant"],
+        )
+        .expect("valid metric");
+        let utf8_errors = IntCounter::new("desmo_utf8_errors_total", "Payloads that were not valid UTF-8")
+            .expect("valid metric");
+        let json_errors = IntCounter::new("desmo_json_errors_total", "JSON-looking payloads that failed to decode")
+            .expect("valid metric");
+        let insert_failures = IntCounterVec::new(
+            Opts::new("desmo_insert_failures_total", "Failed database insert statements"),
+            &["table"],
+        )
+        .expect("valid metric");
+        let dead_letters = IntCounterVec::new(
+            Opts::new("desmo_dead_letters_total", "Records and payloads dead-lettered"),
+            &["reason"],
+        )
+        .expect("valid metric");
+        let duplicate_payloads =
+            IntCounter::new("desmo_duplicate_payloads_total", "Repeated payloads dropped before parsing")
+                .expect("valid metric");
+        let duplicate_retained_reads = IntCounter::new(
+            "desmo_duplicate_retained_reads_total",
+            "Raw reads skipped for redelivered retained messages",
+        )
+        .expect("valid metric");
+        let dropped_dead_letters = IntCounter::new(
+            "desmo_dropped_dead_letters_total",
+            "Dead letters dropped on a full dead-letter queue",
+        )
+        .expect("valid metric");
+
+        for collector in [
+            Box::new(messages_received.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(records_parsed.clone()),
+            Box::new(utf8_errors.clone()),
+            Box::new(json_errors.clone()),
+            Box::new(insert_failures.clone()),
+            Box::new(dead_letters.clone()),
+            Box::new(duplicate_payloads.clone()),
+            Box::new(duplicate_retained_reads.clone()),
+            Box::new(dropped_dead_letters.clone()),
+        ] {
+            registry.register(collector).expect("metric registered once");
+        }
+
+        Self {
+            registry,
+            messages_received,
+            records_parsed,
+            utf8_errors,
+            json_errors,
+            insert_failures,
+            dead_letters,
+            duplicate_payloads,
+            duplicate_retained_reads,
+            dropped_dead_letters,
+        }
+    }
+
+    /// All counters in the Prometheus text exposition format
+    fn render(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .with_context(|| "Failed to encode metrics")?;
+        Ok(buffer)
+    }
+}
+
+/// Serve the counters at `GET /metrics` until the listener fails
+pub async fn serve_metrics(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind metrics endpoint on {}", addr))?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await.with_context(|| "Failed to accept metrics connection")?;
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream).await {
+                debug!("Metrics request from {} failed: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+/// Answer one HTTP/1 request and close the connection
+async fn respond(mut stream: TcpStream) -> Result<()> {
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+
+    let request_line = request.split(|&byte| byte == b'\n').next().unwrap_or_default();
+    let mut parts = std::str::from_utf8(request_line).unwrap_or_default().split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match METRICS.render() {
+            Ok(body) => ("200 OK", TextEncoder::new().format_type().to_string(), body),
+            Err(e) => {
+                warn!("{:#}", e);
+                ("500 Internal Server Error", "text/plain".to_string(), Vec::new())
+            }
+        },
+        _ => ("404 Not Found", "text/plain".to_string(), b"Not Found\n".to_vec()),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send one request to `respond` over a local socket and return the raw response
+    async fn request(request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            respond(stream).await
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap().unwrap();
+
+        response
+    }
+
+    #[tokio::test]
+    async fn parsed_messages_are_counted_on_the_metrics_endpoint() {
+        let before = METRICS.messages_received.get();
+        let readings = || METRICS.records_parsed.with_label_values(&["sensor_reading"]).get();
+        let readings_before = readings();
+
+        let payload = br#"{"device_id": "d1", "temp": 21.5}"#;
+        crate::parser::parse_message(
+            "sensors/d1",
+            payload,
+            0,
+            false,
+            &Default::default(),
+            &mut Default::default(),
+        )
+        .unwrap();
+
+        assert!(METRICS.messages_received.get() > before);
+        assert!(readings() > readings_before);
+
+        let response = request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("desmo_messages_received_total"));
+        assert!(response.contains(r#"desmo_records_parsed_total{variant="sensor_reading"}"#));
+    }
+
+    #[tokio::test]
+    async fn other_paths_are_not_found() {
+        let response = request("GET / HTTP/1.1\r\n\r\n").await;
+
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{}", response);
+    }
+}
@@ -1,34 +1,75 @@
 use anyhow::{Context, Result};
-use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
-use tokio_postgres::Client as PgClient;
+use chrono::Utc;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, Publish, QoS};
+use tokio::sync::mpsc;
 use tracing::{debug, error, info};
 
-use crate::config::MqttConfig;
-use crate::parser::{parse_message, ParsedMessage};
+use crate::config::{Config, ParserConfig};
+use crate::metrics::METRICS;
+use crate::parser::{
+    apply_global_tags, apply_retention_class, parse_message, ParseContext, ParsedMessage,
+};
+use crate::pipeline::{
+    BirthCache, DeviceCardinalityGuard, Downsampler, HealthDeltaAccumulator, MonotonicTimestampFilter,
+    PayloadDeduplicator, PresenceTracker, RetainedDeduplicator, RollupAggregator,
+};
+use crate::writer::WriterLoad;
 
 pub struct MqttBridge {
     _client: AsyncClient,
     eventloop: EventLoop,
-    db_client: PgClient,
+    writer: mpsc::Sender<ParsedMessage>,
+    /// Received messages awaiting an ack, when acks follow the writer's load
+    acks: Option<mpsc::UnboundedSender<Publish>>,
+    parser_config: ParserConfig,
+    dedup: Option<PayloadDeduplicator>,
+    retained_dedup: Option<RetainedDeduplicator>,
+    births: BirthCache,
+    cardinality_guard: Option<DeviceCardinalityGuard>,
+    monotonic_timestamps: Option<MonotonicTimestampFilter>,
+    downsampler: Downsampler,
+    health_deltas: HealthDeltaAccumulator,
+    presence: Option<PresenceTracker>,
+    rollups: Option<RollupAggregator>,
+    presence_scan_interval: std::time::Duration,
 }
 
 impl MqttBridge {
-    pub async fn new(config: MqttConfig, db_client: PgClient) -> Result<Self> {
-        let mut mqttoptions = MqttOptions::new(&config.client_id, &config.host, config.port);
+    pub async fn new(config: &Config, writer: mpsc::Sender<ParsedMessage>, load: WriterLoad) -> Result<Self> {
+        let mqtt = &config.mqtt;
+
+        let mut mqttoptions = MqttOptions::new(&mqtt.client_id, &mqtt.host, mqtt.port);
         mqttoptions.set_keep_alive(std::time::Duration::from_secs(30));
         mqttoptions.set_clean_session(true);
+        mqttoptions.set_manual_acks(mqtt.ack_below_load.is_some());
 
         let (client, eventloop) = AsyncClient::new(mqttoptions, 10);
 
+        // Ack in order of receipt, each once the writer has capacity; the event
+        // loop keeps running meanwhile so keep-alives are still sent
+        let acks = mqtt.ack_below_load.map(|_| {
+            let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<Publish>();
+            let client = client.clone();
+            tokio::spawn(async move {
+                while let Some(publish) = ack_rx.recv().await {
+                    load.wait_for_capacity().await;
+                    if let Err(e) = client.ack(&publish).await {
+                        error!("Failed to acknowledge message on topic {}: {}", publish.topic, e);
+                    }
+                }
+            });
+            ack_tx
+        });
+
         // Subscribe to topics
-        let qos = match config.qos {
+        let qos = match mqtt.qos {
             0 => QoS::AtMostOnce,
             1 => QoS::AtLeastOnce,
             2 => QoS::ExactlyOnce,
             _ => QoS::AtMostOnce,
         };
 
-        for topic in &config.topics {
+        for topic in &mqtt.topics {
             client
                 .subscribe(topic, qos)
                 .await
@@ -38,7 +79,36 @@ impl MqttBridge {
         Ok(Self {
             _client: client,
             eventloop,
-            db_client,
+            writer,
+            acks,
+            parser_config: config.parser.clone(),
+            dedup: config
+                .parser
+                .dedup_window_ms
+                .map(|ms| PayloadDeduplicator::new(chrono::Duration::milliseconds(ms as i64))),
+            retained_dedup: config.parser.retained_dedup.as_ref().map(|dedup| {
+                RetainedDeduplicator::new(dedup.capacity, chrono::Duration::seconds(dedup.ttl_secs as i64))
+            }),
+            births: BirthCache::new(config.parser.birth_cache_capacity),
+            cardinality_guard: config.parser.cardinality_guard.as_ref().map(|guard| {
+                DeviceCardinalityGuard::new(guard.max_devices, chrono::Duration::seconds(guard.window_secs as i64))
+            }),
+            monotonic_timestamps: config.parser.monotonic_timestamps.map(MonotonicTimestampFilter::new),
+            downsampler: Downsampler::new(config.parser.sampling.clone()),
+            health_deltas: HealthDeltaAccumulator::new(&config.parser.health_counters),
+            presence: config
+                .presence
+                .offline_timeout_secs
+                .map(|secs| {
+                    PresenceTracker::new(
+                        chrono::Duration::seconds(secs as i64),
+                        chrono::Duration::seconds(config.presence.activation_grace_secs as i64),
+                    )
+                }),
+            rollups: config.parser.rollups.as_ref().map(|rollups| RollupAggregator::new(rollups.window_secs)),
+            presence_scan_interval: std::time::Duration::from_secs(
+                config.presence.scan_interval_secs.max(1),
+            ),
         })
     }
 
@@ -53,13 +123,18 @@ impl MqttBridge {
             let _ = shutdown_tx.send(()).await;
         });
 
+        let mut presence_scan = tokio::time::interval(self.presence_scan_interval);
+        let mut rollup_flush = tokio::time::interval(std::time::Duration::from_secs(
+            self.parser_config.rollups.as_ref().map_or(60, |rollups| rollups.window_secs.max(1) as u64),
+        ));
+
         loop {
             tokio::select! {
                 event = self.eventloop.poll() => {
                     match event {
                         Ok(notification) => {
                             if let Err(e) = self.handle_event(notification).await {
-                                error!("Error handling event: {}", e);
+                                error!("Error handling event: {:#}", e);
                             }
                         }
                         Err(e) => {
@@ -69,6 +144,12 @@ impl MqttBridge {
                         }
                     }
                 }
+                _ = presence_scan.tick(), if self.presence.is_some() => {
+                    self.emit_offline_events().await;
+                }
+                _ = rollup_flush.tick(), if self.rollups.is_some() => {
+                    self.emit_rollups(false).await;
+                }
                 _ = shutdown_rx.recv() => {
                     info!("Shutdown signal received");
                     break;
@@ -76,27 +157,82 @@ impl MqttBridge {
             }
         }
 
+        // Keep the partial windows rather than losing them
+        self.emit_rollups(true).await;
+
         Ok(())
     }
 
-    async fn handle_event(&self, event: Event) -> Result<()> {
+    async fn handle_event(&mut self, event: Event) -> Result<()> {
         match event {
             Event::Incoming(Packet::Publish(publish)) => {
                 let topic = &publish.topic;
                 let payload = &publish.payload;
+                let (qos, retained) = (i16::from(publish.qos as u8), publish.retain);
+
+                if let Some(acks) = &self.acks {
+                    let _ = acks.send(publish.clone());
+                }
 
                 // Log at debug level only
                 debug!("Received message on topic: {}", topic);
 
-                // Parse the message
-                let parsed_messages = parse_message(topic, payload);
+                // Drop repeated noise before spending time on parsing
+                if let Some(dedup) = &mut self.dedup {
+                    if dedup.is_duplicate(topic, payload, Utc::now()) {
+                        METRICS.duplicate_payloads.inc();
+                        let total = METRICS.duplicate_payloads.get();
+                        debug!("Dropped duplicate payload on topic {} ({} so far)", topic, total);
+                        return Ok(());
+                    }
+                }
 
-                // Insert into database
-                for message in parsed_messages {
-                    if let Err(e) = self.insert_message(message).await {
-                        error!("Failed to insert message: {}", e);
+                // Parse the message, under the CN the broker placed in the topic
+                // (trusted only because the broker ACL forbids publishing there directly)
+                let cert_cn = self
+                    .parser_config
+                    .cert_cn_topic_segment
+                    .and_then(|index| topic.split('/').nth(index))
+                    .filter(|cn| !cn.is_empty());
+                let mut ctx = ParseContext {
+                    births: Some(&mut self.births),
+                    cert_cn,
+                };
+                let mut parsed_messages = parse_message(topic, payload, qos, retained, &self.parser_config, &mut ctx)
+                    .with_context(|| format!("Failed to parse message on topic {}", topic))?;
+
+                // A redelivered retained message keeps its records but not another raw read
+                if let Some(dedup) = self.retained_dedup.as_mut().filter(|_| retained) {
+                    if dedup.is_duplicate(topic, payload, Utc::now()) {
+                        parsed_messages.retain(|message| !matches!(message, ParsedMessage::SocketRead(_)));
+                        METRICS.duplicate_retained_reads.inc();
+                        let total = METRICS.duplicate_retained_reads.get();
+                        debug!("Skipped raw read of repeated retained message on topic {} ({} so far)", topic, total);
                     }
                 }
+
+                // Apply stateful filters
+                if let Some(guard) = &mut self.cardinality_guard {
+                    guard.apply(&mut parsed_messages, Utc::now());
+                }
+                if let Some(filter) = &mut self.monotonic_timestamps {
+                    filter.apply(&mut parsed_messages);
+                }
+                // Roll up every reading before downsampling thins them out
+                if let Some(rollups) = &mut self.rollups {
+                    let closed = rollups.observe(&parsed_messages);
+                    parsed_messages.extend(closed.into_iter().map(ParsedMessage::ReadingRollup));
+                }
+                self.downsampler.apply(&mut parsed_messages);
+                self.health_deltas.apply(&mut parsed_messages);
+                if let Some(presence) = &mut self.presence {
+                    presence.observe(&parsed_messages, Utc::now());
+                }
+
+                // Hand off to the writer task
+                for message in parsed_messages {
+                    self.send(message).await?;
+                }
             }
             Event::Incoming(Packet::ConnAck(_)) => {
                 info!("Connected to MQTT broker");
@@ -115,25 +251,45 @@ impl MqttBridge {
         Ok(())
     }
 
-    async fn insert_message(&self, message: ParsedMessage) -> Result<()> {
-        match message {
-            ParsedMessage::SensorReading(reading) => {
-                reading.insert(&self.db_client).await?;
-            }
-            ParsedMessage::SocketRead(read) => {
-                read.insert(&self.db_client).await?;
-            }
-            ParsedMessage::DeviceLog(log) => {
-                log.insert(&self.db_client).await?;
-            }
-            ParsedMessage::DeviceState(state) => {
-                state.insert(&self.db_client).await?;
+    /// Store rollups of windows that have closed, or of every open window at shutdown
+    async fn emit_rollups(&mut self, shutdown: bool) {
+        let Some(rollups) = &mut self.rollups else {
+            return;
+        };
+
+        let closed = if shutdown { rollups.drain() } else { rollups.flush(Utc::now()) };
+        for rollup in closed {
+            if let Err(e) = self.send(ParsedMessage::ReadingRollup(rollup)).await {
+                error!("Failed to queue reading rollup: {}", e);
             }
-            ParsedMessage::DeviceHealth(health) => {
-                health.insert(&self.db_client).await?;
+        }
+    }
+
+    /// Store an offline event for each device that just went silent
+    async fn emit_offline_events(&mut self) {
+        let Some(presence) = &mut self.presence else {
+            return;
+        };
+
+        let mut events: Vec<ParsedMessage> = presence
+            .scan(Utc::now())
+            .into_iter()
+            .map(ParsedMessage::OfflineEvent)
+            .collect();
+        apply_global_tags(&mut events, &self.parser_config);
+        apply_retention_class(&mut events, None, &self.parser_config);
+
+        for event in events {
+            if let Err(e) = self.send(event).await {
+                error!("Failed to queue offline event: {}", e);
             }
         }
+    }
 
-        Ok(())
+    async fn send(&self, message: ParsedMessage) -> Result<()> {
+        self.writer
+            .send(message)
+            .await
+            .with_context(|| "Writer task has stopped")
     }
 }
@@ -0,0 +1,202 @@
+use chrono::Utc;
+use serde_json::Value;
+use tracing::warn;
+
+use super::{extract_device_id, extract_topic_timestamp, ParseContext};
+use crate::config::{BinaryField, BinaryFrameSpec, Endianness, LengthPrefix, ParserConfig};
+use crate::db::SensorReading;
+
+/// Decode a fixed-width binary frame into one sensor reading per channel
+pub(super) fn parse_frame(
+    topic: &str,
+    frame: &[u8],
+    spec: &BinaryFrameSpec,
+    config: &ParserConfig,
+    ctx: &ParseContext,
+) -> Option<Vec<SensorReading>> {
+    if !frame.starts_with(&spec.header) {
+        warn!("Binary frame on topic {} has an unexpected header", topic);
+        return None;
+    }
+
+    // Device id comes from the frame when declared, otherwise from the topic
+    let device_id = match &spec.device_id {
+        Some(field) => read_raw(frame, field)?.to_string(),
+        None => extract_device_id(topic, &Value::Null, config, ctx)?,
+    };
+
+    let timestamp = extract_topic_timestamp(topic, config).unwrap_or_else(Utc::now);
+
+    let mut readings = Vec::with_capacity(spec.channels.len());
+    for channel in &spec.channels {
+        let Some(raw) = read_raw(frame, channel) else {
+            warn!(
+                "Binary frame on topic {} is too short for channel {} ({} bytes)",
+                topic,
+                channel.name,
+                frame.len()
+            );
+            continue;
+        };
+
+        readings.push(SensorReading::new(
+            device_id.clone(),
+            format!("{}/{}", topic, channel.name),
+            raw as f64 * channel.scale,
+            timestamp,
+        ));
+    }
+
+    if readings.is_empty() {
+        None
+    } else {
+        Some(readings)
+    }
+}
+
+/// Split a payload of concatenated frames, each preceded by its length in bytes
+/// (not counting the prefix). A truncated trailing frame is dropped with a
+/// warning; the complete frames before it are kept.
+pub(super) fn split_frames<'a>(topic: &str, payload: &'a [u8], prefix: &LengthPrefix) -> Vec<&'a [u8]> {
+    if !matches!(prefix.width, 1 | 2 | 4) {
+        warn!("Unsupported length prefix width {} for topic {}", prefix.width, topic);
+        return Vec::new();
+    }
+    let length_field = BinaryField {
+        name: String::new(),
+        offset: 0,
+        width: prefix.width,
+        signed: false,
+        endianness: prefix.endianness,
+        scale: 1.0,
+    };
+
+    let mut frames = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        let Some(length) = read_raw(rest, &length_field).and_then(|length| usize::try_from(length).ok()) else {
+            warn!("Payload on topic {} ends with a partial length prefix ({} bytes)", topic, rest.len());
+            break;
+        };
+        let Some(frame) = rest.get(prefix.width..prefix.width + length) else {
+            warn!(
+                "Payload on topic {} ends with a truncated frame ({} of {} bytes)",
+                topic,
+                rest.len() - prefix.width,
+                length
+            );
+            break;
+        };
+        frames.push(frame);
+        rest = &rest[prefix.width + length..];
+    }
+
+    frames
+}
+
+/// Read an integer field of 1, 2, 4 or 8 bytes, honoring endianness and sign
+fn read_raw(frame: &[u8], field: &BinaryField) -> Option<i128> {
+    if !matches!(field.width, 1 | 2 | 4 | 8) {
+        return None;
+    }
+
+    let bytes = frame.get(field.offset..field.offset.checked_add(field.width)?)?;
+
+    // Normalize into a little-endian 8-byte buffer
+    let mut buf = [0u8; 8];
+    match field.endianness {
+        Endianness::Little => buf[..field.width].copy_from_slice(bytes),
+        Endianness::Big => {
+            for (i, byte) in bytes.iter().rev().enumerate() {
+                buf[i] = *byte;
+            }
+        }
+    }
+
+    let unsigned = u64::from_le_bytes(buf);
+    if field.signed {
+        // Sign-extend from the field width
+        let shift = 64 - 8 * field.width as u32;
+        Some((((unsigned << shift) as i64) >> shift) as i128)
+    } else {
+        Some(unsigned as i128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(toml: &str) -> BinaryFrameSpec {
+        toml::from_str(toml).expect("valid frame spec")
+    }
+
+    const TWO_CHANNELS: &str = r#"
+        topic = "frames/#"
+        header = [170]
+        device_id = { offset = 1, width = 2, endianness = "big" }
+        channels = [
+            { name = "temp", offset = 3, width = 2, signed = true, endianness = "big", scale = 0.1 },
+            { name = "humidity", offset = 5, width = 1 },
+        ]
+    "#;
+
+    #[test]
+    fn two_channel_frame_decodes_into_two_readings() {
+        let frame = [0xAA, 0x01, 0x02, 0xFF, 0x9C, 55];
+
+        let readings = parse_frame(
+            "frames/gw",
+            &frame,
+            &spec(TWO_CHANNELS),
+            &ParserConfig::default(),
+            &ParseContext::default(),
+        )
+        .unwrap();
+
+        assert_eq!(readings.len(), 2);
+        assert!(readings.iter().all(|reading| reading.device_id == "258"));
+        assert_eq!(readings[0].topic, "frames/gw/temp");
+        assert!((readings[0].value + 10.0).abs() < 1e-9);
+        assert_eq!((readings[1].topic.as_str(), readings[1].value), ("frames/gw/humidity", 55.0));
+    }
+
+    #[test]
+    fn frame_with_unexpected_header_is_ignored() {
+        let frame = [0xBB, 0x01, 0x02, 0xFF, 0x9C, 55];
+
+        assert!(parse_frame(
+            "frames/gw",
+            &frame,
+            &spec(TWO_CHANNELS),
+            &ParserConfig::default(),
+            &ParseContext::default(),
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn two_length_prefixed_frames_are_split_and_decoded() {
+        let prefix: LengthPrefix = toml::from_str("width = 2\nendianness = \"big\"").expect("valid length prefix");
+        let frame = [0xAA, 0x01, 0x02, 0xFF, 0x9C, 55];
+        let mut payload = Vec::new();
+        for _ in 0..2 {
+            payload.extend_from_slice(&[0x00, 0x06]);
+            payload.extend_from_slice(&frame);
+        }
+        // A truncated trailing frame is dropped
+        payload.extend_from_slice(&[0x00, 0x06, 0xAA]);
+
+        let frames = split_frames("frames/gw", &payload, &prefix);
+
+        assert_eq!(frames, [&frame[..], &frame[..]]);
+        let readings: Vec<_> = frames
+            .iter()
+            .flat_map(|frame| {
+                parse_frame("frames/gw", frame, &spec(TWO_CHANNELS), &ParserConfig::default(), &ParseContext::default())
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(readings.len(), 4);
+    }
+}
@@ -0,0 +1,92 @@
+use serde_json::Value;
+use tracing::debug;
+
+use super::topic_path;
+use crate::config::ParserConfig;
+use crate::pipeline::BirthCache;
+
+/// Remember the device id carried by a birth message on a configured birth topic
+pub(super) fn remember(topic: &str, json: &Value, config: &ParserConfig, births: &mut BirthCache) {
+    let path = topic_path(topic);
+    if !config.birth_topics.iter().any(|filter| filter.matches(path)) {
+        return;
+    }
+
+    let Some(id) = json
+        .get("device_id")
+        .or_else(|| json.get("deviceId"))
+        .or_else(|| json.get("device"))
+        .and_then(|v| v.as_str())
+    else {
+        return;
+    };
+    let Some((parent, _)) = path.rsplit_once('/') else {
+        return;
+    };
+
+    if births.announce(parent, id) {
+        debug!("Birth message on {} announced device {}", topic, id);
+    }
+}
+
+/// Device id announced for the closest ancestor of the topic, if any
+pub(super) fn cached_device_id(topic: &str, config: &ParserConfig, births: Option<&BirthCache>) -> Option<String> {
+    let births = births.filter(|_| !config.birth_topics.is_empty())?;
+
+    let mut prefix = topic_path(topic);
+    while let Some((parent, _)) = prefix.rsplit_once('/') {
+        if let Some(id) = births.device_id(parent) {
+            return Some(id.to_string());
+        }
+        prefix = parent;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ParserConfig;
+    use crate::parser::{parse_message, ParseContext, ParsedMessage};
+    use crate::pipeline::BirthCache;
+
+    fn device_ids(topic: &str, payload: &str, config: &ParserConfig, births: &mut BirthCache) -> Vec<String> {
+        let mut ctx = ParseContext {
+            births: Some(births),
+            ..Default::default()
+        };
+        parse_message(topic, payload.as_bytes(), 0, false, config, &mut ctx)
+            .unwrap()
+            .into_iter()
+            .filter_map(|record| match record {
+                ParsedMessage::SensorReading(reading) => Some(reading.device_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn birth_message_seeds_the_id_of_later_readings() {
+        let config: ParserConfig = toml::from_str(r#"birth_topics = ["site/+/status"]"#).unwrap();
+        let mut births = BirthCache::new(8);
+        assert_eq!(device_ids("site/gw/temp", r#"{"temp": 21.5}"#, &config, &mut births), ["unknown"]);
+
+        device_ids("site/gw/status", r#"{"device_id": "boiler-7", "online": true}"#, &config, &mut births);
+
+        assert_eq!(device_ids("site/gw/temp", r#"{"temp": 21.5}"#, &config, &mut births), ["boiler-7"]);
+        assert_eq!(device_ids("site/other/temp", r#"{"temp": 21.5}"#, &config, &mut births), ["unknown"]);
+        assert_eq!(device_ids("site/gw/temp", r#"{"temp": 21.5}"#, &config, &mut BirthCache::new(8)), ["unknown"]);
+    }
+
+    #[test]
+    fn oldest_birth_is_forgotten_beyond_the_capacity() {
+        let config: ParserConfig = toml::from_str(r#"birth_topics = ["site/+/status"]"#).unwrap();
+        let mut births = BirthCache::new(1);
+
+        device_ids("site/a/status", r#"{"device_id": "boiler-a"}"#, &config, &mut births);
+        device_ids("site/b/status", r#"{"device_id": "boiler-b"}"#, &config, &mut births);
+
+        assert_eq!(device_ids("site/a/temp", r#"{"temp": 21.5}"#, &config, &mut births), ["unknown"]);
+        assert_eq!(device_ids("site/b/temp", r#"{"temp": 21.5}"#, &config, &mut births), ["boiler-b"]);
+    }
+}
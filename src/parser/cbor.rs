@@ -0,0 +1,110 @@
+use ciborium::value::Value as CborValue;
+use serde_json::{Map, Number, Value};
+
+use super::hex_encode;
+
+/// Decode a CBOR map into a JSON value so the regular extractors can run on it.
+///
+/// Map keys are coerced to strings (`1` → `"1"`) so field lookups such as
+/// `device_id` behave exactly as for JSON. Returns None unless the whole
+/// payload is a single CBOR map.
+pub(super) fn decode(payload: &[u8]) -> Option<Value> {
+    let mut reader = payload;
+    let value: CborValue = ciborium::from_reader(&mut reader).ok()?;
+
+    // Trailing bytes mean this was not a CBOR document after all
+    if !reader.is_empty() || !value.is_map() {
+        return None;
+    }
+
+    Some(to_json(value))
+}
+
+fn to_json(value: CborValue) -> Value {
+    match value {
+        CborValue::Null => Value::Null,
+        CborValue::Bool(b) => Value::Bool(b),
+        CborValue::Integer(i) => {
+            let i = i128::from(i);
+            i64::try_from(i)
+                .map(Number::from)
+                .or_else(|_| u64::try_from(i).map(Number::from))
+                .map(Value::Number)
+                .unwrap_or_else(|_| Value::String(i.to_string()))
+        }
+        CborValue::Float(f) => Number::from_f64(f).map(Value::Number).unwrap_or(Value::Null),
+        CborValue::Text(text) => Value::String(text),
+        CborValue::Bytes(bytes) => Value::String(hex_encode(&bytes)),
+        CborValue::Array(items) => Value::Array(items.into_iter().map(to_json).collect()),
+        CborValue::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                if let Some(key) = key_to_string(key) {
+                    map.insert(key, to_json(value));
+                }
+            }
+            Value::Object(map)
+        }
+        CborValue::Tag(_, inner) => to_json(*inner),
+        _ => Value::Null,
+    }
+}
+
+/// String form of a map key; composite keys (arrays, maps) are dropped
+fn key_to_string(key: CborValue) -> Option<String> {
+    match key {
+        CborValue::Text(text) => Some(text),
+        CborValue::Integer(i) => Some(i128::from(i).to_string()),
+        CborValue::Float(f) => Some(f.to_string()),
+        CborValue::Bool(b) => Some(b.to_string()),
+        CborValue::Bytes(bytes) => Some(hex_encode(&bytes)),
+        CborValue::Tag(_, inner) => key_to_string(*inner),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_message, ParsedMessage};
+
+    fn encode(entries: Vec<(CborValue, CborValue)>) -> Vec<u8> {
+        let mut payload = Vec::new();
+        ciborium::into_writer(&CborValue::Map(entries), &mut payload).expect("CBOR encodes");
+        payload
+    }
+
+    #[test]
+    fn integer_keys_are_coerced_to_strings() {
+        let payload = encode(vec![(CborValue::Integer(1.into()), CborValue::Float(21.5))]);
+
+        assert_eq!(decode(&payload), Some(serde_json::json!({"1": 21.5})));
+    }
+
+    #[test]
+    fn device_id_resolves_in_integer_keyed_map() {
+        let payload = encode(vec![
+            (CborValue::Integer(1.into()), CborValue::Float(21.5)),
+            (CborValue::Text("device_id".to_string()), CborValue::Text("sensor-42".to_string())),
+        ]);
+
+        let records = parse_message(
+            "sensors/cbor",
+            &payload,
+            0,
+            false,
+            &Default::default(),
+            &mut Default::default(),
+        )
+        .unwrap();
+
+        let readings: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::SensorReading(reading) => Some((reading.device_id.as_str(), reading.topic.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(readings, [("sensor-42", "sensors/cbor/1")]);
+    }
+}
@@ -0,0 +1,100 @@
+use chrono::Utc;
+use serde_json::Value;
+use tracing::warn;
+
+use super::{extract_device_id, extract_topic_timestamp, parse_timestamp_text, ParseContext};
+use crate::config::{CsvSchema, ParserConfig};
+use crate::db::SensorReading;
+
+/// Decode CSV rows into one sensor reading per numeric column
+pub(super) fn parse_rows(
+    topic: &str,
+    text: &str,
+    schema: &CsvSchema,
+    config: &ParserConfig,
+    ctx: &ParseContext,
+) -> Option<Vec<SensorReading>> {
+    let device_id = extract_device_id(topic, &Value::Null, config, ctx)?;
+    let timestamp_index = schema
+        .timestamp_column
+        .as_ref()
+        .and_then(|name| schema.columns.iter().position(|column| column == name));
+
+    let mut readings = Vec::new();
+    for row in text.lines().map(str::trim).filter(|row| !row.is_empty()) {
+        let fields: Vec<&str> = row.split(schema.delimiter).map(str::trim).collect();
+
+        let timestamp = timestamp_index
+            .and_then(|index| fields.get(index))
+            .and_then(|field| {
+                let parsed = parse_timestamp_text(field);
+                if parsed.is_none() {
+                    warn!("Invalid CSV timestamp {:?} on topic {}", field, topic);
+                }
+                parsed
+            })
+            .or_else(|| extract_topic_timestamp(topic, config))
+            .unwrap_or_else(Utc::now);
+
+        for (index, (column, field)) in schema.columns.iter().zip(&fields).enumerate() {
+            if column.is_empty() || Some(index) == timestamp_index {
+                continue;
+            }
+
+            // "NaN" and "inf" parse as floats but are not readings
+            let Some(value) = field.parse::<f64>().ok().filter(|value| value.is_finite()) else {
+                continue;
+            };
+
+            readings.push(SensorReading::new(
+                device_id.clone(),
+                format!("{}/{}", topic, column),
+                value,
+                timestamp,
+            ));
+        }
+    }
+
+    if readings.is_empty() {
+        None
+    } else {
+        Some(readings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(toml: &str) -> CsvSchema {
+        toml::from_str(toml).expect("valid CSV schema")
+    }
+
+    #[test]
+    fn timestamp_column_is_honored() {
+        let schema = schema(
+            r#"
+            topic = "csv/#"
+            columns = ["ts", "temp", "humidity"]
+            timestamp_column = "ts"
+            "#,
+        );
+
+        let text = "1700000000,21.5,40\n2024-01-01T12:00:00Z,22.0,41\n";
+        let readings = parse_rows("csv/d1", text, &schema, &ParserConfig::default(), &ParseContext::default()).unwrap();
+
+        let readings: Vec<_> = readings
+            .iter()
+            .map(|reading| (reading.timestamp.to_rfc3339(), reading.topic.as_str(), reading.value))
+            .collect();
+        assert_eq!(
+            readings,
+            [
+                ("2023-11-14T22:13:20+00:00".to_string(), "csv/d1/temp", 21.5),
+                ("2023-11-14T22:13:20+00:00".to_string(), "csv/d1/humidity", 40.0),
+                ("2024-01-01T12:00:00+00:00".to_string(), "csv/d1/temp", 22.0),
+                ("2024-01-01T12:00:00+00:00".to_string(), "csv/d1/humidity", 41.0),
+            ]
+        );
+    }
+}
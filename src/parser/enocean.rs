@@ -0,0 +1,163 @@
+use chrono::Utc;
+use serde_json::Value;
+use tracing::warn;
+
+use super::{extract_device_id, extract_topic_timestamp, topic_segments, ParseContext};
+use crate::config::{EepField, EnOceanConfig, ParserConfig};
+use crate::db::SensorReading;
+
+/// Decode an EnOcean telegram with the field layout of the EEP profile named in the topic
+pub(super) fn parse_telegram(
+    topic: &str,
+    data: &[u8],
+    enocean: &EnOceanConfig,
+    config: &ParserConfig,
+    ctx: &ParseContext,
+) -> Option<Vec<SensorReading>> {
+    let profile_id = topic_segments(topic, config).nth(enocean.profile_segment)?;
+    let Some(fields) = enocean
+        .profiles
+        .iter()
+        .find(|(id, _)| normalize_profile_id(id) == normalize_profile_id(&profile_id))
+        .map(|(_, fields)| fields)
+    else {
+        warn!("No EEP profile {} registered for topic {}", profile_id, topic);
+        return None;
+    };
+
+    let device_id = match enocean.device_segment {
+        Some(index) => topic_segments(topic, config).nth(index)?.into_owned(),
+        None => extract_device_id(topic, &Value::Null, config, ctx)?,
+    };
+    let timestamp = extract_topic_timestamp(topic, config).unwrap_or_else(Utc::now);
+
+    let mut readings = Vec::with_capacity(fields.len());
+    for field in fields {
+        let Some(raw) = read_bits(data, field.offset, field.size) else {
+            warn!(
+                "EnOcean telegram on topic {} is too short for field {} ({} bytes)",
+                topic,
+                field.name,
+                data.len()
+            );
+            continue;
+        };
+
+        readings.push(
+            SensorReading::new(
+                device_id.clone(),
+                format!("{}/{}", topic, field.name),
+                scale(raw, field),
+                timestamp,
+            )
+            .with_unit(field.unit.clone()),
+        );
+    }
+
+    if readings.is_empty() {
+        None
+    } else {
+        Some(readings)
+    }
+}
+
+/// Telegram data bytes, hex-decoded when the payload is hex text (e.g., "0000A408")
+pub(super) fn telegram_data(payload: &[u8]) -> Vec<u8> {
+    let text = String::from_utf8_lossy(payload);
+    let text = text.trim();
+
+    let is_hex = !text.is_empty() && text.len().is_multiple_of(2) && text.bytes().all(|b| b.is_ascii_hexdigit());
+    if !is_hex {
+        return payload.to_vec();
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .filter_map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// "a5-02-05" and "A50205" name the same profile
+fn normalize_profile_id(id: &str) -> String {
+    id.chars().filter(|c| *c != '-').collect::<String>().to_uppercase()
+}
+
+/// Read `size` bits starting `offset` bits from the most significant bit of `data`
+fn read_bits(data: &[u8], offset: usize, size: usize) -> Option<u64> {
+    if size == 0 || size > 32 || offset.checked_add(size)? > data.len() * 8 {
+        return None;
+    }
+
+    let value = (offset..offset + size).fold(0u64, |value, bit| {
+        let set = data[bit / 8] >> (7 - bit % 8) & 1;
+        value << 1 | u64::from(set)
+    });
+
+    Some(value)
+}
+
+/// Map the raw value linearly from the field's raw range onto its scale
+fn scale(raw: u64, field: &EepField) -> f64 {
+    let raw = raw as f64;
+    let [raw_min, raw_max] = field.range.unwrap_or([0.0, ((1u64 << field.size) - 1) as f64]);
+    let Some([scale_min, scale_max]) = field.scale else {
+        return raw;
+    };
+
+    if raw_max == raw_min {
+        return scale_min;
+    }
+
+    scale_min + (raw - raw_min) * (scale_max - scale_min) / (raw_max - raw_min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enocean() -> EnOceanConfig {
+        toml::from_str(
+            r#"
+            topic = "enocean/#"
+            profile_segment = 1
+            device_segment = 2
+            [profiles]
+            "A5-02-05" = [{ name = "temperature", offset = 16, size = 8, range = [255, 0], scale = [0, 40], unit = "C" }]
+            "#,
+        )
+        .expect("valid EnOcean config")
+    }
+
+    #[test]
+    fn registered_profile_payload_is_decoded() {
+        let data = telegram_data(b"0000A408");
+        assert_eq!(data, [0x00, 0x00, 0xA4, 0x08]);
+
+        let readings = parse_telegram(
+            "enocean/a5-02-05/0180ABCD",
+            &data,
+            &enocean(),
+            &ParserConfig::default(),
+            &ParseContext::default(),
+        )
+        .unwrap();
+
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].device_id, "0180ABCD");
+        assert_eq!(readings[0].topic, "enocean/a5-02-05/0180ABCD/temperature");
+        assert_eq!(readings[0].unit.as_deref(), Some("C"));
+        assert!((readings[0].value - 91.0 * 40.0 / 255.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unregistered_profile_is_skipped() {
+        assert!(parse_telegram(
+            "enocean/d2-01-12/0180ABCD",
+            &[0; 4],
+            &enocean(),
+            &ParserConfig::default(),
+            &ParseContext::default(),
+        )
+        .is_none());
+    }
+}
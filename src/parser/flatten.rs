@@ -0,0 +1,95 @@
+use serde_json::Value;
+
+use super::{extract_device_id, extract_timestamp, lookup_path, ParseContext};
+use crate::config::{ColumnType, FlattenedTable, ParserConfig};
+use crate::db::{ColumnValue, FlattenedRow};
+
+/// Build a row for a flattened table from the configured payload paths
+pub(super) fn build_row(
+    topic: &str,
+    json: &Value,
+    table: &FlattenedTable,
+    config: &ParserConfig,
+    ctx: &ParseContext,
+) -> Option<FlattenedRow> {
+    let device_id = extract_device_id(topic, json, config, ctx)?;
+
+    let columns = table
+        .columns
+        .iter()
+        .map(|column| {
+            let value = lookup_path(json, &column.path);
+            (column.column.clone(), convert(value, column.column_type))
+        })
+        .collect();
+
+    Some(FlattenedRow {
+        table: table.table.clone(),
+        device_id,
+        topic: topic.to_string(),
+        columns,
+        timestamp: extract_timestamp(topic, json, config),
+    })
+}
+
+/// Convert a payload value to the column type; mismatches are stored as NULL
+fn convert(value: Option<&Value>, column_type: ColumnType) -> ColumnValue {
+    let value = value.filter(|value| !value.is_null());
+
+    match column_type {
+        ColumnType::Float => ColumnValue::Float(
+            value
+                .and_then(|value| match value {
+                    Value::String(text) => text.trim().parse().ok(),
+                    value => value.as_f64(),
+                })
+                .filter(|value: &f64| value.is_finite()),
+        ),
+        ColumnType::Integer => ColumnValue::Integer(value.and_then(|value| match value {
+            Value::String(text) => text.trim().parse().ok(),
+            value => value.as_i64(),
+        })),
+        ColumnType::Text => ColumnValue::Text(value.map(|value| match value {
+            Value::String(text) => text.clone(),
+            value => value.to_string(),
+        })),
+        ColumnType::Boolean => ColumnValue::Boolean(value.and_then(Value::as_bool)),
+        ColumnType::Json => ColumnValue::Json(value.cloned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_nested_fields_flatten_into_columns() {
+        let table: FlattenedTable = toml::from_str(
+            r#"
+            topic = "pumps/#"
+            table = "pump_status"
+            columns = [
+                { path = "motor.rpm", column = "motor_rpm", type = "integer" },
+                { path = "motor.phases.0.current", column = "phase_a_current", type = "float" },
+            ]
+            "#,
+        )
+        .expect("valid flattened table");
+        let json = serde_json::json!({"device_id": "p1", "motor": {"rpm": 1450, "phases": [{"current": 3.2}]}});
+
+        let row = build_row("pumps/p1", &json, &table, &ParserConfig::default(), &ParseContext::default()).unwrap();
+
+        assert_eq!((row.table.as_str(), row.device_id.as_str()), ("pump_status", "p1"));
+        let [(rpm_column, rpm), (current_column, current)] = row.columns.as_slice() else {
+            panic!("expected two columns, got {:?}", row.columns);
+        };
+        assert_eq!((rpm_column.as_str(), current_column.as_str()), ("motor_rpm", "phase_a_current"));
+        assert!(matches!(rpm, ColumnValue::Integer(Some(1450))));
+        assert!(matches!(current, ColumnValue::Float(Some(current)) if *current == 3.2));
+    }
+
+    #[test]
+    fn mismatched_type_is_stored_as_null() {
+        assert!(matches!(convert(Some(&Value::from("fast")), ColumnType::Integer), ColumnValue::Integer(None)));
+    }
+}
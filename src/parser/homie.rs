@@ -0,0 +1,107 @@
+use chrono::Utc;
+
+use super::{extract_topic_timestamp, topic_segments, ParsedMessage};
+use crate::config::ParserConfig;
+use crate::db::{DeviceLog, DeviceState, SensorReading};
+
+/// Parse a Homie convention message (`<base>/<device>/<node>/<property>`).
+///
+/// Property values become sensor readings, the device `$state` attribute becomes
+/// a device state, and other `$` attributes are kept as debug logs. Returns None
+/// for topics outside the base topic.
+pub(super) fn parse(topic: &str, text: &str, base: &str, config: &ParserConfig) -> Option<Vec<ParsedMessage>> {
+    let segments: Vec<String> = topic_segments(topic, config).map(|s| s.into_owned()).collect();
+    if segments.first().map(String::as_str) != Some(base) {
+        return None;
+    }
+
+    let device_id = segments.get(1).filter(|id| !id.is_empty())?.clone();
+    let timestamp = extract_topic_timestamp(topic, config).unwrap_or_else(Utc::now);
+    let value = text.trim();
+
+    let record = match &segments[2..] {
+        // Device lifecycle: init, ready, disconnected, sleeping, lost, alert
+        [attribute] if attribute == "$state" => ParsedMessage::DeviceState(DeviceState {
+            device_id,
+            topic: topic.to_string(),
+            main_state: None,
+            secondary_state: None,
+            alerts: None,
+            rssi: None,
+            state_reason: Some(value.to_string()),
+            tags: None,
+            retention_class: None,
+            schema_version: None,
+            topic_id: None,
+            device_class: None,
+            timestamp,
+        }),
+        // Device and node metadata such as `$name`, `$fw/version` or `<node>/$properties`
+        attributes if attributes.iter().any(|segment| segment.starts_with('$')) => {
+            ParsedMessage::DeviceLog(DeviceLog {
+                device_id,
+                level: "DEBUG".to_string(),
+                message: format!("{} = {}", attributes.join("/"), value),
+                topic: topic.to_string(),
+                tags: None,
+                retention_class: None,
+                schema_version: None,
+                topic_id: None,
+                device_class: None,
+                timestamp,
+            })
+        }
+        // Property value; `<node>/<property>/set` command topics are ignored
+        [_node, _property] => {
+            let value = match value {
+                "true" => 1.0,
+                "false" => 0.0,
+                other => match other.parse::<f64>() {
+                    Ok(value) if value.is_finite() => value,
+                    _ => return Some(Vec::new()),
+                },
+            };
+            ParsedMessage::SensorReading(SensorReading::new(device_id, topic.to_string(), value, timestamp))
+        }
+        _ => return Some(Vec::new()),
+    };
+
+    let enabled = match &record {
+        ParsedMessage::DeviceState(_) => config.records.device_states,
+        ParsedMessage::DeviceLog(_) => config.records.device_logs,
+        _ => config.records.sensor_readings,
+    };
+
+    Some(if enabled { vec![record] } else { Vec::new() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn property_topic_becomes_a_reading() {
+        let records = parse("homie/d1/env/temperature", "21.5", "homie", &ParserConfig::default()).unwrap();
+
+        let [ParsedMessage::SensorReading(reading)] = records.as_slice() else {
+            panic!("expected one reading, got {:?}", records);
+        };
+        assert_eq!((reading.device_id.as_str(), reading.value), ("d1", 21.5));
+        assert_eq!(reading.topic, "homie/d1/env/temperature");
+    }
+
+    #[test]
+    fn state_attribute_becomes_a_device_state() {
+        let records = parse("homie/d1/$state", "ready", "homie", &ParserConfig::default()).unwrap();
+
+        let [ParsedMessage::DeviceState(state)] = records.as_slice() else {
+            panic!("expected one state, got {:?}", records);
+        };
+        assert_eq!(state.state_reason.as_deref(), Some("ready"));
+    }
+
+    #[test]
+    fn topics_outside_the_base_are_not_homie() {
+        assert!(parse("sensors/d1/env/temperature", "21.5", "homie", &ParserConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,205 @@
+use chrono::Utc;
+use serde_json::{Map, Value};
+
+use super::{extract_device_id, ParseContext};
+use crate::config::ParserConfig;
+use crate::db::SensorReading;
+
+/// Parse InfluxDB line protocol
+/// (`weather,device=abc temperature=25.5,humidity=60i 1465839830100400200`).
+///
+/// Each numeric field becomes a reading on `<topic>/<measurement>/<field>`; the
+/// `device` tag names the device and other tags are kept as reading tags. String
+/// and boolean fields are skipped, and readings without a timestamp are stamped
+/// with the current time. Returns None unless every line is valid line protocol.
+pub(super) fn parse_line_protocol(
+    topic: &str,
+    text: &str,
+    config: &ParserConfig,
+    ctx: &ParseContext,
+) -> Option<Vec<SensorReading>> {
+    let mut readings = Vec::new();
+
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let sections: Vec<&str> = split_unescaped(line, ' ').into_iter().filter(|s| !s.is_empty()).collect();
+        let (series, fields, timestamp) = match sections.as_slice() {
+            [series, fields] => (*series, *fields, None),
+            [series, fields, timestamp] => (*series, *fields, Some(*timestamp)),
+            _ => return None,
+        };
+
+        let mut series = split_unescaped(series, ',').into_iter();
+        let measurement = unescape(series.next().filter(|m| !m.is_empty())?);
+
+        let mut device_id = None;
+        let mut tags = Map::new();
+        for tag in series {
+            let (key, value) = split_pair(tag)?;
+            if key == "device" {
+                device_id = Some(value);
+            } else {
+                tags.insert(key, Value::String(value));
+            }
+        }
+
+        let timestamp = match timestamp {
+            Some(ns) => chrono::DateTime::from_timestamp_nanos(ns.parse().ok()?),
+            None => Utc::now(),
+        };
+        let device_id = device_id.or_else(|| extract_device_id(topic, &Value::Null, config, ctx))?;
+        let tags = (!tags.is_empty()).then_some(Value::Object(tags));
+
+        for field in split_unescaped(fields, ',') {
+            let (key, value) = split_pair(field)?;
+            let Some((value, int_value)) = field_value(&value)? else {
+                continue;
+            };
+
+            readings.push(
+                SensorReading::new(
+                    device_id.clone(),
+                    format!("{}/{}/{}", topic, measurement, key),
+                    value,
+                    timestamp,
+                )
+                .with_int_value(int_value)
+                .with_tags(tags.clone()),
+            );
+        }
+    }
+
+    if readings.is_empty() {
+        None
+    } else {
+        Some(readings)
+    }
+}
+
+/// Numeric value of a field: `Some(None)` for valid non-numeric (string or
+/// boolean) fields, None for malformed ones
+fn field_value(raw: &str) -> Option<Option<(f64, Option<i64>)>> {
+    if raw.len() >= 2 && raw.starts_with('"') && raw.ends_with('"') {
+        return Some(None);
+    }
+    if matches!(raw, "t" | "T" | "true" | "True" | "TRUE" | "f" | "F" | "false" | "False" | "FALSE") {
+        return Some(None);
+    }
+
+    if let Some(int) = raw.strip_suffix('i') {
+        let int: i64 = int.parse().ok()?;
+        return Some(Some((int as f64, Some(int))));
+    }
+    if let Some(uint) = raw.strip_suffix('u') {
+        let uint: u64 = uint.parse().ok()?;
+        return Some(Some((uint as f64, i64::try_from(uint).ok())));
+    }
+
+    let value: f64 = raw.parse().ok()?;
+    Some(value.is_finite().then_some((value, None)))
+}
+
+/// Split on a separator, ignoring backslash-escaped separators and those inside quotes
+fn split_unescaped(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+
+    for (index, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&text[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+
+    parts
+}
+
+/// Split a `key=value` pair on its first unescaped `=`
+fn split_pair(pair: &str) -> Option<(String, String)> {
+    let mut parts = split_unescaped(pair, '=');
+    if parts.len() < 2 || parts[0].is_empty() {
+        return None;
+    }
+    let key = unescape(parts.remove(0));
+    let value = parts.join("=");
+
+    Some((key, unescape(&value)))
+}
+
+/// Drop the backslashes escaping commas, spaces, equals signs and quotes
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(',' | ' ' | '=' | '"' | '\\')) => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_become_readings_with_the_device_tag() {
+        let text = r#"weather,device=abc,site=roof temperature=25.5,humidity=60i,status="ok" 1465839830100400200"#;
+        let readings = parse_line_protocol("influx", text, &ParserConfig::default(), &ParseContext::default()).unwrap();
+
+        let readings: Vec<_> = readings
+            .iter()
+            .map(|reading| (reading.device_id.as_str(), reading.topic.as_str(), reading.value, reading.int_value))
+            .collect();
+        assert_eq!(
+            readings,
+            [
+                ("abc", "influx/weather/temperature", 25.5, None),
+                ("abc", "influx/weather/humidity", 60.0, Some(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn trailing_timestamp_is_in_nanoseconds() {
+        let text = "weather,device=abc temperature=25.5 1465839830100400200";
+        let readings = parse_line_protocol("influx", text, &ParserConfig::default(), &ParseContext::default()).unwrap();
+
+        assert_eq!(readings[0].timestamp.timestamp_nanos_opt(), Some(1_465_839_830_100_400_200));
+        assert_eq!(readings[0].tags, None);
+    }
+
+    #[test]
+    fn missing_timestamp_falls_back_to_now() {
+        let before = Utc::now();
+        let text = "weather,device=abc temperature=25.5";
+        let readings = parse_line_protocol("influx", text, &ParserConfig::default(), &ParseContext::default()).unwrap();
+
+        assert!(readings[0].timestamp >= before);
+    }
+
+    #[test]
+    fn non_line_protocol_text_is_rejected() {
+        assert!(parse_line_protocol(
+            "influx",
+            "just a log line",
+            &ParserConfig::default(),
+            &ParseContext::default(),
+        )
+        .is_none());
+    }
+}
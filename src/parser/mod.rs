@@ -1,310 +1,2965 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::time::{Duration, Instant};
+
 use chrono::Utc;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
 use serde_json::Value;
 use tracing::{debug, warn};
 
-use crate::db::{DeviceHealth, DeviceLog, DeviceState, SensorReading, SocketRead};
+use crate::config::{
+    HealthTimestampEpoch, MissingDeviceIdPolicy, ParserConfig, QualityCodes, TimestampUnit, ValueSensorsPolicy,
+};
+use crate::db::{
+    DeadLetter, DeadLetterReason, DeviceAlert, DeviceCapability, DeviceHealth, DeviceLog, DeviceState, FlattenedRow,
+    MalformedPayload, OfflineEvent, ReadingRollup, SensorReading, SocketRead, TaskHealth,
+};
+use crate::metrics::METRICS;
+use crate::pipeline::BirthCache;
+use crate::stats::{self, COUNTERS};
 
-/// Parse MQTT message into database records
-pub fn parse_message(topic: &str, payload: &[u8]) -> Vec<ParsedMessage> {
-    let mut results = Vec::new();
+mod binary;
+mod birth;
+#[cfg(feature = "cbor")]
+mod cbor;
+mod csv;
+mod enocean;
+mod flatten;
+mod homie;
+mod line_protocol;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+mod syslog;
+#[cfg(feature = "xml")]
+mod xml;
 
-    // Convert payload to string
-    let payload_str = match String::from_utf8(payload.to_vec()) {
-        Ok(s) => s,
-        Err(e) => {
-            warn!("Failed to decode payload as UTF-8: {}", e);
-            return results;
+/// Reasons a message is rejected outright rather than parsed
+#[derive(Debug)]
+pub enum ParseError {
+    /// No device id in the payload or topic under the `error` policy
+    MissingDeviceId,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingDeviceId => write!(f, "Cannot determine device id"),
         }
-    };
+    }
+}
 
-    // Always store raw message
-    results.push(ParsedMessage::SocketRead(SocketRead {
-        topic: topic.to_string(),
-        payload: payload_str.clone(),
-        timestamp: Utc::now(),
-    }));
-
-    // Try to parse as JSON
-    if let Ok(json) = serde_json::from_str::<Value>(&payload_str) {
-        // Parse device state and health (priority - most specific format)
-        if let Some((state, health)) = parse_device_state_and_health(topic, &json) {
-            results.push(ParsedMessage::DeviceState(state));
-            if let Some(h) = health {
-                results.push(ParsedMessage::DeviceHealth(h));
-            }
-        } else {
-            // Parse sensor readings
-            if let Some(readings) = parse_sensor_readings(topic, &json) {
-                results.extend(readings.into_iter().map(ParsedMessage::SensorReading));
-            }
+impl std::error::Error for ParseError {}
 
-            // Parse device logs
-            if let Some(log) = parse_device_log(topic, &json) {
-                results.push(ParsedMessage::DeviceLog(log));
-            }
+/// Per-message state the parser works with alongside its configuration
+#[derive(Default)]
+pub struct ParseContext<'a> {
+    /// Device ids announced by birth messages, kept across messages; birth
+    /// topics are ignored without it
+    pub births: Option<&'a mut BirthCache>,
+    /// Common name of the mTLS client certificate the message arrived under,
+    /// stored as the device id of every record over anything in the topic or payload
+    pub cert_cn: Option<&'a str>,
+}
+
+/// Parse MQTT message into database records. The delivery QoS and retain flag
+/// are recorded on the raw socket read.
+pub fn parse_message(
+    topic: &str,
+    payload: &[u8],
+    qos: i16,
+    retained: bool,
+    config: &ParserConfig,
+    ctx: &mut ParseContext,
+) -> Result<Vec<ParsedMessage>, ParseError> {
+    let started = Instant::now();
+    METRICS.messages_received.inc();
+
+    if !config.topics.allows(topic_path(topic)) {
+        debug!("Ignoring message on filtered topic {}", topic);
+        return Ok(Vec::new());
+    }
+
+    // Rename legacy topics before any records are built
+    let topic = rewrite_topic(topic, config);
+    let topic = topic.as_ref();
+
+    let mut results = match parse_payload(topic, payload, config, ctx) {
+        Err(ParseError::MissingDeviceId) if config.records.dead_letters => {
+            let payload = String::from_utf8_lossy(payload).into_owned();
+            vec![dead_letter(topic, payload, DeadLetterReason::MissingDeviceId)]
         }
-    } else {
-        // Try to parse as plain text log
-        if let Some(log) = parse_plain_text_log(topic, &payload_str) {
-            results.push(ParsedMessage::DeviceLog(log));
+        parsed => parsed?,
+    };
+
+    for record in results.iter_mut() {
+        if let ParsedMessage::SocketRead(read) = record {
+            read.qos = qos;
+            read.retained = retained;
+        }
+        // Decoders with their own id sources (binary frames, EnOcean, Homie) are overridden here
+        if let (Some(cn), Some(device_id)) = (ctx.cert_cn, record.device_id_mut()) {
+            *device_id = cn.to_string();
         }
     }
 
-    debug!("Parsed {} records from topic {}", results.len(), topic);
-    results
+    stamp_schema_version(&mut results, detect_schema_version(topic, None, config));
+    stamp_sensor_types(&mut results, config);
+    apply_global_tags(&mut results, config);
+    apply_retention_class(&mut results, Some(topic), config);
+
+    for record in &results {
+        METRICS.records_parsed.with_label_values(&[record.kind()]).inc();
+    }
+
+    // Report parses exceeding the configured latency threshold
+    if let Some(threshold_ms) = config.slow_parse_threshold_ms {
+        let elapsed = started.elapsed();
+        if elapsed >= Duration::from_millis(threshold_ms) {
+            let total = stats::increment(&COUNTERS.slow_parses);
+            warn!(
+                "Slow parse on topic {}: {} bytes took {:?} (threshold {}ms, {} slow parses so far)",
+                topic,
+                payload.len(),
+                elapsed,
+                threshold_ms,
+                total
+            );
+        }
+    }
+
+    Ok(results)
 }
 
-#[derive(Debug)]
-pub enum ParsedMessage {
-    SensorReading(SensorReading),
-    SocketRead(SocketRead),
-    DeviceLog(DeviceLog),
-    DeviceState(DeviceState),
-    DeviceHealth(DeviceHealth),
+/// Apply the first matching topic rewrite, keeping any `?query` suffix
+fn rewrite_topic<'a>(topic: &'a str, config: &ParserConfig) -> Cow<'a, str> {
+    let path = topic_path(topic);
+    let query = &topic[path.len()..];
+
+    config
+        .topic_rewrite
+        .iter()
+        .find_map(|(filter, template)| filter.rewrite(path, template))
+        .map_or(Cow::Borrowed(topic), |rewritten| Cow::Owned(rewritten + query))
 }
 
-/// Parse JSON sensor readings
-fn parse_sensor_readings(topic: &str, json: &Value) -> Option<Vec<SensorReading>> {
-    let mut readings = Vec::new();
+/// Schema version from the first configured payload field, else the topic segment
+fn detect_schema_version(topic: &str, json: Option<&Value>, config: &ParserConfig) -> Option<String> {
+    let schema = config.schema_version.as_ref()?;
 
-    // Extract device_id from topic or JSON
-    let device_id = extract_device_id(topic, json)?;
+    let from_payload = json.and_then(|json| {
+        schema.fields.iter().find_map(|field| match json.get(field)? {
+            Value::String(version) => Some(version.clone()),
+            Value::Number(version) => Some(version.to_string()),
+            _ => None,
+        })
+    });
 
-    // Handle single sensor value
-    if let Some(value) = json.get("value").and_then(|v| v.as_f64()) {
-        readings.push(SensorReading {
-            device_id: device_id.clone(),
-            topic: topic.to_string(),
-            value,
-            timestamp: extract_timestamp(json),
-        });
-    }
+    from_payload.or_else(|| {
+        let index = schema.topic_segment?;
+        topic_segments(topic, config).nth(index).map(Cow::into_owned)
+    })
+}
 
-    // Handle multiple sensor values in "sensors" array
-    if let Some(sensors) = json.get("sensors").and_then(|v| v.as_array()) {
-        for sensor in sensors {
-            if let (Some(name), Some(value)) = (
-                sensor.get("name").and_then(|v| v.as_str()),
-                sensor.get("value").and_then(|v| v.as_f64()),
-            ) {
-                readings.push(SensorReading {
-                    device_id: device_id.clone(),
-                    topic: format!("{}/{}", topic, name),
-                    value,
-                    timestamp: extract_timestamp(json),
-                });
-            }
+/// Stamp the schema version onto records that do not have one yet
+fn stamp_schema_version(records: &mut [ParsedMessage], version: Option<String>) {
+    let Some(version) = version else {
+        return;
+    };
+
+    for record in records.iter_mut() {
+        if let Some(slot @ None) = record.schema_version_mut() {
+            *slot = Some(version.clone());
         }
     }
+}
 
-    // Handle flat JSON with numeric values (e.g., {"temperature": 25.5, "humidity": 60.0})
-    if let Some(obj) = json.as_object() {
-        for (key, value) in obj {
-            if let Some(num) = value.as_f64() {
-                if key != "timestamp" && key != "device_id" {
-                    readings.push(SensorReading {
-                        device_id: device_id.clone(),
-                        topic: format!("{}/{}", topic, key),
-                        value: num,
-                        timestamp: extract_timestamp(json),
-                    });
-                }
-            }
-        }
+/// Classify sensor readings by the configured metric taxonomy
+fn stamp_sensor_types(records: &mut [ParsedMessage], config: &ParserConfig) {
+    if config.sensor_types.is_empty() {
+        return;
     }
 
-    if readings.is_empty() {
-        None
-    } else {
-        Some(readings)
+    for record in records.iter_mut() {
+        if let ParsedMessage::SensorReading(reading) = record {
+            let metric = reading.topic.rsplit('/').next().unwrap_or_default();
+            reading.sensor_type = config.sensor_types.get(metric).cloned();
+        }
     }
 }
 
-/// Parse device log from JSON
-fn parse_device_log(topic: &str, json: &Value) -> Option<DeviceLog> {
-    // Check if this looks like a log message
-    let level = json
-        .get("level")
-        .or_else(|| json.get("severity"))
-        .and_then(|v| v.as_str())?;
+/// Stamp deployment-wide tags onto every record
+pub fn apply_global_tags(records: &mut [ParsedMessage], config: &ParserConfig) {
+    if config.global_tags.is_empty() {
+        return;
+    }
 
-    let message = json
-        .get("message")
-        .or_else(|| json.get("msg"))
-        .or_else(|| json.get("text"))
-        .and_then(|v| v.as_str())?;
+    let tags = serde_json::to_value(&config.global_tags).ok();
+    for record in records.iter_mut() {
+        if let Some(slot) = record.tags_mut() {
+            *slot = tags.clone();
+        }
+    }
+}
 
-    let device_id = extract_device_id(topic, json)?;
+/// Stamp the retention class resolved from the topic onto every record.
+/// Records without a topic (e.g., offline events) get the default class.
+pub fn apply_retention_class(records: &mut [ParsedMessage], topic: Option<&str>, config: &ParserConfig) {
+    let Some(class) = resolve_retention_class(topic, config) else {
+        return;
+    };
 
-    Some(DeviceLog {
-        device_id,
-        level: level.to_string(),
-        message: message.to_string(),
-        topic: topic.to_string(),
-        timestamp: extract_timestamp(json),
-    })
+    for record in records.iter_mut() {
+        if let Some(slot) = record.retention_class_mut() {
+            *slot = Some(class.clone());
+        }
+    }
 }
 
-/// Parse plain text log
-fn parse_plain_text_log(topic: &str, text: &str) -> Option<DeviceLog> {
-    // Extract device_id from topic
-    let device_id = topic
-        .split('/')
-        .find(|part| !part.is_empty() && *part != "diagnostics" && *part != "debug" && *part != "logs")
-        .unwrap_or("unknown")
-        .to_string();
+/// Retention class of the first rule matching the topic, else the configured default
+fn resolve_retention_class(topic: Option<&str>, config: &ParserConfig) -> Option<String> {
+    topic
+        .and_then(|topic| {
+            config
+                .retention_classes
+                .iter()
+                .find(|rule| rule.topic.matches(topic_path(topic)))
+        })
+        .map(|rule| rule.class.clone())
+        .or_else(|| config.default_retention_class.clone())
+}
 
-    // Determine log level from topic or content
-    let level = if topic.contains("error") || text.to_lowercase().contains("error") {
-        "ERROR"
-    } else if topic.contains("warn") || text.to_lowercase().contains("warn") {
-        "WARN"
-    } else if topic.contains("debug") || topic.contains("diagnostics") {
-        "DEBUG"
-    } else {
-        "INFO"
-    };
+fn parse_payload(
+    topic: &str,
+    payload: &[u8],
+    config: &ParserConfig,
+    ctx: &mut ParseContext,
+) -> Result<Vec<ParsedMessage>, ParseError> {
+    let mut results = Vec::new();
 
-    Some(DeviceLog {
-        device_id,
-        level: level.to_string(),
-        message: text.to_string(),
-        topic: topic.to_string(),
-        timestamp: Utc::now(),
-    })
-}
+    // Decode configured binary frame layouts before any text handling
+    if let Some(spec) = config.binary_frames.iter().find(|spec| spec.topic.matches(topic_path(topic))) {
+        if spec.device_id.is_none() {
+            require_device_id(config, || identify_device(topic, &Value::Null, config, ctx))?;
+        }
+        if config.records.socket_reads {
+            results.push(raw_socket_read(topic, hex_encode(payload)));
+        }
+        if config.records.sensor_readings {
+            let frames = match &spec.length_prefix {
+                Some(prefix) => binary::split_frames(topic, payload, prefix),
+                None => vec![payload],
+            };
+            for frame in frames {
+                if let Some(readings) = binary::parse_frame(topic, frame, spec, config, ctx) {
+                    results.extend(readings.into_iter().map(ParsedMessage::SensorReading));
+                }
+            }
+        }
+        stamp_device_class(&mut results, resolve_device_class(topic, None, config));
+        return Ok(results);
+    }
 
-/// Extract device_id from topic or JSON
-fn extract_device_id(topic: &str, json: &Value) -> Option<String> {
-    // Try to get from JSON first
-    if let Some(id) = json
-        .get("device_id")
-        .or_else(|| json.get("deviceId"))
-        .or_else(|| json.get("device"))
-        .and_then(|v| v.as_str())
-    {
-        return Some(id.to_string());
+    // Decode EnOcean telegrams by the EEP profile named in the topic
+    if let Some(enocean) = config.enocean.as_ref().filter(|enocean| enocean.topic.matches(topic_path(topic))) {
+        if enocean.device_segment.is_none() {
+            require_device_id(config, || identify_device(topic, &Value::Null, config, ctx))?;
+        }
+        let data = enocean::telegram_data(payload);
+        if config.records.socket_reads {
+            results.push(raw_socket_read(topic, hex_encode(&data)));
+        }
+        if config.records.sensor_readings {
+            if let Some(readings) = enocean::parse_telegram(topic, &data, enocean, config, ctx) {
+                results.extend(readings.into_iter().map(ParsedMessage::SensorReading));
+            }
+        }
+        stamp_device_class(&mut results, resolve_device_class(topic, None, config));
+        return Ok(results);
     }
 
-    // Try to extract from topic (e.g., "telemetry/device123/temperature")
-    let parts: Vec<&str> = topic.split('/').collect();
-    if parts.len() >= 2 {
-        // Look for part that looks like a device ID
-        for part in parts.iter() {
-            if part.starts_with("device") || part.len() >= 8 {
-                return Some(part.to_string());
+    // Convert payload to string
+    let payload_str = match String::from_utf8(payload.to_vec()) {
+        Ok(s) => s,
+        Err(e) => {
+            // CBOR maps never start with a valid UTF-8 byte, so they always land here
+            #[cfg(feature = "cbor")]
+            if let Some(json) = cbor::decode(payload) {
+                if config.records.socket_reads {
+                    results.push(raw_socket_read(topic, hex_encode(payload)));
+                }
+                let version = detect_schema_version(topic, Some(&json), config);
+                results.extend(parse_json(topic, json, config, ctx)?);
+                stamp_schema_version(&mut results, version);
+                return Ok(results);
+            }
+
+            // MessagePack maps start with bytes that are never valid leading UTF-8 either
+            #[cfg(feature = "msgpack")]
+            if let Some(json) = msgpack::decode(payload) {
+                if config.records.socket_reads {
+                    results.push(raw_socket_read(topic, msgpack::base64_encode(payload)));
+                }
+                let version = detect_schema_version(topic, Some(&json), config);
+                results.extend(parse_json(topic, json, config, ctx)?);
+                stamp_schema_version(&mut results, version);
+                return Ok(results);
+            }
+
+            // Binary garbage is kept as a parse error rather than vanishing
+            METRICS.utf8_errors.inc();
+            warn!("Failed to decode payload on topic {} as UTF-8: {}", topic, e);
+            if config.records.dead_letters {
+                results.push(dead_letter(topic, hex_encode(payload), DeadLetterReason::InvalidUtf8));
             }
+            results.push(ParsedMessage::ParseError(MalformedPayload {
+                topic: topic.to_string(),
+                payload: hex_encode(payload),
+                reason: format!("invalid UTF-8: {}", e),
+                timestamp: Utc::now(),
+            }));
+            return Ok(results);
         }
+    };
+
+    // Store raw message unless raw capture is disabled
+    if config.records.socket_reads {
+        results.push(raw_socket_read(topic, payload_str.clone()));
     }
 
-    Some("unknown".to_string())
-}
+    // Homie convention topics carry one plain value per property
+    if let Some(base) = &config.homie_base_topic {
+        if let Some(records) = homie::parse(topic, &payload_str, base, config) {
+            results.extend(records);
+            stamp_device_class(&mut results, resolve_device_class(topic, None, config));
+            return Ok(results);
+        }
+    }
 
-/// Extract timestamp from JSON or use current time
-fn extract_timestamp(json: &Value) -> chrono::DateTime<Utc> {
-    if let Some(ts) = json.get("timestamp").or_else(|| json.get("ts")) {
-        // Try to parse as ISO8601 string
-        if let Some(ts_str) = ts.as_str() {
-            if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(ts_str) {
-                return dt.with_timezone(&Utc);
+    // Decode configured CSV layouts instead of JSON
+    if let Some(schema) = config.csv_schemas.iter().find(|schema| schema.topic.matches(topic_path(topic))) {
+        require_device_id(config, || identify_device(topic, &Value::Null, config, ctx))?;
+        if config.records.sensor_readings {
+            if let Some(readings) = csv::parse_rows(topic, &payload_str, schema, config, ctx) {
+                results.extend(readings.into_iter().map(ParsedMessage::SensorReading));
             }
         }
+        stamp_device_class(&mut results, resolve_device_class(topic, None, config));
+        return Ok(results);
+    }
+
+    // Reject pathologically nested payloads before they reach the decoder
+    if exceeds_nesting_depth(&payload_str, config.max_json_depth) {
+        let total = stats::increment(&COUNTERS.rejected_deep_payloads);
+        warn!(
+            "Rejected payload on topic {}: nesting deeper than {} levels ({} rejected so far)",
+            topic, config.max_json_depth, total
+        );
+        let reason = format!("nesting deeper than {} levels", config.max_json_depth);
+        if config.records.dead_letters {
+            results.push(dead_letter(topic, payload_str, DeadLetterReason::ValidationFailed(reason.clone())));
+        }
+        if config.store_parse_errors {
+            note_parse_error(&mut results, reason);
+        }
+        return Ok(results);
+    }
 
-        // Try to parse as Unix timestamp (seconds or milliseconds)
-        if let Some(ts_num) = ts.as_i64() {
-            // Check if this looks like milliseconds (> year 2100 in seconds = 4102444800)
-            if ts_num > 4102444800 {
-                // Milliseconds - convert to seconds and nanoseconds
-                let secs = ts_num / 1000;
-                let nsecs = ((ts_num % 1000) * 1_000_000) as u32;
-                if let Some(dt) = chrono::DateTime::from_timestamp(secs, nsecs) {
-                    return dt;
+    // Try to parse as JSON, then XML when enabled
+    let decoded = serde_json::from_str::<Value>(&payload_str);
+    let json_error = decoded.as_ref().err().map(|e| (e.to_string(), e.line(), e.column()));
+    let decoded = decoded.ok();
+    #[cfg(feature = "xml")]
+    let decoded = decoded.or_else(|| xml::decode(&payload_str, config.max_json_depth));
+
+    if let Some(json) = decoded {
+        let version = detect_schema_version(topic, Some(&json), config);
+        let records = parse_json(topic, json, config, ctx)?;
+        if records.is_empty() && config.records.dead_letters {
+            results.push(dead_letter(topic, payload_str.clone(), DeadLetterReason::NoMatch));
+        }
+        results.extend(records);
+        stamp_schema_version(&mut results, version);
+    } else {
+        // Text that looks like JSON but failed to decode is worth recording
+        if payload_str.trim_start().starts_with(['{', '[']) {
+            if let Some((error, line, column)) = json_error {
+                METRICS.json_errors.inc();
+                if config.records.dead_letters {
+                    let reason = DeadLetterReason::JsonError { line, column };
+                    results.push(dead_letter(topic, payload_str.clone(), reason));
                 }
-            } else {
-                // Seconds
-                if let Some(dt) = chrono::DateTime::from_timestamp(ts_num, 0) {
-                    return dt;
+                if config.store_parse_errors {
+                    note_parse_error(&mut results, error);
                 }
             }
         }
+
+        // InfluxDB line protocol, before falling back to a plain text log
+        if let Some(readings) = line_protocol::parse_line_protocol(topic, &payload_str, config, ctx) {
+            if config.records.sensor_readings {
+                results.extend(readings.into_iter().map(ParsedMessage::SensorReading));
+            }
+            stamp_device_class(&mut results, resolve_device_class(topic, None, config));
+            return Ok(results);
+        }
+
+        // Syslog lines name their host, so they are tried before requiring a device id
+        if let Some(log) = syslog::parse_syslog(topic, &payload_str, config, ctx) {
+            if config.records.device_logs {
+                results.push(ParsedMessage::DeviceLog(log));
+            }
+            stamp_device_class(&mut results, resolve_device_class(topic, None, config));
+            return Ok(results);
+        }
+
+        require_device_id(config, || plain_text_device_id(topic, config, ctx))?;
+
+        // A single "metric:value" pair, when delimiters are configured
+        if let Some(reading) = parse_metric_pair(topic, &payload_str, config, ctx) {
+            if config.records.sensor_readings {
+                results.push(ParsedMessage::SensorReading(reading));
+            }
+            stamp_device_class(&mut results, resolve_device_class(topic, None, config));
+            return Ok(results);
+        }
+
+        // Try to parse as plain text log
+        if config.records.device_logs {
+            if let Some(log) = parse_plain_text_log(topic, &payload_str, config, ctx) {
+                results.push(ParsedMessage::DeviceLog(log));
+            }
+        }
+        stamp_device_class(&mut results, resolve_device_class(topic, None, config));
     }
 
-    Utc::now()
+    debug!("Parsed {} records from topic {}", results.len(), topic);
+    Ok(results)
 }
 
-/// Parse device state and health from JSON
-/// Expected format:
-/// {
-///   "main_state": 1,
-///   "secondary_state": 0,
-///   "alerts": {},
-///   "rssi": -29,
-///   "health": "{\"general\":{\"wifiSsid\":\"GL-S200-33f\",\"freeHeapSize\":57940,...}}"
-/// }
-fn parse_device_state_and_health(
+/// Extract device records from a decoded JSON (or JSON-converted) document
+fn parse_json(
     topic: &str,
-    json: &Value,
-) -> Option<(DeviceState, Option<DeviceHealth>)> {
-    // Check if this looks like a device state message
-    // It should have at least one of: main_state, secondary_state, alerts, rssi
-    let has_state_fields = json.get("main_state").is_some()
-        || json.get("secondary_state").is_some()
-        || json.get("alerts").is_some()
-        || json.get("rssi").is_some();
+    json: Value,
+    config: &ParserConfig,
+    ctx: &mut ParseContext,
+) -> Result<Vec<ParsedMessage>, ParseError> {
+    let mut results = Vec::new();
 
-    if !has_state_fields {
-        return None;
+    // Unwrap bridges that double-encode the device payload as a JSON string
+    let json = decode_nested_payload(json);
+
+    // Batched devices send an array of payloads; each element is parsed on its
+    // own, resolving its own device, timestamp and schema version
+    if let Value::Array(elements) = &json {
+        for element in cap_array(topic, "root", elements, config) {
+            let version = detect_schema_version(topic, Some(element), config);
+            let mut records = parse_json(topic, element.clone(), config, ctx)?;
+            stamp_schema_version(&mut records, version);
+            results.extend(records);
+        }
+        return Ok(results);
     }
 
-    let device_id = extract_device_id(topic, json)?;
-    let timestamp = extract_timestamp(json);
+    // Gateway rollups name their devices in the keys rather than a device_id field
+    if config.device_map_topics.iter().any(|filter| filter.matches(topic_path(topic))) {
+        if config.records.sensor_readings {
+            results.extend(parse_device_map(topic, &json, config, ctx).into_iter().map(ParsedMessage::SensorReading));
+        }
+        stamp_device_class(&mut results, resolve_device_class(topic, Some(&json), config));
+        return Ok(results);
+    }
 
-    // Parse device state (support both snake_case and camelCase)
-    let device_state = DeviceState {
-        device_id: device_id.clone(),
-        topic: topic.to_string(),
-        main_state: json.get("main_state")
-            .or_else(|| json.get("mainState"))
-            .and_then(|v| v.as_i64())
-            .map(|v| v as i32),
-        secondary_state: json.get("secondary_state")
-            .or_else(|| json.get("secondaryState"))
-            .and_then(|v| v.as_i64())
-            .map(|v| v as i32),
-        alerts: json.get("alerts")
-            .or_else(|| json.get("state"))
-            .cloned(),
-        rssi: json.get("rssi").and_then(|v| v.as_i64()).map(|v| v as i32),
-        timestamp,
-    };
+    if let Some(births) = ctx.births.as_deref_mut() {
+        birth::remember(topic, &json, config, births);
+    }
+    require_device_id(config, || identify_device(topic, &json, config, ctx))?;
 
-    // Parse health data if present
-    let device_health = json.get("health").and_then(|health_value| {
-        // Health can be a string (JSON encoded) or direct object
-        let health_json = if let Some(health_str) = health_value.as_str() {
-            serde_json::from_str::<Value>(health_str).ok()?
-        } else {
-            health_value.clone()
-        };
+    // Parse device state and health (priority - most specific format)
+    if let Some(records) = parse_device_state_and_health(topic, &json, config, ctx) {
+        results.extend(records);
+    } else {
+        // Parse sensor readings
+        if config.records.sensor_readings {
+            if let Some(readings) = parse_sensor_readings(topic, &json, config, ctx) {
+                if config.records.device_alerts {
+                    results.extend(threshold_alerts(topic, &json, &readings, config).into_iter().map(ParsedMessage::DeviceAlert));
+                }
+                results.extend(readings.into_iter().map(ParsedMessage::SensorReading));
+            }
+        }
+
+        // Parse device logs
+        if config.records.device_logs {
+            if let Some(log) = parse_device_log(topic, &json, config, ctx) {
+                results.push(ParsedMessage::DeviceLog(log));
+            }
+        }
+    }
+
+    // Capability announcements, alongside whatever else the payload holds
+    if config.records.device_capabilities {
+        results.extend(parse_capabilities(topic, &json, config, ctx).into_iter().map(ParsedMessage::DeviceCapability));
+    }
 
-        // Extract general health data
-        let general = health_json.get("general")?;
+    stamp_device_class(&mut results, resolve_device_class(topic, Some(&json), config));
+
+    // Configured fields stored as typed columns, alongside the standard records
+    for table in config.flattened_tables.iter().filter(|table| table.topic.matches(topic_path(topic))) {
+        if let Some(row) = flatten::build_row(topic, &json, table, config, ctx) {
+            results.push(ParsedMessage::FlattenedRow(row));
+        }
+    }
+
+    Ok(results)
+}
+
+/// One record per capability a device announces
+/// (e.g., `{"device_id": "x", "capabilities": ["temp", "hum", "relay"]}`)
+fn parse_capabilities(topic: &str, json: &Value, config: &ParserConfig, ctx: &ParseContext) -> Vec<DeviceCapability> {
+    let Some(capabilities) = json.get("capabilities").and_then(|v| v.as_array()) else {
+        return Vec::new();
+    };
+    let Some(device_id) = extract_device_id(topic, json, config, ctx) else {
+        return Vec::new();
+    };
+    let timestamp = extract_timestamp(topic, json, config);
 
-        Some(DeviceHealth {
+    cap_array(topic, "capabilities", capabilities, config)
+        .iter()
+        .filter_map(|capability| capability.as_str())
+        .filter(|capability| !capability.is_empty())
+        .map(|capability| DeviceCapability {
             device_id: device_id.clone(),
+            capability: capability.to_string(),
             topic: topic.to_string(),
-            wifi_ssid: general.get("wifiSsid").and_then(|v| v.as_str()).map(|s| s.to_string()),
-            free_heap_size: general.get("freeHeapSize").and_then(|v| v.as_i64()),
-            min_heap_size: general.get("minHeapSize").and_then(|v| v.as_i64()),
-            unexpected_reset_counter: general.get("unexpectedResetCounter").and_then(|v| v.as_i64()).map(|v| v as i32),
-            last_reset_reason: general.get("lastResetReason").and_then(|v| v.as_str()).map(|s| s.to_string()),
-            wifi_connect_counter: general.get("wifiConnectCounter").and_then(|v| v.as_i64()).map(|v| v as i32),
-            cloud_connect_counter: general.get("cloudConnectCounter").and_then(|v| v.as_i64()).map(|v| v as i32),
-            last_wifi_connection_ts: general.get("lastWifiConnectionTs").and_then(|v| v.as_i64()),
-            last_cloud_connection_ts: general.get("lastCloudConnectionTs").and_then(|v| v.as_i64()),
             timestamp,
         })
-    });
+        .collect()
+}
+
+/// Readings from a rollup whose top-level keys are device ids
+/// (e.g., `{"ts": 1700000000, "a": {"temp": 20}, "b": {"temp": 21}}`). Each
+/// device's object is parsed like its own payload, taking the shared timestamp
+/// unless it carries one.
+fn parse_device_map(topic: &str, json: &Value, config: &ParserConfig, ctx: &ParseContext) -> Vec<SensorReading> {
+    let Some(devices) = json.as_object() else {
+        return Vec::new();
+    };
+    let shared_timestamp = json.get("timestamp").or_else(|| json.get("ts"));
+
+    let mut readings = Vec::new();
+    for (device_id, entry) in devices {
+        // Scalars such as the shared timestamp are not devices
+        let Some(fields) = entry.as_object() else {
+            continue;
+        };
+
+        // Timestamps move under "timestamp" so the flat-key pass skips them
+        let mut payload = fields.clone();
+        payload.insert("device_id".to_string(), Value::String(device_id.clone()));
+        if let Some(ts) = payload.remove("ts") {
+            payload.entry("timestamp").or_insert(ts);
+        }
+        if let Some(ts) = shared_timestamp {
+            payload.entry("timestamp").or_insert_with(|| ts.clone());
+        }
+
+        for mut reading in parse_sensor_readings(topic, &Value::Object(payload), config, ctx).into_iter().flatten() {
+            reading.device_id = device_id.clone();
+            readings.push(reading);
+        }
+    }
+
+    readings
+}
 
-    Some((device_state, device_health))
+/// Attach a decoding error to the raw socket read already collected
+fn note_parse_error(records: &mut [ParsedMessage], error: String) {
+    for record in records.iter_mut() {
+        if let ParsedMessage::SocketRead(read) = record {
+            read.parse_error = Some(error.clone());
+        }
+    }
+}
+
+/// Payload the parser gave up on, for the dead_letters table
+fn dead_letter(topic: &str, payload: String, reason: DeadLetterReason) -> ParsedMessage {
+    ParsedMessage::DeadLetter(DeadLetter::new(Some(topic.to_string()), payload, reason))
+}
+
+/// Raw payload record for the socket_reads audit table
+fn raw_socket_read(topic: &str, payload: String) -> ParsedMessage {
+    ParsedMessage::SocketRead(SocketRead {
+        topic: topic.to_string(),
+        payload,
+        parse_error: None,
+        qos: 0,
+        retained: false,
+        tags: None,
+        retention_class: None,
+        schema_version: None,
+        topic_id: None,
+        timestamp: Utc::now(),
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ParsedMessage {
+    SensorReading(SensorReading),
+    SocketRead(SocketRead),
+    DeviceLog(DeviceLog),
+    DeviceState(DeviceState),
+    DeviceAlert(DeviceAlert),
+    DeviceHealth(DeviceHealth),
+    TaskHealth(TaskHealth),
+    OfflineEvent(OfflineEvent),
+    FlattenedRow(FlattenedRow),
+    ParseError(MalformedPayload),
+    /// A payload the parser gave up on, when dead-lettering them is enabled
+    DeadLetter(DeadLetter),
+    ReadingRollup(ReadingRollup),
+    /// One capability a device announced, upserted per device and capability
+    DeviceCapability(DeviceCapability),
+}
+
+impl ParsedMessage {
+    /// Tags column shared by the standard record types (flattened rows have none)
+    fn tags_mut(&mut self) -> Option<&mut Option<Value>> {
+        match self {
+            ParsedMessage::SensorReading(r) => Some(&mut r.tags),
+            ParsedMessage::SocketRead(r) => Some(&mut r.tags),
+            ParsedMessage::DeviceLog(r) => Some(&mut r.tags),
+            ParsedMessage::DeviceState(r) => Some(&mut r.tags),
+            ParsedMessage::DeviceAlert(r) => Some(&mut r.tags),
+            ParsedMessage::DeviceHealth(r) => Some(&mut r.tags),
+            ParsedMessage::TaskHealth(r) => Some(&mut r.tags),
+            ParsedMessage::OfflineEvent(r) => Some(&mut r.tags),
+            ParsedMessage::FlattenedRow(_) => None,
+            ParsedMessage::ParseError(_) => None,
+            ParsedMessage::DeadLetter(_) => None,
+            ParsedMessage::ReadingRollup(_) => None,
+            ParsedMessage::DeviceCapability(_) => None,
+        }
+    }
+
+    /// Retention class column shared by the standard record types (flattened rows have none)
+    fn retention_class_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            ParsedMessage::SensorReading(r) => Some(&mut r.retention_class),
+            ParsedMessage::SocketRead(r) => Some(&mut r.retention_class),
+            ParsedMessage::DeviceLog(r) => Some(&mut r.retention_class),
+            ParsedMessage::DeviceState(r) => Some(&mut r.retention_class),
+            ParsedMessage::DeviceAlert(r) => Some(&mut r.retention_class),
+            ParsedMessage::DeviceHealth(r) => Some(&mut r.retention_class),
+            ParsedMessage::TaskHealth(r) => Some(&mut r.retention_class),
+            ParsedMessage::OfflineEvent(r) => Some(&mut r.retention_class),
+            ParsedMessage::FlattenedRow(_) => None,
+            ParsedMessage::ParseError(_) => None,
+            ParsedMessage::DeadLetter(_) => None,
+            ParsedMessage::ReadingRollup(_) => None,
+            ParsedMessage::DeviceCapability(_) => None,
+        }
+    }
+
+    /// Schema version column of records decoded from a payload
+    fn schema_version_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            ParsedMessage::SensorReading(r) => Some(&mut r.schema_version),
+            ParsedMessage::SocketRead(r) => Some(&mut r.schema_version),
+            ParsedMessage::DeviceLog(r) => Some(&mut r.schema_version),
+            ParsedMessage::DeviceState(r) => Some(&mut r.schema_version),
+            ParsedMessage::DeviceAlert(r) => Some(&mut r.schema_version),
+            ParsedMessage::DeviceHealth(r) => Some(&mut r.schema_version),
+            ParsedMessage::TaskHealth(r) => Some(&mut r.schema_version),
+            ParsedMessage::OfflineEvent(_) => None,
+            ParsedMessage::FlattenedRow(_) => None,
+            ParsedMessage::ParseError(_) => None,
+            ParsedMessage::DeadLetter(_) => None,
+            ParsedMessage::ReadingRollup(_) => None,
+            ParsedMessage::DeviceCapability(_) => None,
+        }
+    }
+
+    /// Topics hierarchy reference of records received on a topic
+    pub fn topic_id_mut(&mut self) -> Option<&mut Option<i64>> {
+        match self {
+            ParsedMessage::SensorReading(r) => Some(&mut r.topic_id),
+            ParsedMessage::SocketRead(r) => Some(&mut r.topic_id),
+            ParsedMessage::DeviceLog(r) => Some(&mut r.topic_id),
+            ParsedMessage::DeviceState(r) => Some(&mut r.topic_id),
+            ParsedMessage::DeviceAlert(r) => Some(&mut r.topic_id),
+            ParsedMessage::DeviceHealth(r) => Some(&mut r.topic_id),
+            ParsedMessage::TaskHealth(r) => Some(&mut r.topic_id),
+            ParsedMessage::OfflineEvent(_) => None,
+            ParsedMessage::FlattenedRow(_) => None,
+            ParsedMessage::ParseError(_) => None,
+            ParsedMessage::DeadLetter(_) => None,
+            ParsedMessage::ReadingRollup(_) => None,
+            ParsedMessage::DeviceCapability(_) => None,
+        }
+    }
+
+    /// Registry group of records that carry one (sensor readings)
+    pub fn group_id_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            ParsedMessage::SensorReading(r) => Some(&mut r.group_id),
+            ParsedMessage::SocketRead(_) => None,
+            ParsedMessage::DeviceLog(_) => None,
+            ParsedMessage::DeviceState(_) => None,
+            ParsedMessage::DeviceAlert(_) => None,
+            ParsedMessage::DeviceHealth(_) => None,
+            ParsedMessage::TaskHealth(_) => None,
+            ParsedMessage::OfflineEvent(_) => None,
+            ParsedMessage::FlattenedRow(_) => None,
+            ParsedMessage::ParseError(_) => None,
+            ParsedMessage::DeadLetter(_) => None,
+            ParsedMessage::ReadingRollup(_) => None,
+            ParsedMessage::DeviceCapability(_) => None,
+        }
+    }
+
+    /// Topic the record was received on (offline events have none)
+    pub fn topic(&self) -> Option<&str> {
+        match self {
+            ParsedMessage::SensorReading(r) => Some(&r.topic),
+            ParsedMessage::SocketRead(r) => Some(&r.topic),
+            ParsedMessage::DeviceLog(r) => Some(&r.topic),
+            ParsedMessage::DeviceState(r) => Some(&r.topic),
+            ParsedMessage::DeviceAlert(r) => Some(&r.topic),
+            ParsedMessage::DeviceHealth(r) => Some(&r.topic),
+            ParsedMessage::TaskHealth(r) => Some(&r.topic),
+            ParsedMessage::OfflineEvent(_) => None,
+            ParsedMessage::FlattenedRow(r) => Some(&r.topic),
+            ParsedMessage::ParseError(r) => Some(&r.topic),
+            ParsedMessage::DeadLetter(r) => r.topic.as_deref(),
+            ParsedMessage::ReadingRollup(r) => Some(&r.topic),
+            ParsedMessage::DeviceCapability(r) => Some(&r.topic),
+        }
+    }
+
+    /// Device the record belongs to (raw socket reads have none)
+    pub fn device_id(&self) -> Option<&str> {
+        match self {
+            ParsedMessage::SensorReading(r) => Some(&r.device_id),
+            ParsedMessage::SocketRead(_) => None,
+            ParsedMessage::DeviceLog(r) => Some(&r.device_id),
+            ParsedMessage::DeviceState(r) => Some(&r.device_id),
+            ParsedMessage::DeviceAlert(r) => Some(&r.device_id),
+            ParsedMessage::DeviceHealth(r) => Some(&r.device_id),
+            ParsedMessage::TaskHealth(r) => Some(&r.device_id),
+            ParsedMessage::OfflineEvent(r) => Some(&r.device_id),
+            ParsedMessage::FlattenedRow(r) => Some(&r.device_id),
+            ParsedMessage::ParseError(_) => None,
+            ParsedMessage::DeadLetter(_) => None,
+            ParsedMessage::ReadingRollup(r) => Some(&r.device_id),
+            ParsedMessage::DeviceCapability(r) => Some(&r.device_id),
+        }
+    }
+
+    /// Mutable device id of records that belong to a device
+    fn device_id_mut(&mut self) -> Option<&mut String> {
+        match self {
+            ParsedMessage::SensorReading(r) => Some(&mut r.device_id),
+            ParsedMessage::SocketRead(_) => None,
+            ParsedMessage::DeviceLog(r) => Some(&mut r.device_id),
+            ParsedMessage::DeviceState(r) => Some(&mut r.device_id),
+            ParsedMessage::DeviceAlert(r) => Some(&mut r.device_id),
+            ParsedMessage::DeviceHealth(r) => Some(&mut r.device_id),
+            ParsedMessage::TaskHealth(r) => Some(&mut r.device_id),
+            ParsedMessage::OfflineEvent(r) => Some(&mut r.device_id),
+            ParsedMessage::FlattenedRow(r) => Some(&mut r.device_id),
+            ParsedMessage::ParseError(_) => None,
+            ParsedMessage::DeadLetter(_) => None,
+            ParsedMessage::ReadingRollup(r) => Some(&mut r.device_id),
+            ParsedMessage::DeviceCapability(r) => Some(&mut r.device_id),
+        }
+    }
+
+    /// Device class used for table routing (raw socket reads and flattened rows are never routed)
+    fn device_class_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            ParsedMessage::SensorReading(r) => Some(&mut r.device_class),
+            ParsedMessage::SocketRead(_) => None,
+            ParsedMessage::DeviceLog(r) => Some(&mut r.device_class),
+            ParsedMessage::DeviceState(r) => Some(&mut r.device_class),
+            ParsedMessage::DeviceAlert(r) => Some(&mut r.device_class),
+            ParsedMessage::DeviceHealth(r) => Some(&mut r.device_class),
+            ParsedMessage::TaskHealth(r) => Some(&mut r.device_class),
+            ParsedMessage::OfflineEvent(_) => None,
+            ParsedMessage::FlattenedRow(_) => None,
+            ParsedMessage::ParseError(_) => None,
+            ParsedMessage::DeadLetter(_) => None,
+            ParsedMessage::ReadingRollup(_) => None,
+            ParsedMessage::DeviceCapability(_) => None,
+        }
+    }
+
+    /// Variant name, used as a metrics label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParsedMessage::SensorReading(_) => "sensor_reading",
+            ParsedMessage::SocketRead(_) => "socket_read",
+            ParsedMessage::DeviceLog(_) => "device_log",
+            ParsedMessage::DeviceState(_) => "device_state",
+            ParsedMessage::DeviceAlert(_) => "device_alert",
+            ParsedMessage::DeviceHealth(_) => "device_health",
+            ParsedMessage::TaskHealth(_) => "task_health",
+            ParsedMessage::OfflineEvent(_) => "offline_event",
+            ParsedMessage::FlattenedRow(_) => "flattened_row",
+            ParsedMessage::ParseError(_) => "parse_error",
+            ParsedMessage::DeadLetter(_) => "dead_letter",
+            ParsedMessage::ReadingRollup(_) => "reading_rollup",
+            ParsedMessage::DeviceCapability(_) => "device_capability",
+        }
+    }
+
+    /// Table the record is stored in, before any per-class prefix
+    pub fn base_table(&self) -> &str {
+        match self {
+            ParsedMessage::SensorReading(_) => "sensor_readings",
+            ParsedMessage::SocketRead(_) => "socket_reads",
+            ParsedMessage::DeviceLog(_) => "device_logs",
+            ParsedMessage::DeviceState(_) => "device_states",
+            ParsedMessage::DeviceAlert(_) => "device_alerts",
+            ParsedMessage::DeviceHealth(_) => "device_health",
+            ParsedMessage::TaskHealth(_) => "task_health",
+            ParsedMessage::OfflineEvent(_) => "offline_events",
+            ParsedMessage::FlattenedRow(r) => &r.table,
+            ParsedMessage::ParseError(_) => "parse_errors",
+            ParsedMessage::DeadLetter(_) => "dead_letters",
+            ParsedMessage::ReadingRollup(_) => "reading_rollups",
+            ParsedMessage::DeviceCapability(_) => "device_capabilities",
+        }
+    }
+
+    /// Base table and device class for records routed into per-class tables
+    pub fn class_route(&self) -> Option<(&str, &str)> {
+        let class = match self {
+            ParsedMessage::SensorReading(r) => &r.device_class,
+            ParsedMessage::SocketRead(_) => return None,
+            ParsedMessage::DeviceLog(r) => &r.device_class,
+            ParsedMessage::DeviceState(r) => &r.device_class,
+            ParsedMessage::DeviceAlert(r) => &r.device_class,
+            ParsedMessage::DeviceHealth(r) => &r.device_class,
+            ParsedMessage::TaskHealth(r) => &r.device_class,
+            ParsedMessage::OfflineEvent(_) => return None,
+            ParsedMessage::FlattenedRow(_) => return None,
+            ParsedMessage::ParseError(_) => return None,
+            ParsedMessage::DeadLetter(_) => return None,
+            ParsedMessage::ReadingRollup(_) => return None,
+            ParsedMessage::DeviceCapability(_) => return None,
+        };
+        class.as_deref().map(|class| (self.base_table(), class))
+    }
+}
+
+/// Resolve the device class used for per-class table routing, from the
+/// configured JSON field or topic segment. None when routing is disabled.
+fn resolve_device_class(topic: &str, json: Option<&Value>, config: &ParserConfig) -> Option<String> {
+    let class_config = config.device_class_tables.as_ref()?;
+
+    let class = match json.and_then(|json| json.get(&class_config.field)).and_then(|v| v.as_str()) {
+        Some(class) => Some(Cow::Borrowed(class)),
+        None => topic_segments(topic, config).nth(class_config.topic_segment?),
+    };
+
+    class.filter(|class| !class.is_empty()).map(Cow::into_owned)
+}
+
+/// Attach the device class to every device-scoped record
+fn stamp_device_class(records: &mut [ParsedMessage], device_class: Option<String>) {
+    if device_class.is_none() {
+        return;
+    }
+
+    for record in records.iter_mut() {
+        if let Some(class) = record.device_class_mut() {
+            *class = device_class.clone();
+        }
+    }
+}
+
+/// Lowercase hex representation of a binary payload
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Check whether JSON-like text nests objects/arrays deeper than `max_depth`,
+/// without recursing or allocating
+fn exceeds_nesting_depth(text: &str, max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for byte in text.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    false
+}
+
+/// Decode a string-valued "payload" field that itself contains a JSON object,
+/// e.g. {"payload":"{\"value\":1}"}. Identity and time fields from the wrapper
+/// are carried over when the inner object lacks them.
+fn decode_nested_payload(json: Value) -> Value {
+    let inner = json
+        .get("payload")
+        .and_then(|v| v.as_str())
+        .and_then(|s| serde_json::from_str::<Value>(s).ok());
+
+    let Some(Value::Object(mut inner)) = inner else {
+        return json;
+    };
+
+    for key in ["device_id", "deviceId", "device", "timestamp", "ts"] {
+        if let Some(value) = json.get(key) {
+            inner.entry(key).or_insert_with(|| value.clone());
+        }
+    }
+
+    debug!("Decoded nested JSON payload");
+    Value::Object(inner)
+}
+
+/// Follow a dotted path through objects, indexing arrays by numeric segments
+/// (e.g., "telemetry.data" or "motor.phases.0.current")
+fn lookup_path<'a>(json: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(json, |value, segment| match value {
+        Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+        _ => value.get(segment),
+    })
+}
+
+/// Numeric top-level keys that describe a reading rather than being one, skipped
+/// in addition to the configured `excluded_keys`
+const NON_SENSOR_KEYS: &[&str] = &["timestamp", "ts", "device_id", "quality", "q", "unit", "units"];
+
+/// Unit reported next to a value (e.g., "C" in `{"value": 25.5, "unit": "C"}`)
+fn extract_unit(json: &Value) -> Option<String> {
+    json.get("unit").or_else(|| json.get("units"))?.as_str().map(str::to_string)
+}
+
+/// Accuracy or confidence of a reading, from the first configured field holding a number
+fn extract_accuracy(json: &Value, config: &ParserConfig) -> Option<f64> {
+    config
+        .accuracy_fields
+        .iter()
+        .find_map(|field| json.get(field).and_then(|v| v.as_f64()))
+}
+
+/// Extract a reading quality flag, normalized to "good", "uncertain" or "bad".
+/// Accepts strings or numeric OPC codes in the configured standard.
+fn extract_quality(json: &Value, config: &ParserConfig) -> Option<String> {
+    let quality = json.get("quality").or_else(|| json.get("q"))?;
+
+    let normalized = if let Some(code) = quality.as_u64() {
+        match config.quality_codes {
+            QualityCodes::OpcDa => match code & 0xC0 {
+                0xC0 => "good",
+                0x40 => "uncertain",
+                _ => "bad",
+            },
+            QualityCodes::OpcUa => match code >> 30 {
+                0 => "good",
+                1 => "uncertain",
+                _ => "bad",
+            },
+        }
+    } else {
+        match quality.as_str()?.to_lowercase().as_str() {
+            "good" | "ok" => "good",
+            "bad" | "error" | "invalid" => "bad",
+            _ => "uncertain",
+        }
+    };
+
+    Some(normalized.to_string())
+}
+
+/// Leading entries of a payload array, truncated to `max_array_len` so one
+/// malformed message cannot produce an unbounded number of readings
+fn cap_array<'a>(topic: &str, field: &str, items: &'a [Value], config: &ParserConfig) -> &'a [Value] {
+    if items.len() <= config.max_array_len {
+        return items;
+    }
+
+    let total = stats::increment(&COUNTERS.truncated_arrays);
+    warn!(
+        "Truncated {} array on topic {} from {} to {} entries ({} truncated so far)",
+        field,
+        topic,
+        items.len(),
+        config.max_array_len,
+        total
+    );
+    &items[..config.max_array_len]
+}
+
+/// Numeric value of an untyped field: numbers, numeric strings such as "25.5" or
+/// "1e3", and percent strings such as "82%", which are stored as fractions (0.82)
+/// for metrics listed in `percent_fractions`. Other text, including "NaN" and
+/// "inf", is not a value.
+fn coerce_value(value: &Value, metric: &str, config: &ParserConfig) -> Option<f64> {
+    if let Some(number) = value.as_f64() {
+        return Some(number);
+    }
+
+    let text = value.as_str()?.trim();
+    let (number, percent) = match text.strip_suffix('%') {
+        Some(number) => (number.trim_end(), true),
+        None => (text, false),
+    };
+    let number: f64 = number.parse().ok().filter(|number: &f64| number.is_finite())?;
+
+    if percent && config.percent_fractions.iter().any(|fraction| fraction == metric) {
+        Some(number / 100.0)
+    } else {
+        Some(number)
+    }
+}
+
+/// Numeric value of `field`, honoring a declared `"type"` of "int" or "float".
+/// Integer-typed values are also kept exactly; values not matching their
+/// declared type are rejected. Untyped values go through [`coerce_value`] as `metric`.
+fn typed_value(
+    topic: &str,
+    entry: &Value,
+    field: &str,
+    metric: &str,
+    config: &ParserConfig,
+) -> Option<(f64, Option<i64>)> {
+    let value = entry.get(field)?;
+    let declared = entry.get("type").and_then(|v| v.as_str());
+
+    let typed = match declared {
+        Some("int" | "integer") => value.as_i64().map(|int| (int as f64, Some(int))),
+        Some("float" | "double") => value.as_f64().map(|float| (float, None)),
+        // Absent or unrelated "type" fields leave the value untyped
+        _ => return coerce_value(value, metric, config).map(|float| (float, None)),
+    };
+
+    if typed.is_none() {
+        warn!(
+            "Rejected reading on topic {}: {} does not match declared type {:?}",
+            topic,
+            value,
+            declared.unwrap_or_default()
+        );
+    }
+    typed
+}
+
+/// Parse JSON sensor readings
+fn parse_sensor_readings(
+    topic: &str,
+    json: &Value,
+    config: &ParserConfig,
+    ctx: &ParseContext,
+) -> Option<Vec<SensorReading>> {
+    let mut readings = Vec::new();
+
+    // Extract device_id from topic or JSON
+    let device_id = extract_device_id(topic, json, config, ctx)?;
+
+    // Quality flag reported alongside the values (applies to single and flat readings)
+    let quality = extract_quality(json, config);
+
+    // Unit reported alongside the values (applies to single and sensors-array readings)
+    let unit = extract_unit(json);
+
+    // Accuracy reported alongside the values (array entries may carry their own)
+    let accuracy = extract_accuracy(json, config);
+
+    // Payloads with both shapes keep the one(s) the policy selects
+    let sensors = json.get("sensors").and_then(|v| v.as_array());
+    let (keep_value, keep_sensors) = match (json.get("value"), sensors, config.value_and_sensors) {
+        (Some(_), Some(_), ValueSensorsPolicy::ValueOnly) => (true, false),
+        (Some(_), Some(_), ValueSensorsPolicy::SensorsOnly) => (false, true),
+        _ => (true, true),
+    };
+
+    // Handle single sensor value
+    let metric = topic.rsplit('/').next().unwrap_or(topic);
+    let single = typed_value(topic, json, "value", metric, config).filter(|_| keep_value);
+    if let Some((value, int_value)) = single {
+        readings.push(
+            SensorReading::new(
+                device_id.clone(),
+                topic.to_string(),
+                value,
+                extract_timestamp(topic, json, config),
+            )
+            .with_int_value(int_value)
+            .with_quality(quality.clone())
+            .with_unit(unit.clone())
+            .with_accuracy(accuracy),
+        );
+    }
+
+    // Handle multiple sensor values in "sensors" array
+    if let Some(sensors) = sensors.filter(|_| keep_sensors) {
+        for sensor in cap_array(topic, "sensors", sensors, config) {
+            if let Some((name, (value, int_value))) = sensor
+                .get("name")
+                .and_then(|v| v.as_str())
+                .and_then(|name| Some((name, typed_value(topic, sensor, "value", name, config)?)))
+            {
+                readings.push(
+                    SensorReading::new(
+                        device_id.clone(),
+                        format!("{}/{}", topic, name),
+                        value,
+                        extract_timestamp(topic, json, config),
+                    )
+                    .with_int_value(int_value)
+                    .with_quality(extract_quality(sensor, config).or_else(|| quality.clone()))
+                    .with_unit(extract_unit(sensor).or_else(|| unit.clone()))
+                    .with_accuracy(extract_accuracy(sensor, config).or(accuracy)),
+                );
+            }
+        }
+    }
+
+    // Handle configured envelope arrays (e.g., {"telemetry": {"data": [{"key": "temp", "value": 21}]}})
+    for envelope in &config.reading_arrays {
+        let Some(entries) = lookup_path(json, &envelope.path).and_then(|v| v.as_array()) else {
+            continue;
+        };
+        for entry in cap_array(topic, &envelope.path, entries, config) {
+            if let Some((name, (value, int_value))) = entry
+                .get(&envelope.name_field)
+                .and_then(|v| v.as_str())
+                .and_then(|name| Some((name, typed_value(topic, entry, &envelope.value_field, name, config)?)))
+            {
+                readings.push(
+                    SensorReading::new(
+                        device_id.clone(),
+                        format!("{}/{}", topic, name),
+                        value,
+                        entry
+                            .get("timestamp")
+                            .or_else(|| entry.get("ts"))
+                            .and_then(parse_timestamp_value)
+                            .unwrap_or_else(|| extract_timestamp(topic, json, config)),
+                    )
+                    .with_int_value(int_value)
+                    .with_quality(extract_quality(entry, config).or_else(|| quality.clone()))
+                    .with_accuracy(extract_accuracy(entry, config).or(accuracy)),
+                );
+            }
+        }
+    }
+
+    // Handle delta-time batches (e.g., {"t0": 1700000000, "dt": 1, "values": [21.0, 21.1]})
+    let batch = parse_delta_batch(topic, json, config);
+    for (timestamp, value) in batch.iter().flatten() {
+        readings.push(
+            SensorReading::new(device_id.clone(), topic.to_string(), *value, *timestamp)
+                .with_quality(quality.clone())
+                .with_accuracy(accuracy),
+        );
+    }
+
+    // Handle timestamped tuples (e.g., {"points": [[1700000000, 21.0], [1700000001, 21.1]]})
+    if let Some(points) = json.get("points").and_then(|v| v.as_array()) {
+        for point in cap_array(topic, "points", points, config) {
+            let Some([ts, value]) = point.as_array().map(Vec::as_slice) else {
+                continue;
+            };
+            if let (Some(timestamp), Some(value)) = (parse_timestamp_value(ts), value.as_f64()) {
+                readings.push(
+                    SensorReading::new(device_id.clone(), topic.to_string(), value, timestamp)
+                        .with_quality(quality.clone())
+                        .with_accuracy(accuracy),
+                );
+            }
+        }
+    }
+
+    // Handle Thingsboard telemetry (e.g., {"ts": 1700000000000, "values": {"temp": 21, "hum": 44}}),
+    // stamped with the element's ts
+    let telemetry = json.get("values").and_then(|v| v.as_object());
+    for (key, value) in telemetry.into_iter().flatten() {
+        if excluded_metric_key(key, config) {
+            continue;
+        }
+        if let Some(value) = coerce_value(value, key, config) {
+            readings.push(
+                SensorReading::new(
+                    device_id.clone(),
+                    format!("{}/{}", topic, key),
+                    value,
+                    extract_timestamp(topic, json, config),
+                )
+                .with_quality(quality.clone())
+                .with_accuracy(accuracy),
+            );
+        }
+    }
+
+    // Handle magnitude/sign pairs (e.g., {"magnitude": 5.2, "negative": true} → -5.2)
+    let mut signed_keys = Vec::new();
+    for rule in &config.signed_values {
+        if let (Some(magnitude), Some(sign)) = (
+            json.get(&rule.magnitude).and_then(|v| v.as_f64()),
+            json.get(&rule.sign).and_then(|v| v.as_bool()),
+        ) {
+            let value = if sign == rule.negative_when { -magnitude.abs() } else { magnitude.abs() };
+            readings.push(
+                SensorReading::new(
+                    device_id.clone(),
+                    format!("{}/{}", topic, rule.name.as_deref().unwrap_or(&rule.magnitude)),
+                    value,
+                    extract_timestamp(topic, json, config),
+                )
+                .with_quality(quality.clone())
+                .with_accuracy(accuracy),
+            );
+            signed_keys.push(rule.magnitude.as_str());
+        }
+    }
+
+    // A typed single value was handled (or rejected) above
+    let typed_single = matches!(
+        json.get("type").and_then(|v| v.as_str()),
+        Some("int" | "integer" | "float" | "double")
+    );
+
+    // Schema version fields describe the payload rather than a measurement
+    let version_keys = config.schema_version.as_ref().map(|schema| schema.fields.as_slice()).unwrap_or_default();
+
+    // Handle flat JSON with numeric values (e.g., {"temperature": 25.5, "humidity": 60.0})
+    if let Some(obj) = json.as_object().filter(|_| batch.is_none()) {
+        for (key, value) in obj {
+            // Inline alarm bounds describe the value rather than a measurement
+            let threshold_key = config.inline_thresholds && matches!(key.as_str(), "min" | "max");
+            if let Some(num) = coerce_value(value, key, config) {
+                if !NON_SENSOR_KEYS.contains(&key.as_str())
+                    && !config.excluded_keys.contains(key)
+                    && !config.accuracy_fields.contains(key)
+                    && !threshold_key
+                    && !signed_keys.contains(&key.as_str())
+                    && !version_keys.contains(key)
+                    && ((!typed_single && keep_value) || key != "value")
+                {
+                    let (metric, unit) = split_unit_suffix(key, config);
+                    readings.push(
+                        SensorReading::new(
+                            device_id.clone(),
+                            format!("{}/{}", topic, metric),
+                            num,
+                            extract_timestamp(topic, json, config),
+                        )
+                        .with_quality(quality.clone())
+                        .with_unit(unit.map(str::to_string))
+                        .with_accuracy(accuracy),
+                    );
+                }
+            } else if let Some(axes) = extract_vector(value) {
+                // Vector sub-objects (e.g., {"accel": {"x": 0.1, "y": -0.2, "z": 9.8}})
+                let magnitude = axes.iter().map(|(_, v)| v * v).sum::<f64>().sqrt();
+                let magnitude = config.vector_magnitude.then_some(("magnitude", magnitude));
+
+                for (axis, num) in axes.into_iter().chain(magnitude) {
+                    readings.push(
+                        SensorReading::new(
+                            device_id.clone(),
+                            format!("{}/{}/{}", topic, key, axis),
+                            num,
+                            extract_timestamp(topic, json, config),
+                        )
+                        .with_quality(quality.clone())
+                        .with_accuracy(accuracy),
+                    );
+                }
+            } else if let Some(group) = value.as_object().filter(|_| config.unit_keyed_values) {
+                // Unit groups (e.g., {"celsius": {"temp": 21}, "percent": {"hum": 44}})
+                for (metric, num) in group.iter().filter_map(|(metric, v)| Some((metric, v.as_f64()?))) {
+                    readings.push(
+                        SensorReading::new(
+                            device_id.clone(),
+                            format!("{}/{}", topic, metric),
+                            num,
+                            extract_timestamp(topic, json, config),
+                        )
+                        .with_quality(quality.clone())
+                        .with_unit(Some(key.clone()))
+                        .with_accuracy(accuracy),
+                    );
+                }
+            } else if config.nested_readings
+                && (value.is_object() || value.is_array())
+                && !excluded_metric_key(key, config)
+                && !NESTED_HANDLED_KEYS.contains(&key.as_str())
+                && !version_keys.contains(key)
+                && !config.reading_arrays.iter().any(|envelope| envelope.path.split('.').next() == Some(key))
+            {
+                // Nested objects (e.g., {"env": {"temp": 25.5}} → topic/env/temp)
+                let mut values = Vec::new();
+                nested_values(key, value, 1, config, &mut values);
+                for (path, num) in values {
+                    readings.push(
+                        SensorReading::new(
+                            device_id.clone(),
+                            format!("{}/{}", topic, path),
+                            num,
+                            extract_timestamp(topic, json, config),
+                        )
+                        .with_quality(quality.clone())
+                        .with_accuracy(accuracy),
+                    );
+                }
+            }
+        }
+    }
+
+    // Optionally drop readings the device itself flagged as bad
+    if config.drop_bad_quality {
+        readings.retain(|r| r.quality.as_deref() != Some("bad"));
+    }
+
+    // NaN and infinities would poison downstream aggregates
+    if !config.allow_non_finite {
+        readings.retain(|r| {
+            if !r.value.is_finite() {
+                warn!("Rejected non-finite reading on topic {}: {}", r.topic, r.value);
+            }
+            r.value.is_finite()
+        });
+    }
+
+    if readings.is_empty() {
+        None
+    } else {
+        Some(readings)
+    }
+}
+
+/// Split a configured unit suffix off a key (e.g., "pressure_hpa" → ("pressure", Some("hpa")))
+fn split_unit_suffix<'a>(key: &'a str, config: &ParserConfig) -> (&'a str, Option<&'a str>) {
+    match key.rsplit_once('_') {
+        Some((metric, unit))
+            if !metric.is_empty() && config.unit_suffixes.iter().any(|suffix| suffix.eq_ignore_ascii_case(unit)) =>
+        {
+            (metric, Some(unit))
+        }
+        _ => (key, None),
+    }
+}
+
+/// Top-level keys whose nested values are already read as their own payload shape
+const NESTED_HANDLED_KEYS: &[&str] = &["sensors", "points", "values"];
+
+/// Levels of nested objects and arrays walked below the payload
+const MAX_NESTING_DEPTH: usize = 8;
+
+/// Keys never stored as readings, at any level of the payload
+fn excluded_metric_key(key: &str, config: &ParserConfig) -> bool {
+    NON_SENSOR_KEYS.contains(&key)
+        || config.excluded_keys.contains(key)
+        || config.accuracy_fields.iter().any(|field| field == key)
+        || (config.inline_thresholds && matches!(key, "min" | "max"))
+}
+
+/// Collect the numbers below a nested object or array of objects with their
+/// slash-joined paths (e.g., `env/temp`, `env/0/temp`)
+fn nested_values(path: &str, value: &Value, depth: usize, config: &ParserConfig, values: &mut Vec<(String, f64)>) {
+    match value {
+        Value::Object(obj) => {
+            for (key, child) in obj.iter().filter(|(key, _)| !excluded_metric_key(key, config)) {
+                let child_path = format!("{}/{}", path, key);
+                match coerce_value(child, key, config) {
+                    Some(num) => values.push((child_path, num)),
+                    None if depth < MAX_NESTING_DEPTH => nested_values(&child_path, child, depth + 1, config, values),
+                    None => {}
+                }
+            }
+        }
+        Value::Array(items) if depth < MAX_NESTING_DEPTH => {
+            for (index, item) in items.iter().enumerate().filter(|(_, item)| item.is_object()) {
+                nested_values(&format!("{}/{}", path, index), item, depth + 1, config, values);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Parse device log from JSON
+fn parse_device_log(topic: &str, json: &Value, config: &ParserConfig, ctx: &ParseContext) -> Option<DeviceLog> {
+    // Check if this looks like a log message
+    let level = json
+        .get("level")
+        .or_else(|| json.get("severity"))
+        .and_then(|v| v.as_str())?;
+
+    let message = json
+        .get("message")
+        .or_else(|| json.get("msg"))
+        .or_else(|| json.get("text"))
+        .and_then(|v| v.as_str())?;
+
+    let device_id = extract_device_id(topic, json, config, ctx)?;
+
+    Some(DeviceLog {
+        device_id,
+        level: level.to_string(),
+        message: message.to_string(),
+        topic: topic.to_string(),
+        tags: None,
+        retention_class: None,
+        schema_version: None,
+        topic_id: None,
+        device_class: None,
+        timestamp: extract_timestamp(topic, json, config),
+    })
+}
+
+/// Device id of a plain text log, from the topic query or path
+fn plain_text_device_id(topic: &str, config: &ParserConfig, ctx: &ParseContext) -> Option<String> {
+    if let Some(id) = ctx.cert_cn {
+        return Some(id.to_string());
+    }
+
+    if let Some(id) = topic_pattern_device_id(topic, config) {
+        return Some(id);
+    }
+
+    if let Some(id) = config
+        .device_id_query_key
+        .as_deref()
+        .and_then(|key| topic_query_value(topic, key))
+    {
+        return Some(id.to_string());
+    }
+
+    topic_segments(topic, config)
+        .find(|part| !part.is_empty() && *part != "diagnostics" && *part != "debug" && *part != "logs")
+        .map(Cow::into_owned)
+        .or_else(|| topic_default_device_id(topic, config))
+        .or_else(|| birth::cached_device_id(topic, config, ctx.births.as_deref()))
+}
+
+/// Parse plain text log
+fn parse_plain_text_log(topic: &str, text: &str, config: &ParserConfig, ctx: &ParseContext) -> Option<DeviceLog> {
+    let device_id = plain_text_device_id(topic, config, ctx).or_else(|| missing_device_id(config))?;
+
+    // Determine log level from topic or content
+    let level = if topic.contains("error") || text.to_lowercase().contains("error") {
+        "ERROR"
+    } else if topic.contains("warn") || text.to_lowercase().contains("warn") {
+        "WARN"
+    } else if topic.contains("debug") || topic.contains("diagnostics") {
+        "DEBUG"
+    } else {
+        "INFO"
+    };
+
+    Some(DeviceLog {
+        device_id,
+        level: level.to_string(),
+        message: text.to_string(),
+        topic: topic.to_string(),
+        tags: None,
+        retention_class: None,
+        schema_version: None,
+        topic_id: None,
+        device_class: None,
+        timestamp: extract_topic_timestamp(topic, config).unwrap_or_else(Utc::now),
+    })
+}
+
+/// Parse a bare `metric:value` payload (e.g., "temp:22.5") into a reading on
+/// `<topic>/<metric>`, splitting on the first configured delimiter
+fn parse_metric_pair(topic: &str, text: &str, config: &ParserConfig, ctx: &ParseContext) -> Option<SensorReading> {
+    let (metric, value) = text.trim().split_once(config.metric_pair_delimiters.as_slice())?;
+    let metric = metric.trim_end();
+    if metric.is_empty() || metric.contains(char::is_whitespace) {
+        return None;
+    }
+    let value = coerce_value(&Value::String(value.to_string()), metric, config)?;
+
+    Some(SensorReading::new(
+        plain_text_device_id(topic, config, ctx).or_else(|| missing_device_id(config))?,
+        format!("{}/{}", topic, metric),
+        value,
+        extract_topic_timestamp(topic, config).unwrap_or_else(Utc::now),
+    ))
+}
+
+/// Device id for a record, falling back per `on_missing_device_id`.
+/// None means the record should be skipped.
+fn extract_device_id(topic: &str, json: &Value, config: &ParserConfig, ctx: &ParseContext) -> Option<String> {
+    identify_device(topic, json, config, ctx).or_else(|| missing_device_id(config))
+}
+
+/// Placeholder device id when none can be determined, unless the policy skips or rejects
+fn missing_device_id(config: &ParserConfig) -> Option<String> {
+    match &config.on_missing_device_id {
+        MissingDeviceIdPolicy::Default(id) => Some(id.clone()),
+        // Rejection is reported once per message by `require_device_id`
+        MissingDeviceIdPolicy::Skip | MissingDeviceIdPolicy::Error => None,
+    }
+}
+
+/// Reject the message when the device cannot be identified under the `error` policy
+fn require_device_id(config: &ParserConfig, identify: impl FnOnce() -> Option<String>) -> Result<(), ParseError> {
+    match config.on_missing_device_id {
+        MissingDeviceIdPolicy::Error if identify().is_none() => Err(ParseError::MissingDeviceId),
+        _ => Ok(()),
+    }
+}
+
+/// Identify the device, taking the first of:
+/// 1. the client certificate CN
+/// 2. the `id` group of `device_id_pattern` matched against the topic
+/// 3. the `device_id`, `deviceId` or `device` JSON field
+/// 4. the configured CoAP query key
+/// 5. the first topic segment that looks like a device id
+/// 6. the configured default for the topic, then the id cached from a birth message
+fn identify_device(topic: &str, json: &Value, config: &ParserConfig, ctx: &ParseContext) -> Option<String> {
+    // A client certificate identifies the device authoritatively
+    if let Some(id) = ctx.cert_cn {
+        return Some(id.to_string());
+    }
+
+    if let Some(id) = topic_pattern_device_id(topic, config) {
+        return Some(id);
+    }
+
+    // Try to get from JSON first
+    if let Some(id) = json
+        .get("device_id")
+        .or_else(|| json.get("deviceId"))
+        .or_else(|| json.get("device"))
+        .and_then(|v| v.as_str())
+    {
+        return Some(id.to_string());
+    }
+
+    // Try the configured query key of a CoAP-style topic (e.g., "sensors/temp?dev=d1")
+    if let Some(id) = config
+        .device_id_query_key
+        .as_deref()
+        .and_then(|key| topic_query_value(topic, key))
+    {
+        return Some(id.to_string());
+    }
+
+    // Try to extract from topic (e.g., "telemetry/device123/temperature")
+    let parts: Vec<Cow<str>> = topic_segments(topic, config).collect();
+    if parts.len() >= 2 {
+        // Look for part that looks like a device ID
+        for (index, part) in parts.iter().enumerate() {
+            // Skip the segment configured to carry the timestamp
+            if config.timestamp_topic_segment == Some(index) {
+                continue;
+            }
+            if part.starts_with("device") || part.len() >= 8 {
+                return Some(part.to_string());
+            }
+        }
+    }
+
+    topic_default_device_id(topic, config).or_else(|| birth::cached_device_id(topic, config, ctx.births.as_deref()))
+}
+
+/// The `id` capture of the configured device id pattern on the topic path
+fn topic_pattern_device_id(topic: &str, config: &ParserConfig) -> Option<String> {
+    let captures = config.device_id_pattern.as_ref()?.captures(topic_path(topic))?;
+    captures.name("id").map(|id| id.as_str().to_string()).filter(|id| !id.is_empty())
+}
+
+/// Configured device id for a single-device topic
+fn topic_default_device_id(topic: &str, config: &ParserConfig) -> Option<String> {
+    let path = topic_path(topic);
+
+    config
+        .default_device_ids
+        .iter()
+        .find(|rule| rule.topic.matches(path))
+        .map(|rule| rule.device_id.clone())
+}
+
+/// Topic without any `?`-delimited query portion
+fn topic_path(topic: &str) -> &str {
+    topic.split_once('?').map_or(topic, |(path, _)| path)
+}
+
+/// Path segments of a topic, percent-decoded when enabled (e.g., "device%2F1" → "device/1")
+fn topic_segments<'a>(topic: &'a str, config: &ParserConfig) -> impl Iterator<Item = Cow<'a, str>> {
+    let decode = config.percent_decode_topics;
+
+    topic_path(topic).split('/').map(move |segment| {
+        if decode {
+            percent_decode_str(segment).decode_utf8().unwrap_or(Cow::Borrowed(segment))
+        } else {
+            Cow::Borrowed(segment)
+        }
+    })
+}
+
+/// Look up a key in the `?`-delimited query portion of a topic (e.g., "sensors/temp?dev=d1")
+fn topic_query_value<'a>(topic: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = topic.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, v)| *k == key && !v.is_empty())
+        .map(|(_, v)| v)
+}
+
+/// Axis values of an object with numeric `x`, `y` and `z` fields
+fn extract_vector(value: &Value) -> Option<[(&'static str, f64); 3]> {
+    let axis = |name| value.get(name).and_then(|v| v.as_f64());
+    Some([("x", axis("x")?), ("y", axis("y")?), ("z", axis("z")?)])
+}
+
+/// Expand a delta-time batch into timestamped values: `values[i]` was sampled at
+/// `t0 + i * dt`, with `dt` in seconds (defaults to 1)
+fn parse_delta_batch(topic: &str, json: &Value, config: &ParserConfig) -> Option<Vec<(chrono::DateTime<Utc>, f64)>> {
+    let t0 = json.get("t0").and_then(parse_timestamp_value)?;
+    let values = cap_array(topic, "values", json.get("values")?.as_array()?, config);
+    let dt = json.get("dt").and_then(|v| v.as_f64()).unwrap_or(1.0);
+    if !dt.is_finite() {
+        return None;
+    }
+    // Saturating cast; out-of-range offsets are rejected per value below
+    let dt_ms = (dt * 1000.0).round() as i64;
+
+    let batch = values
+        .iter()
+        .enumerate()
+        .filter_map(|(i, value)| {
+            let offset = chrono::Duration::try_milliseconds(dt_ms.checked_mul(i64::try_from(i).ok()?)?)?;
+            Some((t0.checked_add_signed(offset)?, value.as_f64()?))
+        })
+        .collect();
+
+    Some(batch)
+}
+
+/// Extract timestamp from JSON, then the configured topic segment, or use current time
+fn extract_timestamp(topic: &str, json: &Value, config: &ParserConfig) -> chrono::DateTime<Utc> {
+    json.get("timestamp")
+        .or_else(|| json.get("ts"))
+        .and_then(parse_timestamp_value)
+        .or_else(|| extract_topic_timestamp(topic, config))
+        .unwrap_or_else(Utc::now)
+}
+
+/// Extract timestamp from the configured topic segment
+/// (e.g., segment 2 of "data/d1/2024-01-01T12:00:00Z/temp")
+fn extract_topic_timestamp(topic: &str, config: &ParserConfig) -> Option<chrono::DateTime<Utc>> {
+    let index = config.timestamp_topic_segment?;
+    let segment = topic_segments(topic, config).nth(index)?;
+
+    parse_timestamp_text(&segment)
+}
+
+/// Parse a textual timestamp: RFC 3339 or Unix seconds/milliseconds/microseconds, possibly fractional
+fn parse_timestamp_text(text: &str) -> Option<chrono::DateTime<Utc>> {
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(text) {
+        return Some(dt.with_timezone(&Utc));
+    }
+
+    match text.parse::<i64>() {
+        Ok(ts) => parse_unix_timestamp(ts),
+        Err(_) => text.parse::<f64>().ok().and_then(parse_fractional_unix_timestamp),
+    }
+}
+
+/// Parse a JSON timestamp value (ISO8601 string or Unix timestamp)
+fn parse_timestamp_value(ts: &Value) -> Option<chrono::DateTime<Utc>> {
+    // Try to parse as ISO8601 string
+    if let Some(ts_str) = ts.as_str() {
+        if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(ts_str) {
+            return Some(dt.with_timezone(&Utc));
+        }
+    }
+
+    // Try to parse as Unix timestamp (seconds, milliseconds or microseconds), keeping any fraction
+    match ts.as_i64() {
+        Some(ts) => parse_unix_timestamp(ts),
+        None => ts.as_f64().and_then(parse_fractional_unix_timestamp),
+    }
+}
+
+/// Year 2100 in Unix seconds; larger values are read as finer units
+const MAX_UNIX_SECONDS: i64 = 4_102_444_800;
+
+/// Year 2100 in Unix milliseconds; larger values are read as microseconds
+const MAX_UNIX_MILLISECONDS: i64 = MAX_UNIX_SECONDS * 1000;
+
+/// Convert a Unix timestamp in seconds, milliseconds or microseconds
+fn parse_unix_timestamp(ts_num: i64) -> Option<chrono::DateTime<Utc>> {
+    if ts_num > MAX_UNIX_MILLISECONDS {
+        // Microseconds (16 digits)
+        chrono::DateTime::from_timestamp_micros(ts_num)
+    } else if ts_num > MAX_UNIX_SECONDS {
+        // Milliseconds (13 digits)
+        chrono::DateTime::from_timestamp_millis(ts_num)
+    } else {
+        // Seconds
+        chrono::DateTime::from_timestamp(ts_num, 0)
+    }
+}
+
+/// Convert a fractional Unix timestamp (e.g., 1700000000.5) with sub-second precision
+fn parse_fractional_unix_timestamp(ts: f64) -> Option<chrono::DateTime<Utc>> {
+    if !ts.is_finite() {
+        return None;
+    }
+
+    // Same unit heuristic as integer timestamps, scaled to nanoseconds per unit
+    let nanos_per_unit = if ts > MAX_UNIX_MILLISECONDS as f64 {
+        1_000
+    } else if ts > MAX_UNIX_SECONDS as f64 {
+        1_000_000
+    } else {
+        1_000_000_000
+    };
+    let units_per_second = 1_000_000_000 / nanos_per_unit;
+
+    let whole = ts.floor();
+    let units = whole as i64;
+    let nsecs = units.rem_euclid(units_per_second) * nanos_per_unit + ((ts - whole) * nanos_per_unit as f64).round() as i64;
+
+    chrono::DateTime::from_timestamp(units.div_euclid(units_per_second), nsecs.min(999_999_999) as u32)
+}
+
+/// Convert a raw device timestamp counted from a configured epoch
+fn from_device_epoch(ts: i64, epoch: &HealthTimestampEpoch) -> Option<chrono::DateTime<Utc>> {
+    let offset = match epoch.unit {
+        TimestampUnit::Seconds => chrono::Duration::try_seconds(ts)?,
+        TimestampUnit::Milliseconds => chrono::Duration::try_milliseconds(ts)?,
+    };
+
+    epoch.epoch.unwrap_or(chrono::DateTime::UNIX_EPOCH).checked_add_signed(offset)
+}
+
+/// Alerts for readings above their `max` or below their `min`. Inline bounds are
+/// read from the object holding the value (the payload, or its `sensors` entry)
+/// and take precedence over a configured bound for the reading's topic.
+fn threshold_alerts(topic: &str, json: &Value, readings: &[SensorReading], config: &ParserConfig) -> Vec<DeviceAlert> {
+    if !config.inline_thresholds && config.thresholds.is_empty() {
+        return Vec::new();
+    }
+
+    let mut alerts = Vec::new();
+    for reading in readings {
+        let source = if reading.topic == topic {
+            Some(json)
+        } else {
+            reading
+                .topic
+                .strip_prefix(topic)
+                .and_then(|rest| rest.strip_prefix('/'))
+                .and_then(|name| {
+                    json.get("sensors")?
+                        .as_array()?
+                        .iter()
+                        .find(|sensor| sensor.get("name").and_then(|v| v.as_str()) == Some(name))
+                })
+        };
+        let rule = config.thresholds.iter().find(|rule| rule.topic.matches(topic_path(&reading.topic)));
+        let bound = |field: &str, configured: Option<f64>| {
+            source
+                .filter(|_| config.inline_thresholds)
+                .and_then(|source| source.get(field))
+                .and_then(|v| v.as_f64())
+                .or(configured)
+        };
+
+        let breach = match (bound("min", rule.and_then(|rule| rule.min)), bound("max", rule.and_then(|rule| rule.max))) {
+            (_, Some(max)) if reading.value > max => Some(("above_max", max)),
+            (Some(min), _) if reading.value < min => Some(("below_min", min)),
+            _ => None,
+        };
+        if let Some((key, threshold)) = breach {
+            alerts.push(DeviceAlert {
+                device_id: reading.device_id.clone(),
+                topic: reading.topic.clone(),
+                alert_key: key.to_string(),
+                alert_value: Value::Null,
+                threshold: Some(threshold),
+                current: Some(reading.value),
+                tags: None,
+                retention_class: None,
+                schema_version: None,
+                topic_id: None,
+                device_class: None,
+                timestamp: reading.timestamp,
+            });
+        }
+    }
+
+    alerts
+}
+
+/// Alert names and values from `{"overheat": {...}}` maps or
+/// `[{"key": "overheat", "value": {...}}]` lists
+fn alert_entries(alerts: Option<&Value>) -> Vec<(String, &Value)> {
+    match alerts {
+        Some(Value::Object(map)) => map.iter().map(|(key, value)| (key.clone(), value)).collect(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter_map(|item| {
+                let key = item.get("key").or_else(|| item.get("name"))?.as_str()?;
+                Some((key.to_string(), item.get("value").unwrap_or(&Value::Null)))
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Seconds the payload's own timestamp is ahead of the receive time, when it has one
+fn clock_offset_seconds(json: &Value, received: chrono::DateTime<Utc>) -> Option<f64> {
+    let reported = json.get("timestamp").or_else(|| json.get("ts")).and_then(parse_timestamp_value)?;
+    Some((reported - received).num_milliseconds() as f64 / 1000.0)
+}
+
+/// Parse device state and health from JSON
+/// Expected format:
+/// {
+///   "main_state": 1,
+///   "secondary_state": 0,
+///   "alerts": {},
+///   "rssi": -29,
+///   "health": "{\"general\":{\"wifiSsid\":\"GL-S200-33f\",\"freeHeapSize\":57940,...},\"tasks\":[...]}"
+/// }
+fn parse_device_state_and_health(
+    topic: &str,
+    json: &Value,
+    config: &ParserConfig,
+    ctx: &ParseContext,
+) -> Option<Vec<ParsedMessage>> {
+    // Check if this looks like a device state message
+    // It should have at least one of: main_state, secondary_state, alerts, rssi
+    let has_state_fields = json.get("main_state").is_some()
+        || json.get("secondary_state").is_some()
+        || json.get("alerts").is_some()
+        || json.get("rssi").is_some();
+
+    if !has_state_fields {
+        return None;
+    }
+
+    let device_id = extract_device_id(topic, json, config, ctx)?;
+    let timestamp = extract_timestamp(topic, json, config);
+    let mut records = Vec::new();
+
+    // Parse device state (support both snake_case and camelCase)
+    if config.records.device_states {
+        records.push(ParsedMessage::DeviceState(DeviceState {
+            device_id: device_id.clone(),
+            topic: topic.to_string(),
+            main_state: json.get("main_state")
+                .or_else(|| json.get("mainState"))
+                .and_then(|v| v.as_i64())
+                .and_then(|v| i32::try_from(v).ok()),
+            secondary_state: json.get("secondary_state")
+                .or_else(|| json.get("secondaryState"))
+                .and_then(|v| v.as_i64())
+                .and_then(|v| i32::try_from(v).ok()),
+            alerts: json.get("alerts")
+                .or_else(|| json.get("state"))
+                .cloned(),
+            rssi: json.get("rssi").and_then(|v| v.as_i64()).and_then(|v| i32::try_from(v).ok()),
+            state_reason: json.get("reason").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            tags: None,
+            retention_class: None,
+            schema_version: None,
+            topic_id: None,
+            device_class: None,
+            timestamp,
+        }));
+    }
+
+    // One row per alert entry, keeping value objects and their threshold/current detail
+    if config.records.device_alerts {
+        for (key, value) in alert_entries(json.get("alerts")) {
+            let detail = |field: &str| value.get(field).and_then(|v| v.as_f64());
+            records.push(ParsedMessage::DeviceAlert(DeviceAlert {
+                device_id: device_id.clone(),
+                topic: topic.to_string(),
+                alert_key: key,
+                alert_value: value.clone(),
+                threshold: detail("threshold"),
+                current: detail("current"),
+                tags: None,
+                retention_class: None,
+                schema_version: None,
+                topic_id: None,
+                device_class: None,
+                timestamp,
+            }));
+        }
+    }
+
+    // Parse health data if present (skipped entirely when no health record type is enabled)
+    // Health can be a string (JSON encoded) or direct object
+    let wants_health = config.records.device_health || config.records.task_health;
+    let health_json = json.get("health").filter(|_| wants_health).and_then(|health_value| {
+        if let Some(health_str) = health_value.as_str() {
+            serde_json::from_str::<Value>(health_str).ok()
+        } else {
+            Some(health_value.clone())
+        }
+    });
+
+    if let Some(health_json) = health_json {
+        // Extract general health data; other sections are only kept in raw_health
+        let general = health_json.get("general");
+        let has_sections = health_json
+            .as_object()
+            .is_some_and(|sections| sections.keys().any(|section| section != "tasks"));
+        if config.records.device_health && (general.is_some() || has_sections) {
+            let general = general.unwrap_or(&Value::Null);
+            let last_wifi_connection_ts = general.get("lastWifiConnectionTs").and_then(|v| v.as_i64());
+            let last_cloud_connection_ts = general.get("lastCloudConnectionTs").and_then(|v| v.as_i64());
+            let epoch = config.health_timestamp_epochs.get(&device_id);
+
+            records.push(ParsedMessage::DeviceHealth(DeviceHealth {
+                device_id: device_id.clone(),
+                topic: topic.to_string(),
+                wifi_ssid: general.get("wifiSsid").and_then(|v| v.as_str()).map(|s| s.to_string()),
+                free_heap_size: general.get("freeHeapSize").and_then(|v| v.as_i64()),
+                min_heap_size: general.get("minHeapSize").and_then(|v| v.as_i64()),
+                unexpected_reset_counter: general.get("unexpectedResetCounter").and_then(|v| v.as_i64()).and_then(|v| i32::try_from(v).ok()),
+                last_reset_reason: general.get("lastResetReason").and_then(|v| v.as_str()).map(|s| s.to_string()),
+                wifi_connect_counter: general.get("wifiConnectCounter").and_then(|v| v.as_i64()).and_then(|v| i32::try_from(v).ok()),
+                cloud_connect_counter: general.get("cloudConnectCounter").and_then(|v| v.as_i64()).and_then(|v| i32::try_from(v).ok()),
+                last_wifi_connection_ts,
+                last_cloud_connection_ts,
+                last_wifi_connection_at: epoch.zip(last_wifi_connection_ts).and_then(|(e, ts)| from_device_epoch(ts, e)),
+                last_cloud_connection_at: epoch.zip(last_cloud_connection_ts).and_then(|(e, ts)| from_device_epoch(ts, e)),
+                raw_health: Some(health_json.clone()),
+                clock_offset_seconds: clock_offset_seconds(json, Utc::now()),
+                tags: None,
+                retention_class: None,
+                schema_version: None,
+                topic_id: None,
+                device_class: None,
+                timestamp,
+            }));
+        }
+
+        // Extract per-task watchdog data (RTOS task list)
+        let tasks = health_json.get("tasks").filter(|_| config.records.task_health);
+        if let Some(tasks) = tasks.and_then(|v| v.as_array()) {
+            for task in tasks {
+                if let Some(name) = task.get("name").and_then(|v| v.as_str()) {
+                    records.push(ParsedMessage::TaskHealth(TaskHealth {
+                        device_id: device_id.clone(),
+                        topic: topic.to_string(),
+                        task_name: name.to_string(),
+                        stack_free: task.get("stackFree").and_then(|v| v.as_i64()),
+                        state: task.get("state").and_then(|v| v.as_str()).map(|s| s.to_string()),
+                        tags: None,
+                        retention_class: None,
+                        schema_version: None,
+                        topic_id: None,
+                        device_class: None,
+                        timestamp,
+                    }));
+                }
+            }
+        }
+    }
+
+    Some(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    fn config(toml: &str) -> ParserConfig {
+        toml::from_str(toml).expect("valid parser config")
+    }
+
+    fn parse(topic: &str, payload: &str, config: &ParserConfig) -> Vec<ParsedMessage> {
+        parse_message(
+            topic,
+            payload.as_bytes(),
+            0,
+            false,
+            config,
+            &mut ParseContext::default(),
+        )
+        .expect("message parses")
+    }
+
+    fn readings(records: &[ParsedMessage]) -> Vec<&SensorReading> {
+        records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::SensorReading(reading) => Some(reading),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn task_health(records: &[ParsedMessage]) -> Vec<&TaskHealth> {
+        records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::TaskHealth(task) => Some(task),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn task_health_array_produces_one_row_per_task() {
+        let payload = r#"{"device_id": "rtos-1", "main_state": 1, "health": {"general": {"freeHeapSize": 1000},
+            "tasks": [{"name": "wifi", "stackFree": 512, "state": "running"}, {"name": "mqtt", "stackFree": 256, "state": "blocked"}]}}"#;
+        let records = parse("devices/rtos-1/state", payload, &ParserConfig::default());
+
+        let tasks = task_health(&records);
+        assert_eq!(tasks.len(), 2);
+        assert_eq!((tasks[0].task_name.as_str(), tasks[0].stack_free), ("wifi", Some(512)));
+        assert_eq!((tasks[1].task_name.as_str(), tasks[1].state.as_deref()), ("mqtt", Some("blocked")));
+        assert!(tasks.iter().all(|task| task.device_id == "rtos-1"));
+        let task_records = records.iter().filter(|record| record.base_table() == "task_health").count();
+        assert_eq!(task_records, 2);
+    }
+
+    #[test]
+    fn missing_task_array_produces_no_task_rows() {
+        let payload = r#"{"device_id": "rtos-1", "main_state": 1, "health": {"general": {"freeHeapSize": 1000}}}"#;
+        let records = parse("devices/rtos-1/state", payload, &ParserConfig::default());
+
+        assert!(task_health(&records).is_empty());
+    }
+
+    #[test]
+    fn timestamp_is_taken_from_topic_segment() {
+        let config = config("timestamp_topic_segment = 2");
+        let records = parse("data/d1/2024-01-01T12:00:00Z", r#"{"device_id": "d1", "temp": 21.5}"#, &config);
+
+        let readings = readings(&records);
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].timestamp.to_rfc3339(), "2024-01-01T12:00:00+00:00");
+    }
+
+    #[test]
+    fn disabled_device_health_produces_no_health_record() {
+        let payload = r#"{"device_id": "rtos-1", "main_state": 1, "health": {"general": {"freeHeapSize": 1000}}}"#;
+        let is_health = |record: &ParsedMessage| matches!(record, ParsedMessage::DeviceHealth(_));
+
+        let records = parse("devices/rtos-1/state", payload, &ParserConfig::default());
+        assert!(records.iter().any(is_health));
+
+        let records = parse("devices/rtos-1/state", payload, &config("[records]\ndevice_health = false"));
+        assert!(!records.iter().any(is_health));
+        assert!(records.iter().any(|record| matches!(record, ParsedMessage::SocketRead(_))));
+    }
+
+    #[test]
+    fn double_encoded_payload_is_decoded() {
+        let payload = r#"{"device_id": "d1", "payload": "{\"temp\": 21.5}"}"#;
+        let records = parse("sensors/d1", payload, &ParserConfig::default());
+
+        let readings = readings(&records);
+        assert_eq!(readings.len(), 1);
+        assert_eq!((readings[0].topic.as_str(), readings[0].value), ("sensors/d1/temp", 21.5));
+        assert_eq!(readings[0].device_id, "d1");
+    }
+
+    #[test]
+    fn slow_parse_warning_fires_for_large_payload() {
+        let values: Vec<String> = (0..2000).map(|i| format!("\"m{}\": {}", i, i)).collect();
+        let payload = format!("{{\"device_id\": \"d1\", {}}}", values.join(", "));
+        let before = COUNTERS.slow_parses.load(Ordering::Relaxed);
+
+        parse("sensors/d1", &payload, &config("slow_parse_threshold_ms = 0"));
+
+        assert!(COUNTERS.slow_parses.load(Ordering::Relaxed) > before);
+    }
+
+    #[test]
+    fn device_id_is_taken_from_topic_query() {
+        let records = parse("sensors/temp?dev=d1", r#"{"temp": 21.5}"#, &config("device_id_query_key = \"dev\""));
+
+        let readings = readings(&records);
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].device_id, "d1");
+    }
+
+    #[test]
+    fn deeply_nested_payload_is_rejected() {
+        let payload = format!(r#"{{"device_id": "d1", "temp": {}1{}}}"#, "[".repeat(100_000), "]".repeat(100_000));
+        let before = COUNTERS.rejected_deep_payloads.load(Ordering::Relaxed);
+
+        let records = parse("sensors/d1", &payload, &ParserConfig::default());
+
+        assert!(readings(&records).is_empty());
+        assert!(records.iter().any(|record| matches!(record, ParsedMessage::SocketRead(_))));
+        assert!(COUNTERS.rejected_deep_payloads.load(Ordering::Relaxed) > before);
+    }
+
+    #[test]
+    fn numeric_quality_codes_are_normalized() {
+        let quality = |code: u64, config: &ParserConfig| {
+            let payload = format!(r#"{{"device_id": "d1", "temp": 21.5, "quality": {}}}"#, code);
+            let records = parse("sensors/d1", &payload, config);
+            readings(&records).first().and_then(|reading| reading.quality.clone())
+        };
+        let opc_ua = config("quality_codes = \"opc_ua\"");
+
+        assert_eq!(quality(192, &ParserConfig::default()).as_deref(), Some("good"));
+        assert_eq!(quality(0, &opc_ua).as_deref(), Some("good"));
+        assert_eq!(quality(0x4000_0000, &opc_ua).as_deref(), Some("uncertain"));
+        assert_eq!(quality(0x8000_0000, &opc_ua).as_deref(), Some("bad"));
+    }
+
+    #[test]
+    fn bad_quality_readings_can_be_dropped() {
+        let config = config("quality_codes = \"opc_ua\"\ndrop_bad_quality = true");
+        let records = parse("sensors/d1", r#"{"device_id": "d1", "temp": 21.5, "quality": 2147483648}"#, &config);
+
+        assert!(readings(&records).is_empty());
+    }
+
+    #[test]
+    fn global_tags_are_stamped_on_readings() {
+        let config = config("[global_tags]\nregion = \"eu-west\"\nenv = \"prod\"");
+        let records = parse("sensors/d1", r#"{"device_id": "d1", "temp": 21.5}"#, &config);
+
+        let readings = readings(&records);
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].tags, Some(serde_json::json!({"region": "eu-west", "env": "prod"})));
+    }
+
+    #[test]
+    fn state_change_reason_is_captured() {
+        let payload = r#"{"device_id": "d1", "main_state": 2, "reason": "overheat"}"#;
+        let records = parse("devices/d1/state", payload, &ParserConfig::default());
+
+        let reasons: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::DeviceState(state) => Some(state.state_reason.as_deref()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reasons, [Some("overheat")]);
+    }
+
+    #[test]
+    fn device_classes_route_to_separate_tables() {
+        let config = config("[device_class_tables]\nfield = \"class\"");
+        let mut tables: Vec<String> = ["pump", "valve"]
+            .iter()
+            .flat_map(|class| {
+                let payload = format!(r#"{{"device_id": "d1", "class": "{}", "temp": 21.5}}"#, class);
+                parse("sensors/d1", &payload, &config)
+            })
+            .filter(|record| matches!(record, ParsedMessage::SensorReading(_)))
+            .map(|record| {
+                let (base, class) = record.class_route().expect("reading is routed by class");
+                crate::db::class_table(base, Some(class))
+            })
+            .collect();
+        tables.sort();
+
+        assert_eq!(tables, ["pump_sensor_readings", "valve_sensor_readings"]);
+    }
+
+    #[test]
+    fn debug_topics_are_tagged_with_their_retention_class() {
+        let config = config(
+            r#"
+            default_retention_class = "long"
+            [[retention_classes]]
+            topic = "debug/#"
+            class = "short"
+            "#,
+        );
+
+        let records = parse("debug/d1", r#"{"device_id": "d1", "temp": 21.5}"#, &config);
+        assert_eq!(readings(&records)[0].retention_class.as_deref(), Some("short"));
+        let socket_read = records.iter().find_map(|record| match record {
+            ParsedMessage::SocketRead(read) => Some(read),
+            _ => None,
+        });
+        assert_eq!(socket_read.unwrap().retention_class.as_deref(), Some("short"));
+
+        let records = parse("sensors/d1", r#"{"device_id": "d1", "temp": 21.5}"#, &config);
+        assert_eq!(readings(&records)[0].retention_class.as_deref(), Some("long"));
+    }
+
+    #[test]
+    fn delta_batch_reconstructs_timestamps_and_values() {
+        let payload = r#"{"device_id": "d1", "t0": 1700000000, "dt": 10, "values": [21.0, 21.5, 22.0]}"#;
+        let records = parse("sensors/d1/temp", payload, &ParserConfig::default());
+
+        let readings: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.timestamp.timestamp(), reading.value))
+            .collect();
+        assert_eq!(readings, [(1700000000, 21.0), (1700000010, 21.5), (1700000020, 22.0)]);
+    }
+
+    #[test]
+    fn magnitude_and_sign_fields_combine_into_signed_value() {
+        let config = config(
+            r#"
+            [[signed_values]]
+            magnitude = "current"
+            sign = "negative"
+            "#,
+        );
+        let records = parse("sensors/d1", r#"{"device_id": "d1", "current": 5.2, "negative": true}"#, &config);
+
+        let readings: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.topic.as_str(), reading.value))
+            .collect();
+        assert_eq!(readings, [("sensors/d1/current", -5.2)]);
+    }
+
+    #[test]
+    fn imu_vector_produces_one_reading_per_axis() {
+        let payload = r#"{"device_id": "d1", "accel": {"x": 0.1, "y": -0.2, "z": 9.8}}"#;
+        let values = |config: &ParserConfig| -> Vec<(String, f64)> {
+            readings(&parse("imu/d1", payload, config))
+                .into_iter()
+                .map(|reading| (reading.topic.clone(), reading.value))
+                .collect()
+        };
+
+        let axes = values(&ParserConfig::default());
+        let expected = [("imu/d1/accel/x", 0.1), ("imu/d1/accel/y", -0.2), ("imu/d1/accel/z", 9.8)];
+        assert_eq!(axes, expected.map(|(topic, value)| (topic.to_string(), value)));
+
+        let with_magnitude = values(&config("vector_magnitude = true"));
+        assert_eq!(with_magnitude.len(), 4);
+        assert_eq!(with_magnitude[3].0, "imu/d1/accel/magnitude");
+        assert!((with_magnitude[3].1 - (0.01f64 + 0.04 + 96.04).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn missing_device_id_policies() {
+        let payload = br#"{"temp": 21.5}"#;
+        let parse = |policy: &str| parse_message(
+            "a/b",
+            payload,
+            0,
+            false,
+            &config(policy),
+            &mut ParseContext::default(),
+        );
+
+        let records = parse("on_missing_device_id = { default = \"anon\" }").unwrap();
+        assert_eq!(readings(&records)[0].device_id, "anon");
+
+        let records = parse("on_missing_device_id = \"skip\"").unwrap();
+        assert!(readings(&records).is_empty());
+        assert!(records.iter().any(|record| matches!(record, ParsedMessage::SocketRead(_))));
+
+        let rejected = parse("on_missing_device_id = \"error\"");
+        assert!(matches!(rejected, Err(ParseError::MissingDeviceId)));
+    }
+
+    #[test]
+    fn percent_encoded_topic_segment_is_decoded() {
+        let records = parse("sensors/device%2F1", r#"{"temp": 21.5}"#, &config("percent_decode_topics = true"));
+        assert_eq!(readings(&records)[0].device_id, "device/1");
+
+        let records = parse("sensors/device%2F1", r#"{"temp": 21.5}"#, &ParserConfig::default());
+        assert_eq!(readings(&records)[0].device_id, "device%2F1");
+    }
+
+    #[test]
+    fn metric_is_mapped_to_sensor_type() {
+        let config = config("[sensor_types]\ntemp = \"temperature\"");
+        let records = parse("sensors/d1", r#"{"device_id": "d1", "temp": 21.5, "volts": 3.3}"#, &config);
+
+        let types: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.topic.as_str(), reading.sensor_type.as_deref()))
+            .collect();
+        assert_eq!(types, [("sensors/d1/temp", Some("temperature")), ("sensors/d1/volts", None)]);
+    }
+
+    #[test]
+    fn points_array_produces_timestamped_readings() {
+        let payload = r#"{"device_id": "d1", "points": [[1700000000, 21.0], [1700000060, 21.5]]}"#;
+        let records = parse("sensors/d1/temp", payload, &ParserConfig::default());
+
+        let readings: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.timestamp.timestamp(), reading.value))
+            .collect();
+        assert_eq!(readings, [(1700000000, 21.0), (1700000060, 21.5)]);
+    }
+
+    #[test]
+    fn malformed_payload_socket_read_carries_the_error() {
+        let socket_read_error = |config: &ParserConfig| {
+            parse("sensors/d1", r#"{"device_id": "d1", "temp": }"#, config).into_iter().find_map(|record| match record {
+                ParsedMessage::SocketRead(read) => Some(read.parse_error),
+                _ => None,
+            })
+        };
+
+        let error = socket_read_error(&config("store_parse_errors = true")).expect("raw read is stored");
+        assert!(error.is_some_and(|error| error.contains("expected value")));
+        assert_eq!(socket_read_error(&ParserConfig::default()), Some(None));
+    }
+
+    #[test]
+    fn legacy_topic_is_rewritten_with_captures() {
+        let config = config(r#"topic_rewrite = [["legacy/+/temp", "sensors/$1/temperature"]]"#);
+        let records = parse("legacy/d1/temp", r#"{"device_id": "d1", "value": 21.5}"#, &config);
+
+        let topics: Vec<_> = records.iter().filter_map(|record| record.topic()).collect();
+        assert!(topics.contains(&"sensors/d1/temperature"));
+        assert!(topics.iter().all(|topic| topic.starts_with("sensors/d1/temperature")));
+    }
+
+    #[test]
+    fn fractional_seconds_timestamp_keeps_the_half_second() {
+        let payload = r#"{"device_id": "d1", "ts": 1700000000.5, "temp": 21.5}"#;
+        let records = parse("sensors/d1", payload, &ParserConfig::default());
+
+        let timestamp = readings(&records)[0].timestamp;
+        assert_eq!(timestamp.timestamp(), 1700000000);
+        assert_eq!(timestamp.timestamp_subsec_millis(), 500);
+    }
+
+    #[test]
+    fn configured_default_device_id_is_applied() {
+        let config = config(
+            r#"
+            [[default_device_ids]]
+            topic = "plant/boiler/#"
+            device_id = "boiler-1"
+            "#,
+        );
+
+        let records = parse("plant/boiler/temp", r#"{"temp": 81.5}"#, &config);
+        assert_eq!(readings(&records)[0].device_id, "boiler-1");
+
+        let records = parse("plant/chiller/temp", r#"{"temp": 6.5}"#, &config);
+        assert_eq!(readings(&records)[0].device_id, "unknown");
+    }
+
+    #[test]
+    fn units_keyed_map_sets_the_unit() {
+        let payload = r#"{"device_id": "d1", "celsius": {"temp": 21.5}}"#;
+        let records = parse("sensors/d1", payload, &config("unit_keyed_values = true"));
+
+        let readings = readings(&records);
+        assert_eq!(readings.len(), 1);
+        assert_eq!((readings[0].topic.as_str(), readings[0].value), ("sensors/d1/temp", 21.5));
+        assert_eq!(readings[0].unit.as_deref(), Some("celsius"));
+    }
+
+    #[test]
+    fn boot_relative_health_timestamp_becomes_absolute() {
+        let config = config(
+            r#"
+            [health_timestamp_epochs.rtos-1]
+            epoch = "2024-01-01T00:00:00Z"
+            unit = "milliseconds"
+            "#,
+        );
+        let payload = r#"{"device_id": "rtos-1", "main_state": 1, "health": {"general": {"lastWifiConnectionTs": 90000}}}"#;
+        let records = parse("devices/rtos-1/state", payload, &config);
+
+        let health = records
+            .iter()
+            .find_map(|record| match record {
+                ParsedMessage::DeviceHealth(health) => Some(health),
+                _ => None,
+            })
+            .expect("payload carries health");
+        assert_eq!(health.last_wifi_connection_ts, Some(90000));
+        let absolute = health.last_wifi_connection_at.map(|at| at.to_rfc3339());
+        assert_eq!(absolute.as_deref(), Some("2024-01-01T00:01:30+00:00"));
+    }
+
+    #[test]
+    fn schema_version_is_captured_from_the_payload() {
+        let config = config("[schema_version]\nfields = [\"schema\"]");
+        let records = parse("sensors/d1", r#"{"device_id": "d1", "schema": "v2", "temp": 21.5}"#, &config);
+
+        let readings = readings(&records);
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].schema_version.as_deref(), Some("v2"));
+        let socket_read = records.iter().find_map(|record| match record {
+            ParsedMessage::SocketRead(read) => Some(read),
+            _ => None,
+        });
+        assert_eq!(socket_read.unwrap().schema_version.as_deref(), Some("v2"));
+    }
+
+    #[test]
+    fn telemetry_envelope_data_array_produces_a_reading() {
+        let config = config("[[reading_arrays]]\npath = \"telemetry.data\"");
+        let payload = r#"{"device_id": "d1", "telemetry": {"data": [{"key": "temp", "value": 21.5}]}}"#;
+        let records = parse("sensors/d1", payload, &config);
+
+        let readings: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.topic.as_str(), reading.value))
+            .collect();
+        assert_eq!(readings, [("sensors/d1/temp", 21.5)]);
+    }
+
+    #[test]
+    fn int_typed_reading_fills_the_integer_column() {
+        let typed = |value: &str| {
+            let payload = format!(r#"{{"device_id": "d1", "value": {}, "type": "int"}}"#, value);
+            let records = parse("sensors/d1/count", &payload, &ParserConfig::default());
+            readings(&records).iter().map(|reading| (reading.value, reading.int_value)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(typed("42"), [(42.0, Some(42))]);
+        assert!(typed("4.2").is_empty());
+    }
+
+    #[test]
+    fn oversized_sensors_array_is_truncated() {
+        let sensors: Vec<String> = (0..5).map(|i| format!(r#"{{"name": "s{}", "value": {}}}"#, i, i)).collect();
+        let payload = format!(r#"{{"device_id": "d1", "sensors": [{}]}}"#, sensors.join(", "));
+        let before = COUNTERS.truncated_arrays.load(Ordering::Relaxed);
+
+        let records = parse("sensors/d1", &payload, &config("max_array_len = 3"));
+
+        let topics: Vec<_> = readings(&records).into_iter().map(|reading| reading.topic.as_str()).collect();
+        assert_eq!(topics, ["sensors/d1/s0", "sensors/d1/s1", "sensors/d1/s2"]);
+        assert!(COUNTERS.truncated_arrays.load(Ordering::Relaxed) > before);
+    }
+
+    #[test]
+    fn unit_suffix_is_split_from_the_key() {
+        let config = config(r#"unit_suffixes = ["hpa", "c"]"#);
+        let records = parse("sensors/d1", r#"{"device_id": "d1", "pressure_hpa": 1013.2, "temp_C": 21.5}"#, &config);
+
+        let readings: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.topic.as_str(), reading.unit.as_deref()))
+            .collect();
+        assert_eq!(readings, [("sensors/d1/pressure", Some("hpa")), ("sensors/d1/temp", Some("C"))]);
+    }
+
+    #[test]
+    fn unix_timestamp_units_are_detected_at_their_boundaries() {
+        let rfc3339 = |ts: i64| parse_unix_timestamp(ts).unwrap().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+
+        // Seconds up to year 2100, then milliseconds, then microseconds
+        assert_eq!(rfc3339(1_700_000_000), "2023-11-14T22:13:20.000000Z");
+        assert_eq!(rfc3339(MAX_UNIX_SECONDS), "2100-01-01T00:00:00.000000Z");
+        assert_eq!(rfc3339(1_700_000_000_123), "2023-11-14T22:13:20.123000Z");
+        assert_eq!(rfc3339(MAX_UNIX_SECONDS + 1), "1970-02-17T11:34:04.801000Z");
+        assert_eq!(rfc3339(MAX_UNIX_MILLISECONDS), "2100-01-01T00:00:00.000000Z");
+        assert_eq!(rfc3339(1_700_000_000_123_456), "2023-11-14T22:13:20.123456Z");
+        assert_eq!(rfc3339(MAX_UNIX_MILLISECONDS + 1), "1970-02-17T11:34:04.800001Z");
+
+        let records = parse("sensors/d1", r#"{"device_id": "d1", "temp": 21.5, "ts": 1700000000123}"#, &config(""));
+        assert_eq!(readings(&records)[0].timestamp.timestamp_millis(), 1_700_000_000_123);
+    }
+
+    #[test]
+    fn invalid_utf8_becomes_a_parse_error_record() {
+        let records = parse_message(
+            "sensors/d1",
+            &[0xff, 0xfe, 0x41],
+            0,
+            false,
+            &config(""),
+            &mut ParseContext::default(),
+        )
+        .unwrap();
+
+        let errors: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::ParseError(error) => Some((error.topic.as_str(), error.payload.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, [("sensors/d1", "fffe41")]);
+
+        // Valid UTF-8 that is not JSON is still kept as a raw socket read
+        let records = parse("sensors/d1", "not json", &config(""));
+        assert!(records.iter().any(|record| matches!(record, ParsedMessage::SocketRead(_))));
+        assert!(!records.iter().any(|record| matches!(record, ParsedMessage::ParseError(_))));
+    }
+
+    #[test]
+    fn certificate_common_name_wins_over_topic_and_payload() {
+        let payload = br#"{"device_id": "from-payload", "temp": 21.5}"#;
+        let mut ctx = ParseContext {
+            cert_cn: Some("from-cert"),
+            ..Default::default()
+        };
+        let records = parse_message("sensors/from-topic", payload, 0, false, &config(""), &mut ctx).unwrap();
+
+        let device_ids: Vec<_> = readings(&records).iter().map(|reading| reading.device_id.as_str()).collect();
+        assert_eq!(device_ids, ["from-cert"]);
+    }
+
+    fn device_alerts(records: &[ParsedMessage]) -> Vec<&DeviceAlert> {
+        records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::DeviceAlert(alert) => Some(alert),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alert_value_object_keeps_threshold_and_current() {
+        let payload = r#"{"device_id": "d1", "alerts": [{"key": "overheat", "value": {"threshold": 80, "current": 92}}]}"#;
+        let records = parse("devices/d1/state", payload, &config(""));
+
+        let alerts = device_alerts(&records);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].alert_key, "overheat");
+        assert_eq!((alerts[0].threshold, alerts[0].current), (Some(80.0), Some(92.0)));
+        assert_eq!(alerts[0].alert_value, serde_json::json!({"threshold": 80, "current": 92}));
+    }
+
+    #[test]
+    fn excluded_keys_are_not_readings() {
+        let payload = r#"{"device_id": "d1", "temp": 21.5, "seq": 4, "uptime": 100, "firmware_version": 2, "battery": 80}"#;
+
+        let topics = |config: &ParserConfig| -> Vec<String> {
+            readings(&parse("sensors/d1", payload, config)).iter().map(|reading| reading.topic.clone()).collect()
+        };
+        assert_eq!(topics(&config("")), ["sensors/d1/battery", "sensors/d1/temp"]);
+
+        let config = config(r#"excluded_keys = ["battery", "seq"]"#);
+        assert_eq!(topics(&config), ["sensors/d1/firmware_version", "sensors/d1/temp", "sensors/d1/uptime"]);
+
+        let records = parse("sensors/d1", r#"{"device_id":"d1","ts":1700000000,"temp":21.5}"#, &config);
+        let values: Vec<_> = readings(&records).iter().map(|reading| (reading.topic.as_str(), reading.value)).collect();
+        assert_eq!(values, [("sensors/d1/temp", 21.5)]);
+    }
+
+    #[test]
+    fn percent_strings_are_percentages_or_fractions() {
+        let payload = r#"{"device_id": "d1", "battery": "82%"}"#;
+
+        let value = |config: &ParserConfig| readings(&parse("sensors/d1", payload, config))[0].value;
+        assert_eq!(value(&config("")), 82.0);
+        assert_eq!(value(&config(r#"percent_fractions = ["battery"]"#)), 0.82);
+    }
+
+    #[test]
+    fn denied_topic_produces_no_records() {
+        let config = config(
+            r#"
+            [topics]
+            include = ["telemetry/#"]
+            exclude = ["telemetry/+/debug"]
+            "#,
+        );
+        let payload = r#"{"device_id": "d1", "temp": 21.5}"#;
+
+        assert!(parse("telemetry/d1/debug", payload, &config).is_empty());
+        assert!(!readings(&parse("telemetry/d1/temp", payload, &config)).is_empty());
+    }
+
+    #[test]
+    fn socket_read_records_qos_and_retain_flag() {
+        let records = parse_message(
+            "sensors/d1",
+            br#"{"device_id": "d1", "temp": 21.5}"#,
+            1,
+            true,
+            &config(""),
+            &mut ParseContext::default(),
+        )
+        .unwrap();
+
+        let reads: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::SocketRead(read) => Some((read.qos, read.retained)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reads, [(1, true)]);
+    }
+
+    #[test]
+    fn unknown_health_fields_are_kept_as_raw_health() {
+        let health = serde_json::json!({"general": {"freeHeapSize": 1000, "cpuTempC": 48.5}, "radio": {"channel": 6}});
+        let payload = serde_json::json!({"device_id": "rtos-1", "main_state": 1, "health": health.to_string()});
+        let records = parse("devices/rtos-1/state", &payload.to_string(), &config(""));
+
+        let stored = records
+            .iter()
+            .find_map(|record| match record {
+                ParsedMessage::DeviceHealth(health) => Some(health),
+                _ => None,
+            })
+            .expect("payload carries health");
+        assert_eq!(stored.free_heap_size, Some(1000));
+        assert_eq!(stored.raw_health, Some(health));
+    }
+
+    #[test]
+    fn metric_pair_text_becomes_a_reading() {
+        let config = config(r#"metric_pair_delimiters = [":", "="]"#);
+
+        for payload in ["temp:22.5", "temp=22.5"] {
+            let records = parse("sensors/d1", payload, &config);
+            let readings: Vec<_> = readings(&records)
+                .into_iter()
+                .map(|reading| (reading.topic.as_str(), reading.value))
+                .collect();
+            assert_eq!(readings, [("sensors/d1/temp", 22.5)]);
+        }
+
+        // Without delimiters the text stays a plain log
+        assert!(readings(&parse("sensors/d1", "temp:22.5", &ParserConfig::default())).is_empty());
+    }
+
+    #[test]
+    fn json_error_dead_letter_carries_its_position() {
+        let json_errors = || METRICS.dead_letters.with_label_values(&["json_error"]).get();
+        let before = json_errors();
+
+        let payload = "{\n  \"device_id\": \"d1\",\n  \"temp\": ,\n}";
+        let records = parse("sensors/d1", payload, &config("[records]\ndead_letters = true"));
+
+        let reasons: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::DeadLetter(dead_letter) => Some(&dead_letter.reason),
+                _ => None,
+            })
+            .collect();
+        assert!(matches!(reasons.as_slice(), [DeadLetterReason::JsonError { line: 3, column: 11 }]), "{:?}", reasons);
+        assert!(json_errors() > before);
+    }
+
+    #[test]
+    fn alerts_object_produces_one_record_per_entry() {
+        let payload = r#"{"device_id": "d1", "main_state": 1, "alerts": {"overheat": true, "low_battery": 12}}"#;
+        let records = parse("devices/d1/state", payload, &config(""));
+
+        let alerts: Vec<_> = device_alerts(&records)
+            .into_iter()
+            .map(|alert| (alert.alert_key.as_str(), alert.alert_value.clone()))
+            .collect();
+        assert_eq!(
+            alerts,
+            [
+                ("low_battery", serde_json::json!(12)),
+                ("overheat", serde_json::json!(true)),
+            ]
+        );
+
+        let records = parse("devices/d1/state", r#"{"device_id": "d1", "main_state": 1, "alerts": {}}"#, &config(""));
+        assert!(device_alerts(&records).is_empty());
+    }
+
+    #[test]
+    fn numeric_strings_are_coerced_into_values() {
+        let payload = r#"{"device_id": "d1", "a": "25.5", "b": "-3", "c": "1e3", "d": "hello", "e": "2024-01-01"}"#;
+        let records = parse("sensors/d1", payload, &config(""));
+
+        let coerced: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.topic.as_str(), reading.value))
+            .collect();
+        assert_eq!(coerced, [("sensors/d1/a", 25.5), ("sensors/d1/b", -3.0), ("sensors/d1/c", 1000.0)]);
+
+        let values = |value: &str| -> Vec<f64> {
+            let payload = format!(r#"{{"device_id": "d1", "value": "{}"}}"#, value);
+            readings(&parse("sensors/d1", &payload, &config(""))).iter().map(|reading| reading.value).collect()
+        };
+        assert_eq!(values("1e3").first(), Some(&1000.0));
+        assert!(values("hello").is_empty());
+    }
+
+    #[test]
+    fn sensors_only_policy_drops_the_top_level_value() {
+        let payload = r#"{"device_id": "d1", "value": 1.0, "sensors": [{"name": "temp", "value": 21.5}]}"#;
+        let topics = |config: &ParserConfig| -> Vec<String> {
+            readings(&parse("sensors/d1", payload, config)).iter().map(|reading| reading.topic.clone()).collect()
+        };
+
+        assert!(topics(&config("")).contains(&"sensors/d1".to_string()));
+        assert_eq!(topics(&config(r#"value_and_sensors = "sensors_only""#)), ["sensors/d1/temp"]);
+    }
+
+    #[test]
+    fn infinite_value_produces_no_reading() {
+        // Each axis is finite, but the magnitude overflows to infinity
+        let payload = r#"{"device_id": "d1", "accel": {"x": 1e200, "y": 1e200, "z": 1e200}}"#;
+        let magnitude = |config: &ParserConfig| -> Vec<f64> {
+            readings(&parse("imu/d1", payload, config))
+                .iter()
+                .filter(|reading| reading.topic == "imu/d1/accel/magnitude")
+                .map(|reading| reading.value)
+                .collect()
+        };
+
+        assert_eq!(readings(&parse("imu/d1", payload, &config("vector_magnitude = true"))).len(), 3);
+        assert!(magnitude(&config("vector_magnitude = true")).is_empty());
+        assert_eq!(magnitude(&config("vector_magnitude = true\nallow_non_finite = true")), [f64::INFINITY]);
+    }
+
+    #[test]
+    fn device_id_pattern_takes_precedence() {
+        let config = config(r#"device_id_pattern = "v1/[^/]+/[^/]+/sensor/(?P<id>[^/]+)""#);
+        let records = parse("v1/plant/line3/sensor/ABCD1234", r#"{"device_id": "from-payload", "temp": 21.5}"#, &config);
+
+        let device_ids: Vec<_> = readings(&records).iter().map(|reading| reading.device_id.as_str()).collect();
+        assert_eq!(device_ids, ["ABCD1234"]);
+
+        // Topics the pattern does not match fall back to the payload
+        let records = parse("other/topic", r#"{"device_id": "from-payload", "temp": 21.5}"#, &config);
+        assert_eq!(readings(&records)[0].device_id, "from-payload");
+    }
+
+    #[test]
+    fn device_id_pattern_without_an_id_group_is_rejected() {
+        assert!(toml::from_str::<ParserConfig>(r#"device_id_pattern = "v1/(.+)""#).is_err());
+    }
+
+    #[test]
+    fn clock_offset_is_device_time_minus_receive_time() {
+        let received = chrono::DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z").unwrap().with_timezone(&Utc);
+
+        let ahead = serde_json::json!({"timestamp": "2024-01-01T12:00:42.5Z"});
+        assert_eq!(clock_offset_seconds(&ahead, received), Some(42.5));
+        let behind = serde_json::json!({"ts": 1704110390});
+        assert_eq!(clock_offset_seconds(&behind, received), Some(-10.0));
+        assert_eq!(clock_offset_seconds(&serde_json::json!({}), received), None);
+    }
+
+    #[test]
+    fn unit_is_captured_with_per_sensor_override() {
+        let units = |payload: &str| -> Vec<(String, Option<String>)> {
+            readings(&parse("sensors/d1", payload, &config("")))
+                .iter()
+                .map(|reading| (reading.topic.clone(), reading.unit.clone()))
+                .collect()
+        };
+        let unit = |topic: &str, unit: Option<&str>| (topic.to_string(), unit.map(str::to_string));
+
+        let single = units(r#"{"device_id": "d1", "value": 25.5, "units": "C"}"#);
+        assert_eq!(single.first(), Some(&unit("sensors/d1", Some("C"))));
+
+        let payload = r#"{"device_id": "d1", "unit": "C", "sensors": [{"name": "a", "value": 1}, {"name": "b", "value": 2, "unit": "F"}]}"#;
+        let sensors = units(payload);
+        assert_eq!(sensors, [unit("sensors/d1/a", Some("C")), unit("sensors/d1/b", Some("F"))]);
+
+        assert_eq!(units(r#"{"device_id": "d1", "temp": 21.5}"#), [unit("sensors/d1/temp", None)]);
+    }
+
+    #[test]
+    fn device_map_produces_readings_per_device_with_the_shared_ts() {
+        let config = config(r#"device_map_topics = ["gateway/+/rollup"]"#);
+        let payload = r#"{"ts": 1700000000, "a": {"temp": 20}, "b": {"temp": 21}}"#;
+        let records = parse("gateway/g1/rollup", payload, &config);
+
+        let readings: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.device_id.as_str(), reading.value, reading.timestamp.timestamp()))
+            .collect();
+        assert_eq!(readings, [("a", 20.0, 1_700_000_000), ("b", 21.0, 1_700_000_000)]);
+    }
+
+    #[test]
+    fn capabilities_produce_one_row_each() {
+        let records = parse("devices/x/hello", r#"{"device_id": "x", "capabilities": ["temp", "hum", "relay"]}"#, &config(""));
+
+        let capabilities: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::DeviceCapability(capability) => {
+                    Some((capability.device_id.as_str(), capability.capability.as_str()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(capabilities, [("x", "temp"), ("x", "hum"), ("x", "relay")]);
+    }
+
+    #[test]
+    fn over_threshold_reading_also_produces_an_alert() {
+        let alerts = |payload: &str, config: &ParserConfig| -> Vec<(String, Option<f64>, Option<f64>)> {
+            let records = parse("plant/d1/temperature", payload, config);
+            assert!(!readings(&records).is_empty());
+            device_alerts(&records)
+                .into_iter()
+                .map(|alert| (alert.alert_key.clone(), alert.threshold, alert.current))
+                .collect()
+        };
+        let breach = |key: &str, threshold: f64, current: f64| (key.to_string(), Some(threshold), Some(current));
+
+        let inline = config("inline_thresholds = true");
+        let over = alerts(r#"{"device_id": "d1", "value": 92, "max": 80}"#, &inline);
+        assert_eq!(over, [breach("above_max", 80.0, 92.0)]);
+        assert!(alerts(r#"{"device_id": "d1", "value": 72, "max": 80}"#, &inline).is_empty());
+
+        let configured = config(
+            r#"
+            [[thresholds]]
+            topic = "plant/+/temperature"
+            min = 5
+            "#,
+        );
+        assert_eq!(alerts(r#"{"device_id": "d1", "value": 2}"#, &configured), [breach("below_min", 5.0, 2.0)]);
+    }
+
+    #[test]
+    fn nested_objects_flatten_into_path_topics() {
+        let config = config("nested_readings = true");
+        let topics = |payload: &str| -> Vec<(String, f64)> {
+            readings(&parse("sensors/d1", payload, &config))
+                .iter()
+                .map(|reading| (reading.topic.clone(), reading.value))
+                .collect()
+        };
+        let reading = |topic: &str, value: f64| (topic.to_string(), value);
+
+        let two_levels = topics(r#"{"device_id": "d1", "env": {"temp": 25.5, "hum": 60}}"#);
+        assert_eq!(two_levels, [reading("sensors/d1/env/hum", 60.0), reading("sensors/d1/env/temp", 25.5)]);
+
+        // Excluded keys are skipped at every level
+        let payload = r#"{"device_id": "d1", "env": {"probes": [{"temp": 20}, {"temp": 21}], "room": {"seq": 9}}}"#;
+        assert_eq!(
+            topics(payload),
+            [reading("sensors/d1/env/probes/0/temp", 20.0), reading("sensors/d1/env/probes/1/temp", 21.0)]
+        );
+
+        let too_deep = format!(r#"{{"device_id": "d1", {}"temp": 1{}}}"#, r#""a": {"#.repeat(12), "}".repeat(12));
+        assert!(topics(&too_deep).is_empty());
+    }
+
+    #[test]
+    fn root_array_elements_are_parsed_independently() {
+        let payload = r#"[
+            {"device_id": "a", "temp": 1, "timestamp": 1700000000},
+            {"device_id": "b", "temp": 2, "timestamp": 1700000060},
+            {"device_id": "b", "main_state": 1}
+        ]"#;
+        let records = parse("sensors/batch", payload, &config(""));
+
+        let readings: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.device_id.as_str(), reading.value, reading.timestamp.timestamp()))
+            .collect();
+        assert_eq!(readings, [("a", 1.0, 1_700_000_000), ("b", 2.0, 1_700_000_060)]);
+        assert!(records.iter().any(|record| matches!(record, ParsedMessage::DeviceState(_))));
+        let socket_reads = records.iter().filter(|record| matches!(record, ParsedMessage::SocketRead(_))).count();
+        assert_eq!(socket_reads, 1);
+    }
+
+    #[test]
+    fn hdop_is_captured_as_accuracy() {
+        let config = config(r#"accuracy_fields = ["accuracy", "hdop"]"#);
+        let records = parse("gps/d1", r#"{"device_id": "d1", "lat": 52.1, "lon": 4.3, "hdop": 0.9}"#, &config);
+
+        let readings: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.topic.as_str(), reading.accuracy))
+            .collect();
+        assert_eq!(readings, [("gps/d1/lat", Some(0.9)), ("gps/d1/lon", Some(0.9))]);
+    }
+
+    #[test]
+    fn thingsboard_element_produces_a_reading_per_value() {
+        let payload = r#"[{"ts": 1700000000000, "values": {"temp": 21, "hum": 44}}]"#;
+        let records = parse("v1/devices/d1/telemetry", payload, &config(""));
+
+        let readings: Vec<_> = readings(&records)
+            .into_iter()
+            .map(|reading| (reading.topic.as_str(), reading.value, reading.timestamp.timestamp_millis()))
+            .collect();
+        assert_eq!(
+            readings,
+            [
+                ("v1/devices/d1/telemetry/hum", 44.0, 1_700_000_000_000),
+                ("v1/devices/d1/telemetry/temp", 21.0, 1_700_000_000_000),
+            ]
+        );
+    }
+
+    /// Whether the raw payload was kept, as a socket read or, when it is not UTF-8, as a parse error
+    fn keeps_raw_payload(records: &[ParsedMessage]) -> bool {
+        records
+            .iter()
+            .any(|record| matches!(record, ParsedMessage::SocketRead(_) | ParsedMessage::ParseError(_)))
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn random_bytes_never_panic(
+            topic in "[a-z0-9/+#$%?=]{0,40}",
+            payload in proptest::collection::vec(proptest::prelude::any::<u8>(), 0..512),
+        ) {
+            let records = parse_message(
+                &topic,
+                &payload,
+                0,
+                false,
+                &ParserConfig::default(),
+                &mut ParseContext::default(),
+            )
+            .unwrap();
+            proptest::prop_assert!(keeps_raw_payload(&records));
+        }
+
+        #[test]
+        fn truncated_json_with_huge_numbers_never_panics(
+            key in "[a-z_]{1,12}",
+            number in "-?[0-9]{1,400}(\\.[0-9]{0,20})?([eE]-?[0-9]{1,4})?",
+            cut in 0usize..600,
+        ) {
+            let document = format!(
+                r#"{{"device_id": "d1", "{}": {}, "sensors": [{{"name": "n", "value": {}}}]}}"#,
+                key, number, number
+            );
+            let payload = &document.as_bytes()[..cut.min(document.len())];
+
+            let records = parse_message(
+                "sensors/d1",
+                payload,
+                0,
+                false,
+                &ParserConfig::default(),
+                &mut ParseContext::default(),
+            )
+            .unwrap();
+            proptest::prop_assert!(keeps_raw_payload(&records));
+        }
+    }
 }
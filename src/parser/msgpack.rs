@@ -0,0 +1,70 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Decode a MessagePack map into a JSON value so the regular extractors can run on it.
+///
+/// Returns None unless the whole payload is a single MessagePack map with string
+/// keys; binary values are not representable in JSON and fail the decode.
+pub(super) fn decode(payload: &[u8]) -> Option<Value> {
+    let mut reader = payload;
+    let value = Value::deserialize(&mut rmp_serde::Deserializer::new(&mut reader)).ok()?;
+
+    // Trailing bytes mean this was not a MessagePack document after all
+    if !reader.is_empty() || !value.is_object() {
+        return None;
+    }
+
+    Some(value)
+}
+
+/// Standard base64 of a binary payload, for the raw socket_reads audit record
+pub(super) fn base64_encode(payload: &[u8]) -> String {
+    use base64::Engine;
+
+    base64::engine::general_purpose::STANDARD.encode(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::parser::{parse_message, ParsedMessage};
+
+    #[test]
+    fn map_payload_decodes_into_json() {
+        let payload = rmp_serde::to_vec_named(&json!({"device_id": "d1", "temp": 21.5})).unwrap();
+
+        assert_eq!(decode(&payload), Some(json!({"device_id": "d1", "temp": 21.5})));
+        assert_eq!(decode(&rmp_serde::to_vec(&[1, 2, 3]).unwrap()), None);
+    }
+
+    #[test]
+    fn msgpack_payload_produces_readings_and_a_base64_socket_read() {
+        let payload = rmp_serde::to_vec_named(&json!({"device_id": "d1", "temp": 21.5})).unwrap();
+        let records = parse_message(
+            "sensors/d1",
+            &payload,
+            0,
+            false,
+            &Default::default(),
+            &mut Default::default(),
+        )
+        .unwrap();
+
+        let readings: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::SensorReading(reading) => Some((reading.device_id.as_str(), reading.value)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(readings, [("d1", 21.5)]);
+
+        let raw = records.iter().find_map(|record| match record {
+            ParsedMessage::SocketRead(read) => Some(read.payload.as_str()),
+            _ => None,
+        });
+        assert_eq!(raw, Some(base64_encode(&payload).as_str()));
+    }
+}
@@ -0,0 +1,240 @@
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use serde_json::{Map, Value};
+
+use super::{extract_topic_timestamp, missing_device_id, plain_text_device_id, ParseContext};
+use crate::config::ParserConfig;
+use crate::db::DeviceLog;
+
+/// Parse a syslog line, either RFC 5424
+/// (`<165>1 2003-10-11T22:14:15.003Z host app 1234 ID47 [meta x="1"] message`) or
+/// RFC 3164 (`<34>Oct 11 22:14:15 host app: message`).
+///
+/// The severity in the priority becomes the log level and the hostname the
+/// device id, unless the client certificate names the device or the hostname is
+/// nil, in which case the topic does as for plain text logs. The app name and
+/// facility are kept as tags. RFC 3164 timestamps carry no year or zone and are
+/// read as UTC in the most recent year that puts them at most a day ahead.
+/// Returns None when the line does not start with a valid priority and header.
+pub(super) fn parse_syslog(topic: &str, text: &str, config: &ParserConfig, ctx: &ParseContext) -> Option<DeviceLog> {
+    let (priority, rest) = text.trim_end().strip_prefix('<')?.split_once('>')?;
+    if priority.is_empty() || priority.len() > 3 || !priority.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let priority: u8 = priority.parse().ok().filter(|p| *p <= 191)?;
+
+    let header = match rest.strip_prefix("1 ") {
+        Some(rest) => parse_rfc5424(rest)?,
+        None => parse_rfc3164(rest, Utc::now())?,
+    };
+
+    let mut tags = Map::new();
+    tags.insert("facility".to_string(), Value::from(priority >> 3));
+    if let Some(app) = header.app {
+        tags.insert("app".to_string(), Value::String(app.to_string()));
+    }
+
+    let device_id = ctx
+        .cert_cn
+        .or(header.hostname)
+        .map(str::to_string)
+        .or_else(|| plain_text_device_id(topic, config, ctx))
+        .or_else(|| missing_device_id(config))?;
+
+    Some(DeviceLog {
+        device_id,
+        level: severity_level(priority & 0x07).to_string(),
+        message: header.message.to_string(),
+        topic: topic.to_string(),
+        tags: Some(Value::Object(tags)),
+        retention_class: None,
+        schema_version: None,
+        topic_id: None,
+        device_class: None,
+        timestamp: header
+            .timestamp
+            .or_else(|| extract_topic_timestamp(topic, config))
+            .unwrap_or_else(Utc::now),
+    })
+}
+
+/// Fields after the priority; nil (`-`) fields are None
+struct Header<'a> {
+    timestamp: Option<DateTime<Utc>>,
+    hostname: Option<&'a str>,
+    app: Option<&'a str>,
+    message: &'a str,
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`, after the version
+fn parse_rfc5424(rest: &str) -> Option<Header<'_>> {
+    let mut fields = rest.splitn(6, ' ');
+    let timestamp = fields.next()?;
+    let hostname = fields.next()?;
+    let app = fields.next()?;
+    let _procid = fields.next()?;
+    let _msgid = fields.next()?;
+    let message = skip_structured_data(fields.next()?)?;
+
+    let timestamp = match timestamp {
+        "-" => None,
+        timestamp => Some(DateTime::parse_from_rfc3339(timestamp).ok()?.with_timezone(&Utc)),
+    };
+
+    Some(Header {
+        timestamp,
+        hostname: nil_to_none(hostname),
+        app: nil_to_none(app),
+        message: message.trim_start_matches('\u{feff}'),
+    })
+}
+
+/// Text following the structured data: `-` or one or more `[id param="value"]`
+/// elements, where values may contain escaped `"`, `\` and `]`
+fn skip_structured_data(text: &str) -> Option<&str> {
+    let rest = match text.strip_prefix('-') {
+        Some(rest) => rest,
+        None => {
+            let mut rest = text;
+            while let Some(element) = rest.strip_prefix('[') {
+                let mut in_value = false;
+                let mut escaped = false;
+                let end = element.char_indices().find_map(|(index, c)| {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' if in_value => escaped = true,
+                        '"' => in_value = !in_value,
+                        ']' if !in_value => return Some(index),
+                        _ => {}
+                    }
+                    None
+                })?;
+                rest = &element[end + 1..];
+            }
+            if rest.len() == text.len() {
+                return None;
+            }
+            rest
+        }
+    };
+
+    match rest {
+        "" => Some(""),
+        rest => rest.strip_prefix(' '),
+    }
+}
+
+/// `Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG`
+fn parse_rfc3164(rest: &str, now: DateTime<Utc>) -> Option<Header<'_>> {
+    // The day is space-padded ("Oct  1"), so the timestamp is a fixed 15 characters
+    let timestamp = parse_bsd_timestamp(rest.get(..15)?, now)?;
+    let after = rest.get(15..)?.strip_prefix(' ')?;
+    let (hostname, body) = after.split_once(' ').unwrap_or((after, ""));
+    if hostname.is_empty() {
+        return None;
+    }
+
+    // The tag is optional; without a colon the whole body is the message
+    let (app, message) = match body.split_once(": ") {
+        Some((tag, message)) if !tag.is_empty() && !tag.contains(' ') => {
+            (Some(tag.split_once('[').map_or(tag, |(app, _)| app)), message)
+        }
+        _ => (None, body),
+    };
+
+    Some(Header {
+        timestamp: Some(timestamp),
+        hostname: Some(hostname),
+        app,
+        message,
+    })
+}
+
+/// A year-less `Mmm dd hh:mm:ss` timestamp, in the latest year that is not
+/// more than a day ahead of `now` (allowing for device clocks running ahead)
+fn parse_bsd_timestamp(text: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let parse = |year: i32| {
+        NaiveDateTime::parse_from_str(&format!("{} {}", year, text), "%Y %b %e %H:%M:%S")
+            .ok()
+            .map(|timestamp| timestamp.and_utc())
+    };
+
+    let timestamp = parse(now.year())?;
+    if timestamp - now > chrono::Duration::days(1) {
+        parse(now.year() - 1)
+    } else {
+        Some(timestamp)
+    }
+}
+
+fn nil_to_none(field: &str) -> Option<&str> {
+    (field != "-" && !field.is_empty()).then_some(field)
+}
+
+/// Our log level for a syslog severity (0 emergency to 7 debug)
+fn severity_level(severity: u8) -> &'static str {
+    match severity {
+        0..=3 => "ERROR",
+        4 => "WARN",
+        5 | 6 => "INFO",
+        _ => "DEBUG",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc3164_line_is_parsed() {
+        let text = "<34>Oct 11 22:14:15 mymachine su[231]: 'su root' failed for lonvick on /dev/pts/8";
+        let log = parse_syslog("logs/gw", text, &ParserConfig::default(), &ParseContext::default()).unwrap();
+
+        assert_eq!((log.device_id.as_str(), log.level.as_str()), ("mymachine", "ERROR"));
+        assert_eq!(log.message, "'su root' failed for lonvick on /dev/pts/8");
+        assert_eq!(log.tags, Some(serde_json::json!({"facility": 4, "app": "su"})));
+        assert_eq!(log.timestamp.format("%m-%d %H:%M:%S").to_string(), "10-11 22:14:15");
+    }
+
+    #[test]
+    fn rfc5424_line_is_parsed() {
+        let text = concat!(
+            r#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 "#,
+            r#"[exampleSDID@32473 iut="3"] An application event"#
+        );
+        let log = parse_syslog("logs/gw", text, &ParserConfig::default(), &ParseContext::default()).unwrap();
+
+        assert_eq!((log.device_id.as_str(), log.level.as_str()), ("mymachine.example.com", "INFO"));
+        assert_eq!(log.message, "An application event");
+        assert_eq!(log.tags, Some(serde_json::json!({"facility": 20, "app": "evntslog"})));
+        assert_eq!(log.timestamp.to_rfc3339(), "2003-10-11T22:14:15.003+00:00");
+    }
+
+    #[test]
+    fn certificate_common_name_wins_over_the_hostname() {
+        let text = "<34>Oct 11 22:14:15 mymachine su[231]: 'su root' failed for lonvick on /dev/pts/8";
+        let ctx = ParseContext {
+            cert_cn: Some("from-cert"),
+            ..Default::default()
+        };
+        let log = parse_syslog("logs/gw", text, &ParserConfig::default(), &ctx).unwrap();
+
+        assert_eq!(log.device_id, "from-cert");
+    }
+
+    #[test]
+    fn bsd_timestamp_in_the_future_belongs_to_last_year() {
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+
+        let timestamp = parse_bsd_timestamp("Dec 31 23:59:59", now).unwrap();
+
+        assert_eq!(timestamp.to_rfc3339(), "2023-12-31T23:59:59+00:00");
+    }
+
+    #[test]
+    fn non_syslog_text_is_left_to_the_plain_text_parser() {
+        for text in ["pump started", "<x>Oct 11 22:14:15 host app: message", "<999>1 - - - - - -"] {
+            let log = parse_syslog("logs/gw", text, &ParserConfig::default(), &ParseContext::default());
+            assert!(log.is_none(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, XmlVersion};
+use serde_json::{Map, Value};
+
+/// An element being built while walking the document
+struct Element {
+    name: String,
+    fields: Map<String, Value>,
+    text: String,
+}
+
+/// Decode an XML document into a JSON value so the regular extractors can run on it.
+///
+/// The root element is unwrapped, child elements become object fields (repeated
+/// names become arrays), attributes become fields, and numeric text becomes numbers:
+/// `<reading><value>21.5</value></reading>` → `{"value": 21.5}`
+///
+/// Documents nesting elements deeper than `max_depth` are rejected.
+pub(super) fn decode(text: &str, max_depth: usize) -> Option<Value> {
+    if !text.trim_start().starts_with('<') {
+        return None;
+    }
+
+    let mut reader = Reader::from_str(text);
+    reader.config_mut().trim_text(true);
+
+    let mut stack: Vec<Element> = Vec::new();
+
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(start) => {
+                if stack.len() >= max_depth {
+                    return None;
+                }
+                stack.push(open_element(&start)?);
+            }
+            Event::Empty(start) => {
+                let element = open_element(&start)?;
+                if let Some(root) = close_element(&mut stack, element) {
+                    return Some(root);
+                }
+            }
+            Event::End(_) => {
+                let element = stack.pop()?;
+                if let Some(root) = close_element(&mut stack, element) {
+                    return Some(root);
+                }
+            }
+            Event::Text(content) => stack.last_mut()?.text.push_str(&content.xml10_content()),
+            Event::CData(content) => {
+                let content = content.xml10_content();
+                stack.last_mut()?.text.push_str(&content);
+            }
+            Event::GeneralRef(reference) => {
+                let resolved = if reference.is_char_ref() {
+                    reference.resolve_char_ref().ok()??.to_string()
+                } else {
+                    resolve_predefined_entity(&reference.xml10_content())?.to_string()
+                };
+                stack.last_mut()?.text.push_str(&resolved);
+            }
+            Event::Eof => return None,
+            _ => {}
+        }
+    }
+}
+
+fn open_element(start: &BytesStart) -> Option<Element> {
+    let name = start.local_name().into_inner().to_string();
+
+    let mut fields = Map::new();
+    for attribute in start.attributes() {
+        let attribute = attribute.ok()?;
+        let key = attribute.key.local_name().into_inner();
+        let value = attribute.normalized_value(XmlVersion::Implicit1_0).ok()?;
+        fields.insert(key.to_string(), scalar(&value));
+    }
+
+    Some(Element {
+        name,
+        fields,
+        text: String::new(),
+    })
+}
+
+/// Attach a finished element to its parent; returns the value once the root closes
+fn close_element(stack: &mut [Element], element: Element) -> Option<Value> {
+    let value = if element.fields.is_empty() {
+        scalar(&element.text)
+    } else {
+        Value::Object(element.fields)
+    };
+
+    let Some(parent) = stack.last_mut() else {
+        return Some(value);
+    };
+
+    match parent.fields.get_mut(&element.name) {
+        Some(Value::Array(items)) => items.push(value),
+        Some(existing) => {
+            let first = existing.take();
+            *existing = Value::Array(vec![first, value]);
+        }
+        None => {
+            parent.fields.insert(element.name, value);
+        }
+    }
+
+    None
+}
+
+/// Text content as a number when it parses as one, otherwise as a string
+fn scalar(text: &str) -> Value {
+    let text = text.trim();
+    if text.is_empty() {
+        return Value::Null;
+    }
+
+    text.parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+        .map(Value::Number)
+        .unwrap_or_else(|| Value::String(text.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_message, ParsedMessage};
+
+    #[test]
+    fn simple_reading_document_decodes_to_fields() {
+        let json = decode("<reading><value>21.5</value></reading>", 32).unwrap();
+
+        assert_eq!(json, serde_json::json!({"value": 21.5}));
+    }
+
+    #[test]
+    fn xml_reading_becomes_a_sensor_reading() {
+        let payload = b"<reading><device_id>d1</device_id><value>21.5</value></reading>";
+        let records = parse_message(
+            "sensors/d1/temp",
+            payload,
+            0,
+            false,
+            &Default::default(),
+            &mut Default::default(),
+        )
+        .unwrap();
+
+        let reading = records
+            .iter()
+            .find_map(|record| match record {
+                ParsedMessage::SensorReading(reading) if reading.topic == "sensors/d1/temp" => Some(reading),
+                _ => None,
+            })
+            .expect("payload produces a reading");
+        assert_eq!((reading.device_id.as_str(), reading.value), ("d1", 21.5));
+    }
+
+    #[test]
+    fn document_deeper_than_limit_is_rejected() {
+        assert!(decode("<a><b><c>1</c></b></a>", 2).is_none());
+    }
+}
@@ -0,0 +1,834 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+
+use chrono::{DateTime, Duration, Utc};
+use lru::LruCache;
+use tracing::{debug, info, warn};
+
+use crate::config::{HealthCounterConfig, MonotonicTimestampPolicy, SamplingPolicy, SamplingRule};
+use crate::db::{DeviceHealth, OfflineEvent, ReadingRollup, SensorReading};
+use crate::parser::ParsedMessage;
+use crate::stats::{self, COUNTERS};
+
+/// A user-defined transform applied to each record between parse and insert.
+/// Returning no records drops the input; returning several expands it.
+pub trait Stage: Send + Sync {
+    fn apply(&self, message: ParsedMessage) -> Vec<ParsedMessage>;
+}
+
+/// Ordered stages run by the writer on every record before it is buffered
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Self {
+        Self { stages }
+    }
+
+    /// Feed a record through every stage in order, each stage seeing the
+    /// output of the previous one
+    pub fn run(&self, message: ParsedMessage) -> Vec<ParsedMessage> {
+        let mut messages = vec![message];
+        for stage in &self.stages {
+            messages = messages.into_iter().flat_map(|message| stage.apply(message)).collect();
+            if messages.is_empty() {
+                break;
+            }
+        }
+        messages
+    }
+}
+
+/// Drops sensor readings according to per-topic sampling rules.
+///
+/// State is kept per `(device_id, topic)` series so that each series is
+/// downsampled independently across messages.
+pub struct Downsampler {
+    rules: Vec<SamplingRule>,
+    counters: HashMap<(String, String), u64>,
+    last_buckets: HashMap<(String, String), i64>,
+}
+
+impl Downsampler {
+    pub fn new(rules: Vec<SamplingRule>) -> Self {
+        Self {
+            rules,
+            counters: HashMap::new(),
+            last_buckets: HashMap::new(),
+        }
+    }
+
+    /// Remove readings that the sampling policy says should not be stored
+    pub fn apply(&mut self, records: &mut Vec<ParsedMessage>) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        records.retain(|record| match record {
+            ParsedMessage::SensorReading(reading) => self.keep(reading),
+            _ => true,
+        });
+    }
+
+    fn keep(&mut self, reading: &SensorReading) -> bool {
+        let Some(rule) = self.rules.iter().find(|r| r.topic.matches(&reading.topic)) else {
+            return true;
+        };
+
+        let key = (reading.device_id.clone(), reading.topic.clone());
+
+        match rule.policy {
+            SamplingPolicy::EveryNth { n } => {
+                let counter = self.counters.entry(key).or_insert(0);
+                let keep = counter.is_multiple_of(n.max(1));
+                *counter += 1;
+                keep
+            }
+            SamplingPolicy::TimeBucket { bucket_ms } => {
+                let bucket = reading.timestamp.timestamp_millis() / bucket_ms.max(1) as i64;
+                self.last_buckets.insert(key, bucket) != Some(bucket)
+            }
+        }
+    }
+}
+
+/// Converts delta-encoded DeviceHealth counters into running absolutes.
+///
+/// Totals are cached per `(device_id, field)` for the lifetime of the bridge.
+pub struct HealthDeltaAccumulator {
+    delta_fields: Vec<String>,
+    totals: HashMap<(String, String), i64>,
+}
+
+impl HealthDeltaAccumulator {
+    pub fn new(counters: &HashMap<String, HealthCounterConfig>) -> Self {
+        let mut delta_fields = Vec::new();
+        for (field, counter) in counters {
+            if !counter.delta {
+                continue;
+            }
+            if !COUNTER_FIELDS.contains(&field.as_str()) {
+                warn!("Ignoring unknown health counter field: {}", field);
+                continue;
+            }
+            delta_fields.push(field.clone());
+        }
+
+        Self {
+            delta_fields,
+            totals: HashMap::new(),
+        }
+    }
+
+    /// Replace delta counters with accumulated absolutes
+    pub fn apply(&mut self, records: &mut [ParsedMessage]) {
+        if self.delta_fields.is_empty() {
+            return;
+        }
+
+        for record in records.iter_mut() {
+            let ParsedMessage::DeviceHealth(health) = record else {
+                continue;
+            };
+
+            for field in &self.delta_fields {
+                let device_id = health.device_id.clone();
+                let Some(Some(delta)) = counter_field(health, field) else {
+                    continue;
+                };
+
+                let total = self.totals.entry((device_id, field.clone())).or_insert(0);
+                *total += i64::from(*delta);
+                *delta = (*total).clamp(i64::from(i32::MIN), i64::from(i32::MAX)) as i32;
+            }
+        }
+    }
+}
+
+/// Counter fields of DeviceHealth addressable by name
+const COUNTER_FIELDS: &[&str] = &[
+    "unexpected_reset_counter",
+    "wifi_connect_counter",
+    "cloud_connect_counter",
+];
+
+fn counter_field<'a>(health: &'a mut DeviceHealth, name: &str) -> Option<&'a mut Option<i32>> {
+    match name {
+        "unexpected_reset_counter" => Some(&mut health.unexpected_reset_counter),
+        "wifi_connect_counter" => Some(&mut health.wifi_connect_counter),
+        "cloud_connect_counter" => Some(&mut health.cloud_connect_counter),
+        _ => None,
+    }
+}
+
+/// Tracks when each device was last heard from and detects devices that
+/// went silent for longer than the offline timeout.
+///
+/// New devices only become active once they have kept reporting for the
+/// activation grace period, so provisioning flaps do not produce churn.
+pub struct PresenceTracker {
+    timeout: Duration,
+    grace: Duration,
+    last_seen: HashMap<String, DateTime<Utc>>,
+    /// Devices within their grace period: first and last report
+    pending: HashMap<String, (DateTime<Utc>, DateTime<Utc>)>,
+    offline: HashSet<String>,
+}
+
+impl PresenceTracker {
+    pub fn new(timeout: Duration, grace: Duration) -> Self {
+        Self {
+            timeout,
+            grace,
+            last_seen: HashMap::new(),
+            pending: HashMap::new(),
+            offline: HashSet::new(),
+        }
+    }
+
+    /// Record the devices present in a parsed message as seen at `now`
+    pub fn observe(&mut self, records: &[ParsedMessage], now: DateTime<Utc>) {
+        for device_id in records.iter().filter_map(|r| r.device_id()) {
+            if !self.last_seen.contains_key(device_id) && !self.activate(device_id, now) {
+                continue;
+            }
+            if self.offline.remove(device_id) {
+                info!("Device {} is back online", device_id);
+            }
+            self.last_seen.insert(device_id.to_string(), now);
+        }
+    }
+
+    /// Whether a device not yet tracked has reported for the whole grace period
+    fn activate(&mut self, device_id: &str, now: DateTime<Utc>) -> bool {
+        if self.grace <= Duration::zero() {
+            return true;
+        }
+
+        let (first_seen, last_seen) = self.pending.entry(device_id.to_string()).or_insert((now, now));
+        *last_seen = now;
+        if now - *first_seen < self.grace {
+            return false;
+        }
+
+        info!("Device {} is active after reporting since {}", device_id, first_seen);
+        self.pending.remove(device_id);
+        true
+    }
+
+    /// Emit one event per device that transitioned to offline since the last scan
+    pub fn scan(&mut self, now: DateTime<Utc>) -> Vec<OfflineEvent> {
+        let mut events = Vec::new();
+
+        // Forget new devices that went quiet before their grace period ended
+        let grace = self.grace;
+        self.pending.retain(|device_id, (_, last_seen)| {
+            let reporting = now - *last_seen <= grace;
+            if !reporting {
+                debug!("Device {} went silent during its activation grace period", device_id);
+            }
+            reporting
+        });
+
+        for (device_id, last_seen) in &self.last_seen {
+            if now - *last_seen > self.timeout && self.offline.insert(device_id.clone()) {
+                warn!("Device {} went offline (last seen {})", device_id, last_seen);
+                events.push(OfflineEvent {
+                    device_id: device_id.clone(),
+                    last_seen: *last_seen,
+                    tags: None,
+                    retention_class: None,
+                    timestamp: now,
+                });
+            }
+        }
+
+        events
+    }
+}
+
+/// Entries kept before stale topics are swept from the deduplicator
+const DEDUP_SWEEP_THRESHOLD: usize = 10_000;
+
+/// Drops byte-identical payloads repeated on the same topic within a window,
+/// before they are parsed.
+///
+/// Only the last accepted payload per topic is remembered, so a device spamming
+/// the same bytes gets one message through per window.
+pub struct PayloadDeduplicator {
+    window: Duration,
+    last_accepted: HashMap<String, (u64, DateTime<Utc>)>,
+}
+
+impl PayloadDeduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_accepted: HashMap::new(),
+        }
+    }
+
+    /// Whether this payload repeats the last one accepted on the topic within the window
+    pub fn is_duplicate(&mut self, topic: &str, payload: &[u8], now: DateTime<Utc>) -> bool {
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        if let Some((last_hash, accepted_at)) = self.last_accepted.get(topic) {
+            if *last_hash == hash && now - *accepted_at < self.window {
+                return true;
+            }
+        }
+
+        if self.last_accepted.len() >= DEDUP_SWEEP_THRESHOLD {
+            let window = self.window;
+            self.last_accepted.retain(|_, (_, accepted_at)| now - *accepted_at < window);
+        }
+
+        self.last_accepted.insert(topic.to_string(), (hash, now));
+        false
+    }
+}
+
+/// Recognizes retained messages redelivered with the same topic and payload,
+/// so their raw reads are stored once rather than on every reconnect.
+///
+/// Pairs are kept in an LRU of bounded size, keyed by a blake3 hash of the
+/// payload, and stop suppressing repeats once `ttl` has passed.
+pub struct RetainedDeduplicator {
+    ttl: Duration,
+    seen: LruCache<(String, blake3::Hash), DateTime<Utc>>,
+}
+
+impl RetainedDeduplicator {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            ttl,
+            seen: LruCache::new(NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN)),
+        }
+    }
+
+    /// Whether this topic and payload were already seen within the TTL. A repeat
+    /// does not extend the TTL, so a pair is stored again at least once per TTL.
+    pub fn is_duplicate(&mut self, topic: &str, payload: &[u8], now: DateTime<Utc>) -> bool {
+        let key = (topic.to_string(), blake3::hash(payload));
+        if let Some(first_seen) = self.seen.get(&key) {
+            if now - *first_seen < self.ttl {
+                return true;
+            }
+        }
+
+        self.seen.put(key, now);
+        false
+    }
+}
+
+/// Device ids announced by birth messages, keyed by the parent of the birth topic
+/// (e.g., "site/gw-1" for a birth on "site/gw-1/status").
+///
+/// Parents are kept in an LRU of bounded size, so a flood of birth topics
+/// forgets the least recently announced first.
+pub struct BirthCache {
+    device_ids: LruCache<String, String>,
+}
+
+impl BirthCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            device_ids: LruCache::new(NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN)),
+        }
+    }
+
+    /// Remember the device id announced under a parent topic; false when it was already known
+    pub fn announce(&mut self, parent: &str, device_id: &str) -> bool {
+        if self.device_ids.get(parent).map(String::as_str) == Some(device_id) {
+            return false;
+        }
+
+        self.device_ids.put(parent.to_string(), device_id.to_string());
+        true
+    }
+
+    /// Device id last announced under a parent topic
+    pub fn device_id(&self, parent: &str) -> Option<&str> {
+        self.device_ids.peek(parent).map(String::as_str)
+    }
+}
+
+/// Caps the number of distinct device ids accepted within a sliding window.
+///
+/// Protects table cardinality from bugs that mint a new device id per message:
+/// once the cap is reached, records from ids not seen within the window are
+/// dropped until older ids expire.
+pub struct DeviceCardinalityGuard {
+    max_devices: usize,
+    window: Duration,
+    last_seen: HashMap<String, DateTime<Utc>>,
+}
+
+impl DeviceCardinalityGuard {
+    pub fn new(max_devices: usize, window: Duration) -> Self {
+        Self {
+            max_devices,
+            window,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    /// Remove records from new device ids that would exceed the cap
+    pub fn apply(&mut self, records: &mut Vec<ParsedMessage>, now: DateTime<Utc>) {
+        let mut rejected = HashSet::new();
+
+        records.retain(|record| {
+            let Some(device_id) = record.device_id() else {
+                return true;
+            };
+            if rejected.contains(device_id) {
+                return false;
+            }
+
+            if !self.last_seen.contains_key(device_id) && self.last_seen.len() >= self.max_devices {
+                let window = self.window;
+                self.last_seen.retain(|_, seen| now - *seen < window);
+
+                if self.last_seen.len() >= self.max_devices {
+                    rejected.insert(device_id.to_string());
+                    return false;
+                }
+            }
+
+            self.last_seen.insert(device_id.to_string(), now);
+            true
+        });
+
+        for device_id in rejected {
+            let total = stats::increment(&COUNTERS.rejected_new_devices);
+            warn!(
+                "Dropped records from new device {}: {} distinct devices already seen within {}s ({} rejected so far)",
+                device_id,
+                self.max_devices,
+                self.window.num_seconds(),
+                total
+            );
+        }
+    }
+}
+
+/// Enforces strictly increasing timestamps per `(device_id, topic)` reading series.
+///
+/// Remembers the last accepted timestamp of each series for the lifetime of the
+/// bridge; readings at or before it are dropped or flagged per the policy.
+pub struct MonotonicTimestampFilter {
+    policy: MonotonicTimestampPolicy,
+    last_timestamps: HashMap<(String, String), DateTime<Utc>>,
+}
+
+impl MonotonicTimestampFilter {
+    pub fn new(policy: MonotonicTimestampPolicy) -> Self {
+        Self {
+            policy,
+            last_timestamps: HashMap::new(),
+        }
+    }
+
+    /// Drop or flag readings whose timestamp does not advance their series
+    pub fn apply(&mut self, records: &mut Vec<ParsedMessage>) {
+        records.retain_mut(|record| {
+            let ParsedMessage::SensorReading(reading) = record else {
+                return true;
+            };
+            if self.advance(reading) {
+                return true;
+            }
+
+            let total = stats::increment(&COUNTERS.out_of_order_readings);
+            debug!(
+                "Out-of-order reading for device {} on {} at {} ({} so far)",
+                reading.device_id, reading.topic, reading.timestamp, total
+            );
+
+            match self.policy {
+                MonotonicTimestampPolicy::Drop => false,
+                MonotonicTimestampPolicy::Flag => {
+                    let tags = reading.tags.get_or_insert_with(|| serde_json::json!({}));
+                    if let Some(tags) = tags.as_object_mut() {
+                        tags.insert("out_of_order".to_string(), serde_json::Value::Bool(true));
+                    }
+                    true
+                }
+            }
+        });
+    }
+
+    /// Record the reading's timestamp if it is newer than the series' last one
+    fn advance(&mut self, reading: &SensorReading) -> bool {
+        let key = (reading.device_id.clone(), reading.topic.clone());
+
+        match self.last_timestamps.get(&key) {
+            Some(last) if reading.timestamp <= *last => false,
+            _ => {
+                self.last_timestamps.insert(key, reading.timestamp);
+                true
+            }
+        }
+    }
+}
+
+/// Aggregates readings into fixed windows per `(device_id, topic)` series.
+///
+/// A window closes when a reading for a later window arrives or, for series that
+/// went quiet, once a full window has passed after its end. Readings for windows
+/// already closed are left out of the rollups.
+pub struct RollupAggregator {
+    window_secs: u32,
+    open: HashMap<(String, String), RollupWindow>,
+}
+
+struct RollupWindow {
+    start: DateTime<Utc>,
+    count: i64,
+    min: f64,
+    max: f64,
+    sum: f64,
+}
+
+impl RollupAggregator {
+    pub fn new(window_secs: u32) -> Self {
+        Self {
+            window_secs: window_secs.max(1),
+            open: HashMap::new(),
+        }
+    }
+
+    /// Fold readings into their windows, returning rollups of the windows they closed
+    pub fn observe(&mut self, records: &[ParsedMessage]) -> Vec<ReadingRollup> {
+        let mut closed = Vec::new();
+
+        for record in records {
+            let ParsedMessage::SensorReading(reading) = record else {
+                continue;
+            };
+            let Some(start) = self.window_start(reading.timestamp) else {
+                continue;
+            };
+            let key = (reading.device_id.clone(), reading.topic.clone());
+
+            match self.open.get_mut(&key) {
+                Some(window) if window.start == start => {
+                    window.count += 1;
+                    window.min = window.min.min(reading.value);
+                    window.max = window.max.max(reading.value);
+                    window.sum += reading.value;
+                }
+                Some(window) if window.start > start => {
+                    debug!(
+                        "Reading for device {} on {} at {} arrived after its window closed",
+                        reading.device_id, reading.topic, reading.timestamp
+                    );
+                }
+                _ => {
+                    let window = RollupWindow {
+                        start,
+                        count: 1,
+                        min: reading.value,
+                        max: reading.value,
+                        sum: reading.value,
+                    };
+                    if let Some(previous) = self.open.insert(key.clone(), window) {
+                        closed.push(self.rollup(key.0, key.1, previous));
+                    }
+                }
+            }
+        }
+
+        closed
+    }
+
+    /// Close windows that ended at least one window before `now`
+    pub fn flush(&mut self, now: DateTime<Utc>) -> Vec<ReadingRollup> {
+        let cutoff = now - Duration::seconds(2 * self.window_secs as i64);
+        let due: Vec<(String, String)> = self
+            .open
+            .iter()
+            .filter(|(_, window)| window.start <= cutoff)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        due.into_iter()
+            .filter_map(|key| {
+                let window = self.open.remove(&key)?;
+                Some(self.rollup(key.0, key.1, window))
+            })
+            .collect()
+    }
+
+    /// Close every open window, e.g. at shutdown
+    pub fn drain(&mut self) -> Vec<ReadingRollup> {
+        let open = std::mem::take(&mut self.open);
+        open.into_iter()
+            .map(|((device_id, topic), window)| self.rollup(device_id, topic, window))
+            .collect()
+    }
+
+    fn window_start(&self, timestamp: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let secs = timestamp.timestamp();
+        DateTime::from_timestamp(secs - secs.rem_euclid(self.window_secs as i64), 0)
+    }
+
+    fn rollup(&self, device_id: String, topic: String, window: RollupWindow) -> ReadingRollup {
+        ReadingRollup {
+            device_id,
+            topic,
+            window_secs: i32::try_from(self.window_secs).unwrap_or(i32::MAX),
+            count: window.count,
+            min: window.min,
+            max: window.max,
+            avg: window.sum / window.count as f64,
+            timestamp: window.start,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(device_id: &str, topic: &str, value: f64, timestamp: DateTime<Utc>) -> ParsedMessage {
+        ParsedMessage::SensorReading(SensorReading::new(device_id.to_string(), topic.to_string(), value, timestamp))
+    }
+
+    #[test]
+    fn every_nth_policy_keeps_one_of_ten() {
+        let rule: SamplingRule =
+            toml::from_str("topic = \"sensors/#\"\npolicy = \"every_nth\"\nn = 10").expect("valid sampling rule");
+        let mut downsampler = Downsampler::new(vec![rule]);
+        let now = Utc::now();
+        let mut records: Vec<_> = (0..10).map(|i| reading("d1", "sensors/temp", i as f64, now)).collect();
+
+        downsampler.apply(&mut records);
+
+        assert_eq!(records.len(), 1);
+    }
+
+    fn health(device_id: &str, unexpected_resets: i32) -> ParsedMessage {
+        let payload = format!(
+            r#"{{"device_id": "{}", "main_state": 1, "health": {{"general": {{"unexpectedResetCounter": {}}}}}}}"#,
+            device_id, unexpected_resets
+        );
+        crate::parser::parse_message(
+            "devices/state",
+            payload.as_bytes(),
+            0,
+            false,
+            &Default::default(),
+            &mut Default::default(),
+        )
+        .expect("health payload parses")
+        .into_iter()
+        .find(|record| matches!(record, ParsedMessage::DeviceHealth(_)))
+        .expect("payload carries health")
+    }
+
+    #[test]
+    fn delta_health_counters_accumulate_into_absolutes() {
+        let counters = HashMap::from([("unexpected_reset_counter".to_string(), HealthCounterConfig { delta: true })]);
+        let mut accumulator = HealthDeltaAccumulator::new(&counters);
+
+        let mut records = vec![health("d1", 2)];
+        accumulator.apply(&mut records);
+        let mut later = vec![health("d1", 3)];
+        accumulator.apply(&mut later);
+
+        let ParsedMessage::DeviceHealth(health) = &later[0] else {
+            panic!("expected a health record");
+        };
+        assert_eq!(health.unexpected_reset_counter, Some(5));
+    }
+
+    #[test]
+    fn stale_device_triggers_a_single_offline_event() {
+        let mut presence = PresenceTracker::new(Duration::seconds(60), Duration::zero());
+        let start = Utc::now();
+        presence.observe(&[reading("d1", "sensors/temp", 1.0, start)], start);
+
+        assert!(presence.scan(start + Duration::seconds(30)).is_empty());
+        let events = presence.scan(start + Duration::seconds(61));
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].device_id.as_str(), events[0].last_seen), ("d1", start));
+        assert!(presence.scan(start + Duration::seconds(120)).is_empty());
+    }
+
+    #[test]
+    fn rapid_identical_payloads_are_collapsed() {
+        let mut dedup = PayloadDeduplicator::new(Duration::milliseconds(500));
+        let start = Utc::now();
+        let kept = (0..5)
+            .filter(|i| !dedup.is_duplicate("sensors/d1", b"{\"temp\": 21.5}", start + Duration::milliseconds(i * 10)))
+            .count();
+
+        assert_eq!(kept, 1);
+        assert!(!dedup.is_duplicate("sensors/d2", b"{\"temp\": 21.5}", start));
+        assert!(!dedup.is_duplicate("sensors/d1", b"{\"temp\": 22.0}", start));
+        assert!(!dedup.is_duplicate("sensors/d1", b"{\"temp\": 22.0}", start + Duration::seconds(1)));
+    }
+
+    struct DropLogs;
+
+    impl Stage for DropLogs {
+        fn apply(&self, message: ParsedMessage) -> Vec<ParsedMessage> {
+            match message {
+                ParsedMessage::DeviceLog(_) => Vec::new(),
+                other => vec![other],
+            }
+        }
+    }
+
+    struct DuplicateReadings;
+
+    impl Stage for DuplicateReadings {
+        fn apply(&self, message: ParsedMessage) -> Vec<ParsedMessage> {
+            match message {
+                ParsedMessage::SensorReading(_) => vec![message.clone(), message],
+                other => vec![other],
+            }
+        }
+    }
+
+    #[test]
+    fn stages_drop_logs_and_duplicate_readings() {
+        let pipeline = Pipeline::new(vec![Box::new(DropLogs), Box::new(DuplicateReadings)]);
+        let log = crate::parser::parse_message(
+            "diagnostics/logs/d1",
+            br#"{"device_id": "d1", "level": "INFO", "message": "booted"}"#,
+            0,
+            false,
+            &Default::default(),
+            &mut Default::default(),
+        )
+        .unwrap()
+        .into_iter()
+        .find(|record| matches!(record, ParsedMessage::DeviceLog(_)))
+        .expect("payload carries a log");
+
+        assert!(pipeline.run(log).is_empty());
+        let readings = pipeline.run(reading("d1", "sensors/temp", 21.5, Utc::now()));
+        assert_eq!(readings.len(), 2);
+        assert!(readings.iter().all(|record| matches!(record, ParsedMessage::SensorReading(_))));
+    }
+
+    #[test]
+    fn new_device_beyond_the_cap_is_dropped() {
+        let mut guard = DeviceCardinalityGuard::new(2, Duration::minutes(10));
+        let now = Utc::now();
+        let mut records = vec![reading("d1", "sensors/temp", 1.0, now), reading("d2", "sensors/temp", 2.0, now)];
+        guard.apply(&mut records, now);
+        assert_eq!(records.len(), 2);
+
+        let mut records = vec![reading("d3", "sensors/temp", 3.0, now), reading("d1", "sensors/temp", 4.0, now)];
+        guard.apply(&mut records, now);
+        let devices: Vec<_> = records.iter().filter_map(|record| record.device_id()).collect();
+        assert_eq!(devices, ["d1"]);
+
+        // Once the known ids expire from the window, new ones are accepted again
+        let later = now + Duration::minutes(11);
+        let mut records = vec![reading("d3", "sensors/temp", 5.0, later)];
+        guard.apply(&mut records, later);
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn device_silent_within_grace_period_is_not_registered_active() {
+        let mut presence = PresenceTracker::new(Duration::seconds(60), Duration::seconds(30));
+        let start = Utc::now();
+        presence.observe(&[reading("flaky", "sensors/temp", 1.0, start)], start);
+
+        // It never became active, so it is never reported offline either
+        assert!(presence.scan(start + Duration::seconds(45)).is_empty());
+        assert!(presence.scan(start + Duration::minutes(10)).is_empty());
+        assert!(!presence.last_seen.contains_key("flaky"));
+
+        // A device that keeps reporting through the grace period becomes active
+        for offset in [0, 20, 40] {
+            let now = start + Duration::seconds(offset);
+            presence.observe(&[reading("steady", "sensors/temp", 1.0, now)], now);
+        }
+        assert!(presence.last_seen.contains_key("steady"));
+    }
+
+    #[test]
+    fn out_of_order_reading_is_dropped() {
+        let mut filter = MonotonicTimestampFilter::new(MonotonicTimestampPolicy::Drop);
+        let start = Utc::now();
+        let mut records = vec![
+            reading("d1", "sensors/temp", 1.0, start),
+            reading("d1", "sensors/temp", 2.0, start - Duration::seconds(5)),
+            reading("d2", "sensors/temp", 3.0, start - Duration::seconds(5)),
+            reading("d1", "sensors/temp", 4.0, start + Duration::seconds(5)),
+        ];
+
+        filter.apply(&mut records);
+
+        let values: Vec<f64> = records
+            .iter()
+            .filter_map(|record| match record {
+                ParsedMessage::SensorReading(reading) => Some(reading.value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(values, [1.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn out_of_order_reading_is_flagged() {
+        let mut filter = MonotonicTimestampFilter::new(MonotonicTimestampPolicy::Flag);
+        let start = Utc::now();
+        let mut records = vec![reading("d1", "sensors/temp", 1.0, start), reading("d1", "sensors/temp", 2.0, start)];
+
+        filter.apply(&mut records);
+
+        let ParsedMessage::SensorReading(repeated) = &records[1] else {
+            panic!("expected a reading");
+        };
+        assert_eq!(repeated.tags, Some(serde_json::json!({"out_of_order": true})));
+    }
+
+    #[test]
+    fn readings_in_one_minute_roll_up_into_one_row() {
+        let mut aggregator = RollupAggregator::new(60);
+        let minute = DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        let records: Vec<_> = [(5, 20.0), (20, 23.0), (45, 21.0), (59, 24.0)]
+            .into_iter()
+            .map(|(secs, value)| reading("d1", "sensors/temp", value, minute + Duration::seconds(secs)))
+            .collect();
+
+        assert!(aggregator.observe(&records).is_empty());
+        let rollups = aggregator.observe(&[reading("d1", "sensors/temp", 30.0, minute + Duration::seconds(60))]);
+
+        assert_eq!(rollups.len(), 1);
+        let rollup = &rollups[0];
+        assert_eq!((rollup.device_id.as_str(), rollup.topic.as_str()), ("d1", "sensors/temp"));
+        assert_eq!(rollup.timestamp, minute);
+        assert_eq!(rollup.count, 4);
+        assert_eq!((rollup.min, rollup.max, rollup.avg), (20.0, 24.0, 22.0));
+    }
+
+    #[test]
+    fn repeated_retained_message_is_suppressed_within_the_ttl() {
+        let mut dedup = RetainedDeduplicator::new(2, Duration::minutes(10));
+        let start = Utc::now();
+        let payload = b"{\"main_state\": 1}";
+
+        assert!(!dedup.is_duplicate("devices/d1/state", payload, start));
+        assert!(dedup.is_duplicate("devices/d1/state", payload, start + Duration::minutes(1)));
+        assert!(!dedup.is_duplicate("devices/d1/state", b"{\"main_state\": 2}", start));
+        assert!(!dedup.is_duplicate("devices/d1/state", payload, start + Duration::minutes(11)));
+
+        // The least recently used pair is forgotten once the cache is full
+        assert!(!dedup.is_duplicate("devices/d2/state", payload, start));
+        assert!(!dedup.is_duplicate("devices/d3/state", payload, start));
+        assert!(!dedup.is_duplicate("devices/d1/state", b"{\"main_state\": 2}", start));
+    }
+}
@@ -0,0 +1,169 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::config::ParserConfig;
+use crate::parser::{parse_message, ParseContext, ParsedMessage};
+use crate::pipeline::BirthCache;
+
+/// One captured MQTT message, stored as a line of JSON:
+/// `{"timestamp": "2024-01-01T00:00:00Z", "topic": "telemetry/d1", "payload": "{\"value\": 21.5}"}`,
+/// optionally with the delivery `qos` and `retained` flag
+#[derive(Debug, Deserialize)]
+struct CapturedMessage {
+    timestamp: DateTime<Utc>,
+    topic: String,
+    payload: String,
+    #[serde(default)]
+    qos: i16,
+    #[serde(default)]
+    retained: bool,
+}
+
+/// Totals from replaying a capture
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    /// Captured messages parsed
+    pub messages: u64,
+    /// Records handed to the sink
+    pub records: u64,
+    /// Lines that were not valid captured messages or failed to parse
+    pub skipped: u64,
+}
+
+/// Replay a JSON-lines capture into the sink, preserving the captured gaps between
+/// messages divided by `speed` (2.0 replays twice as fast as real time; infinity
+/// replays as fast as possible). Messages go through the parser but not the
+/// bridge's stateful filters, which depend on wall-clock arrival.
+pub async fn replay(
+    path: &Path,
+    speed: f64,
+    config: &ParserConfig,
+    sink: &mpsc::Sender<ParsedMessage>,
+) -> Result<ReplaySummary> {
+    if speed.is_nan() || speed <= 0.0 {
+        bail!("Replay speed must be positive, got {}", speed);
+    }
+
+    let file = File::open(path)
+        .await
+        .with_context(|| format!("Failed to open capture {}", path.display()))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let mut summary = ReplaySummary::default();
+    let mut origin: Option<(DateTime<Utc>, Instant)> = None;
+    let mut births = BirthCache::new(config.birth_cache_capacity);
+    let mut line_number = 0;
+
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .with_context(|| format!("Failed to read capture {}", path.display()))?
+    {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let captured: CapturedMessage = match serde_json::from_str(&line) {
+            Ok(captured) => captured,
+            Err(e) => {
+                warn!("Skipping capture line {}: {}", line_number, e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+
+        // Schedule against the first message so sleep overshoot does not accumulate
+        let (first_timestamp, started) = *origin.get_or_insert((captured.timestamp, Instant::now()));
+        if let Ok(offset) = (captured.timestamp - first_timestamp).to_std() {
+            let delay = Duration::try_from_secs_f64(offset.as_secs_f64() / speed).unwrap_or(Duration::MAX);
+            if let Some(deadline) = started.checked_add(delay) {
+                tokio::time::sleep_until(deadline).await;
+            }
+        }
+
+        match parse_message(
+            &captured.topic,
+            captured.payload.as_bytes(),
+            captured.qos,
+            captured.retained,
+            config,
+            &mut ParseContext {
+                births: Some(&mut births),
+                ..Default::default()
+            },
+        ) {
+            Ok(records) => {
+                summary.messages += 1;
+                for record in records {
+                    sink.send(record).await.with_context(|| "Replay sink closed")?;
+                    summary.records += 1;
+                }
+            }
+            Err(e) => {
+                debug!("Failed to parse capture line {}: {:#}", line_number, e);
+                summary.skipped += 1;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fast_replay_processes_every_message() {
+        let path = std::env::temp_dir().join(format!("desmo-replay-{}.jsonl", std::process::id()));
+        let captured = |timestamp: &str, device_id: &str, temp: f64| {
+            let payload = format!(r#"{{"device_id": "{}", "temp": {}}}"#, device_id, temp);
+            serde_json::json!({"timestamp": timestamp, "topic": "sensors/d1", "payload": payload}).to_string()
+        };
+        let capture = [
+            captured("2024-01-01T00:00:00Z", "d1", 21.5),
+            captured("2024-01-01T06:00:00Z", "d1", 22.0),
+            "not a captured message".to_string(),
+            captured("2024-01-02T00:00:00Z", "d2", 19.0),
+        ];
+        std::fs::write(&path, capture.join("\n")).unwrap();
+
+        let (sink, mut received) = mpsc::channel(16);
+        let summary = tokio::time::timeout(
+            Duration::from_secs(5),
+            replay(&path, f64::INFINITY, &ParserConfig::default(), &sink),
+        )
+        .await
+        .expect("replay at infinite speed does not wait")
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        drop(sink);
+
+        let mut records = Vec::new();
+        while let Some(record) = received.recv().await {
+            records.push(record);
+        }
+        assert_eq!(summary.messages, 3);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.records, records.len() as u64);
+        assert!(!records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn non_positive_speed_is_rejected() {
+        let (sink, _received) = mpsc::channel(1);
+        for speed in [0.0, -1.0, f64::NAN] {
+            assert!(replay(Path::new("unused.jsonl"), speed, &ParserConfig::default(), &sink).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::error;
+
+use crate::db::{self, Executor, ReconnectingClient};
+use crate::parser::ParsedMessage;
+use crate::pipeline::Pipeline;
+
+/// Records buffered between the MQTT loop and the sink task
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Destination for parsed records
+pub trait Sink: Send + Sync {
+    fn write(&self, message: &ParsedMessage) -> impl Future<Output = Result<()>> + Send;
+
+    fn write_batch(&self, messages: &[ParsedMessage]) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Writes records into their PostgreSQL tables, retrying once on a fresh
+/// connection if the connection drops mid-query
+pub struct PostgresSink {
+    client: Arc<ReconnectingClient>,
+}
+
+impl PostgresSink {
+    pub fn new(client: Arc<ReconnectingClient>) -> Self {
+        Self { client }
+    }
+}
+
+impl PostgresSink {
+    /// A connection from the pool, when inserts are pooled
+    async fn pooled(&self) -> Result<Option<deadpool_postgres::Object>> {
+        match self.client.pool() {
+            Some(pool) => Ok(Some(
+                pool.get()
+                    .await
+                    .with_context(|| "Failed to get a pooled database connection")?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Sink for PostgresSink {
+    async fn write(&self, message: &ParsedMessage) -> Result<()> {
+        if let Some(connection) = self.pooled().await? {
+            return insert_message(&connection, message).await;
+        }
+
+        self.client
+            .with_retry(|client| async move { insert_message(&*client, message).await })
+            .await
+    }
+
+    async fn write_batch(&self, messages: &[ParsedMessage]) -> Result<()> {
+        if let Some(connection) = self.pooled().await? {
+            db::insert_batch(&connection, messages).await?;
+            return Ok(());
+        }
+
+        self.client
+            .with_retry(|client| async move { db::insert_batch(&*client, messages).await })
+            .await?;
+        Ok(())
+    }
+}
+
+async fn insert_message(client: &impl Executor, message: &ParsedMessage) -> Result<()> {
+    match message {
+        ParsedMessage::SensorReading(reading) => reading.insert(client).await,
+        ParsedMessage::SocketRead(read) => read.insert(client).await,
+        ParsedMessage::DeviceLog(log) => log.insert(client).await,
+        ParsedMessage::DeviceState(state) => state.insert(client).await,
+        ParsedMessage::DeviceAlert(alert) => alert.insert(client).await,
+        ParsedMessage::DeviceHealth(health) => health.insert(client).await,
+        ParsedMessage::TaskHealth(task) => task.insert(client).await,
+        ParsedMessage::OfflineEvent(event) => event.insert(client).await,
+        ParsedMessage::FlattenedRow(row) => row.insert(client).await,
+        ParsedMessage::ParseError(error) => error.insert(client).await,
+        ParsedMessage::DeadLetter(letter) => letter.insert(client).await,
+        ParsedMessage::ReadingRollup(rollup) => rollup.insert(client).await,
+        ParsedMessage::DeviceCapability(capability) => capability.insert(client).await,
+    }
+}
+
+/// Prints each record as a line of JSON tagged with its `type`, for debugging
+/// the parser without a database
+pub struct StdoutJsonSink;
+
+impl Sink for StdoutJsonSink {
+    async fn write(&self, message: &ParsedMessage) -> Result<()> {
+        self.write_batch(std::slice::from_ref(message)).await
+    }
+
+    async fn write_batch(&self, messages: &[ParsedMessage]) -> Result<()> {
+        let mut lines = Vec::new();
+        for message in messages {
+            serde_json::to_writer(&mut lines, message).with_context(|| "Failed to encode record as JSON")?;
+            lines.push(b'\n');
+        }
+
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(&lines).await.with_context(|| "Failed to write records to stdout")?;
+        stdout.flush().await.with_context(|| "Failed to write records to stdout")?;
+
+        Ok(())
+    }
+}
+
+/// Writes records into a local SQLite database with the same tables as
+/// PostgreSQL, blocking the worker thread for the length of each transaction
+#[cfg(feature = "sqlite")]
+pub struct SqliteSink {
+    connection: std::sync::Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteSink {
+    pub fn open(path: &std::path::Path, flattened_tables: &[crate::config::FlattenedTable]) -> Result<Self> {
+        Ok(Self {
+            connection: std::sync::Mutex::new(db::sqlite::open(path, flattened_tables)?),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Sink for SqliteSink {
+    async fn write(&self, message: &ParsedMessage) -> Result<()> {
+        self.write_batch(std::slice::from_ref(message)).await
+    }
+
+    async fn write_batch(&self, messages: &[ParsedMessage]) -> Result<()> {
+        tokio::task::block_in_place(|| {
+            let mut connection = self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            db::sqlite::insert_batch(&mut connection, messages)
+        })?;
+        Ok(())
+    }
+}
+
+/// Discards every record, for benchmarking the parser
+pub struct NullSink;
+
+impl Sink for NullSink {
+    async fn write(&self, _message: &ParsedMessage) -> Result<()> {
+        Ok(())
+    }
+
+    async fn write_batch(&self, _messages: &[ParsedMessage]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Start a task writing records sent to the returned channel to the sink, after
+/// the transform pipeline, in batches of up to `batch_size` of whatever has
+/// arrived. Failed batches are retried record by record; the task exits once
+/// the sender is dropped. PostgreSQL goes through the writer task instead, which
+/// adds routing, fair batching and dead-lettering.
+pub fn spawn<S: Sink + 'static>(
+    sink: S,
+    batch_size: usize,
+    pipeline: Pipeline,
+) -> (mpsc::Sender<ParsedMessage>, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let batch_size = batch_size.max(1);
+
+    let task = tokio::spawn(async move {
+        let mut received = Vec::with_capacity(batch_size);
+        while rx.recv_many(&mut received, batch_size).await > 0 {
+            let batch: Vec<ParsedMessage> = received.drain(..).flat_map(|message| pipeline.run(message)).collect();
+            if let Err(e) = sink.write_batch(&batch).await {
+                error!("Failed to write batch, retrying records individually: {:#}", e);
+                for message in &batch {
+                    if let Err(e) = sink.write(message).await {
+                        error!("Failed to write record: {:#}", e);
+                    }
+                }
+            }
+        }
+    });
+
+    (tx, task)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::db::SensorReading;
+
+    /// Keeps the values written, failing any batch holding more than one record
+    #[derive(Default)]
+    struct RecordingSink {
+        written: Arc<Mutex<Vec<f64>>>,
+    }
+
+    impl Sink for RecordingSink {
+        async fn write(&self, message: &ParsedMessage) -> Result<()> {
+            if let ParsedMessage::SensorReading(reading) = message {
+                self.written.lock().unwrap().push(reading.value);
+            }
+            Ok(())
+        }
+
+        async fn write_batch(&self, messages: &[ParsedMessage]) -> Result<()> {
+            if messages.len() > 1 {
+                anyhow::bail!("batch of {} rejected", messages.len());
+            }
+            for message in messages {
+                self.write(message).await?;
+            }
+            Ok(())
+        }
+    }
+
+    fn reading(value: f64) -> ParsedMessage {
+        let reading = SensorReading::new("d1".to_string(), "sensors/d1/temp".to_string(), value, Utc::now());
+        ParsedMessage::SensorReading(reading)
+    }
+
+    #[tokio::test]
+    async fn records_reach_the_sink_even_when_a_batch_fails() {
+        let sink = RecordingSink::default();
+        let written = sink.written.clone();
+        let (tx, task) = spawn(sink, 10, Pipeline::new(Vec::new()));
+
+        for value in [1.0, 2.0, 3.0] {
+            tx.send(reading(value)).await.unwrap();
+        }
+        drop(tx);
+        task.await.unwrap();
+
+        assert_eq!(*written.lock().unwrap(), [1.0, 2.0, 3.0]);
+    }
+
+    #[tokio::test]
+    async fn null_sink_accepts_everything() {
+        NullSink.write(&reading(1.0)).await.unwrap();
+        NullSink.write_batch(&[reading(1.0), reading(2.0)]).await.unwrap();
+    }
+}
@@ -0,0 +1,25 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Process-wide counters for bridge diagnostics
+pub struct Counters {
+    pub slow_parses: AtomicU64,
+    pub rejected_deep_payloads: AtomicU64,
+    pub buffer_overflows: AtomicU64,
+    pub rejected_new_devices: AtomicU64,
+    pub truncated_arrays: AtomicU64,
+    pub out_of_order_readings: AtomicU64,
+}
+
+pub static COUNTERS: Counters = Counters {
+    slow_parses: AtomicU64::new(0),
+    rejected_deep_payloads: AtomicU64::new(0),
+    buffer_overflows: AtomicU64::new(0),
+    rejected_new_devices: AtomicU64::new(0),
+    truncated_arrays: AtomicU64::new(0),
+    out_of_order_readings: AtomicU64::new(0),
+};
+
+/// Increment a counter and return the new total
+pub fn increment(counter: &AtomicU64) -> u64 {
+    counter.fetch_add(1, Ordering::Relaxed) + 1
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+/// MQTT-style topic filter supporting `+` (single level) and `#` (multi-level) wildcards
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TopicFilter(String);
+
+impl TopicFilter {
+    /// Check whether a concrete topic matches this filter
+    pub fn matches(&self, topic: &str) -> bool {
+        let mut filter_levels = self.0.split('/');
+        let mut topic_levels = topic.split('/');
+
+        loop {
+            match (filter_levels.next(), topic_levels.next()) {
+                // `#` matches the parent level and everything below it
+                (Some("#"), _) => return true,
+                (Some("+"), Some(_)) => {}
+                (Some(filter), Some(level)) if filter == level => {}
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Topic levels matched by each wildcard, in order. `#` captures all
+    /// remaining levels joined by `/`. None when the topic does not match.
+    pub fn captures(&self, topic: &str) -> Option<Vec<String>> {
+        let filter_levels: Vec<&str> = self.0.split('/').collect();
+        let topic_levels: Vec<&str> = topic.split('/').collect();
+        let mut captures = Vec::new();
+
+        for (index, filter) in filter_levels.iter().enumerate() {
+            match (*filter, topic_levels.get(index)) {
+                ("#", _) => {
+                    captures.push(topic_levels.get(index..).unwrap_or_default().join("/"));
+                    return Some(captures);
+                }
+                ("+", Some(level)) => captures.push(level.to_string()),
+                (filter, Some(level)) if filter == *level => {}
+                _ => return None,
+            }
+        }
+
+        (filter_levels.len() == topic_levels.len()).then_some(captures)
+    }
+
+    /// Rewrite a matching topic into `template`, substituting `$1`, `$2`, ...
+    /// with the wildcard captures (e.g., "legacy/+/temp" → "sensors/$1/temperature")
+    pub fn rewrite(&self, topic: &str, template: &str) -> Option<String> {
+        let captures = self.captures(topic)?;
+
+        // Substitute from the highest index so `$1` does not clobber `$10`
+        let mut rewritten = template.to_string();
+        for (index, capture) in captures.iter().enumerate().rev() {
+            rewritten = rewritten.replace(&format!("${}", index + 1), capture);
+        }
+
+        Some(rewritten)
+    }
+}
+
+/// Topics accepted for parsing: those matching any `include` filter (every topic
+/// when the list is empty) and no `exclude` filter
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopicRules {
+    pub include: Vec<TopicFilter>,
+    pub exclude: Vec<TopicFilter>,
+}
+
+impl TopicRules {
+    pub fn allows(&self, topic: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|filter| filter.matches(topic)))
+            && !self.exclude.iter().any(|filter| filter.matches(topic))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_like_mqtt() {
+        let filter = TopicFilter("telemetry/+/debug".to_string());
+        assert!(filter.matches("telemetry/d1/debug"));
+        assert!(!filter.matches("telemetry/d1/temp"));
+        assert!(!filter.matches("telemetry/d1/debug/extra"));
+
+        let filter = TopicFilter("telemetry/#".to_string());
+        assert!(filter.matches("telemetry"));
+        assert!(filter.matches("telemetry/d1/temp"));
+        assert!(!filter.matches("status/d1"));
+    }
+
+    #[test]
+    fn excluded_topics_are_denied_within_included_ones() {
+        let rules: TopicRules = toml::from_str(
+            r#"
+            include = ["telemetry/#"]
+            exclude = ["telemetry/+/debug"]
+            "#,
+        )
+        .expect("valid topic rules");
+
+        assert!(rules.allows("telemetry/d1/temp"));
+        assert!(!rules.allows("telemetry/d1/debug"));
+        assert!(!rules.allows("status/d1"));
+        assert!(TopicRules::default().allows("status/d1"));
+    }
+}
@@ -0,0 +1,987 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+use crate::config::{DatabaseConfig, InsertMode, OverflowPolicy};
+use crate::db::{self, DbRouter, DeadLetter, DeadLetterReason, DeviceSnapshot, ReconnectingClient};
+use crate::metrics::METRICS;
+use crate::parser::ParsedMessage;
+use crate::pipeline::Pipeline;
+use crate::sink::{PostgresSink, Sink};
+use crate::stats::{self, COUNTERS};
+
+/// Records buffered between the MQTT loop and the writer task
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Dead letters held while earlier ones are still being written
+const DEAD_LETTER_CAPACITY: usize = 1024;
+
+/// How long a device's registry group is cached before it is looked up again
+const DEVICE_GROUP_TTL: Duration = Duration::from_secs(300);
+
+/// Part of a batch handed to an insert worker with the database it goes to,
+/// holding its batch's in-flight slot
+type WorkerBatch = (Arc<ReconnectingClient>, Vec<ParsedMessage>, Arc<InFlightSlot>);
+
+/// Buckets are kept per routed database (by index) and target table
+type BucketKey = (usize, String);
+
+/// How batches are written, from the database configuration
+#[derive(Clone, Copy)]
+struct WriteOptions {
+    insert_mode: InsertMode,
+    device_states_current: bool,
+    device_snapshots: bool,
+}
+
+/// A batch's share of its database's `max_in_flight`, waking the writer when
+/// it is released
+struct InFlightSlot {
+    permit: Option<OwnedSemaphorePermit>,
+    released: Arc<Notify>,
+}
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        // Release before waking, so the writer finds the slot free
+        drop(self.permit.take());
+        self.released.notify_one();
+    }
+}
+
+/// Take a free in-flight slot from the first database that has one, waiting
+/// for a batch to finish while all of them are busy
+async fn acquire_slot(routes: Vec<(usize, Arc<Semaphore>)>, released: Arc<Notify>) -> (usize, InFlightSlot) {
+    loop {
+        for (route, in_flight) in &routes {
+            if let Ok(permit) = in_flight.clone().try_acquire_owned() {
+                let slot = InFlightSlot {
+                    permit: Some(permit),
+                    released: released.clone(),
+                };
+                return (*route, slot);
+            }
+        }
+        released.notified().await;
+    }
+}
+
+/// How full the writer's buffer is, shared with the MQTT layer so it can hold
+/// back acks while the writer falls behind
+#[derive(Clone)]
+pub struct WriterLoad {
+    shared: Arc<LoadState>,
+}
+
+struct LoadState {
+    buffered: AtomicUsize,
+    max_buffered: usize,
+    /// Load below which the writer has capacity again
+    resume_below: f64,
+    /// Woken whenever records leave the buffer
+    drained: Notify,
+}
+
+impl WriterLoad {
+    /// A load for a buffer of `max_buffered` records, with capacity while the
+    /// load is below `resume_below`
+    pub fn new(max_buffered: usize, resume_below: f64) -> Self {
+        Self {
+            shared: Arc::new(LoadState {
+                buffered: AtomicUsize::new(0),
+                max_buffered: max_buffered.max(1),
+                resume_below,
+                drained: Notify::new(),
+            }),
+        }
+    }
+
+    /// Buffer fullness, from 0.0 (empty) to 1.0 (full)
+    pub fn load_level(&self) -> f64 {
+        let buffered = self.shared.buffered.load(Ordering::Relaxed);
+        (buffered as f64 / self.shared.max_buffered as f64).min(1.0)
+    }
+
+    /// Wait until the load is below the resume level
+    pub async fn wait_for_capacity(&self) {
+        loop {
+            // Registered before the check so a flush in between still wakes it
+            let drained = self.shared.drained.notified();
+            if self.load_level() < self.shared.resume_below {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    fn set_buffered(&self, buffered: usize) {
+        if self.shared.buffered.swap(buffered, Ordering::Relaxed) > buffered {
+            self.shared.drained.notify_waiters();
+        }
+    }
+}
+
+/// Start the writer task on its own database connections. Records sent to the
+/// returned channel pass through the transform pipeline, then are routed to their
+/// database and grouped by target table and flushed in batches, with at
+/// most `max_in_flight` batches pipelined per database and at most `max_buffered`
+/// records held back; the task flushes everything and exits once the sender is
+/// dropped. With `ordered_device_workers` set, each device's records are
+/// written by a single worker in the order they were queued. In staging mode,
+/// sensor readings are copied into the staging table and moved into
+/// sensor_readings every `staging_flush_interval_ms` and on exit. The number
+/// of buffered records is published to `load`.
+pub fn spawn(
+    router: DbRouter,
+    config: &DatabaseConfig,
+    pipeline: Pipeline,
+    load: WriterLoad,
+) -> (mpsc::Sender<ParsedMessage>, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let max_in_flight = config.max_in_flight.max(1);
+    let options = WriteOptions {
+        insert_mode: config.insert_mode,
+        device_states_current: config.device_states_current,
+        device_snapshots: config.device_snapshots,
+    };
+
+    let workers = match config.ordered_device_workers {
+        Some(count) => (0..count.max(1))
+            .map(|_| {
+                let (worker_tx, mut worker_rx) = mpsc::channel::<WorkerBatch>(max_in_flight);
+                tokio::spawn(async move {
+                    while let Some((client, batch, slot)) = worker_rx.recv().await {
+                        write_batch(&client, &batch, options).await;
+                        drop(slot);
+                    }
+                });
+                worker_tx
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let writer = Writer {
+        router,
+        rx,
+        pipeline,
+        buckets: HashMap::new(),
+        buffered: 0,
+        max_buffered: config.max_buffered.max(1),
+        overflow_policy: config.overflow_policy,
+        next_seq: 0,
+        batch_size: config.batch_size.max(1),
+        batch_interval: Duration::from_millis(config.batch_interval_ms.max(1)),
+        flush_all: false,
+        released: Arc::new(Notify::new()),
+        max_in_flight,
+        dead_letters: DeadLetterQueue::new(DEAD_LETTER_CAPACITY),
+        dead_letter_flush: None,
+        class_tables: HashSet::new(),
+        topic_hierarchy: config.topic_hierarchy,
+        topic_ids: HashMap::new(),
+        device_groups: config.device_groups,
+        group_ids: HashMap::new(),
+        workers,
+        options,
+        staging_flush_interval: Duration::from_millis(config.staging_flush_interval_ms.max(1)),
+        load,
+    };
+
+    (tx, tokio::spawn(writer.run()))
+}
+
+struct Writer {
+    router: DbRouter,
+    rx: mpsc::Receiver<ParsedMessage>,
+    pipeline: Pipeline,
+    /// Pending records per database and target table, each served round-robin across devices
+    buckets: HashMap<BucketKey, FairQueue<ParsedMessage>>,
+    /// Records across all buckets
+    buffered: usize,
+    max_buffered: usize,
+    overflow_policy: OverflowPolicy,
+    /// Arrival order, used to find the oldest record when evicting
+    next_seq: u64,
+    batch_size: usize,
+    batch_interval: Duration,
+    /// Set when the interval fires; cleared once every bucket has been flushed
+    flush_all: bool,
+    /// Woken whenever a batch releases its database's in-flight slot
+    released: Arc<Notify>,
+    max_in_flight: usize,
+    /// Records dead-lettered on a full buffer, written as one batch per database
+    dead_letters: DeadLetterQueue,
+    /// The dead-letter batch being written, if any
+    dead_letter_flush: Option<JoinHandle<()>>,
+    class_tables: HashSet<BucketKey>,
+    topic_hierarchy: bool,
+    /// Leaf ids of topics already interned into each database's topics hierarchy
+    topic_ids: HashMap<(usize, String), i64>,
+    device_groups: bool,
+    /// Registry group per database and device (None when unregistered) and when
+    /// it was looked up
+    group_ids: HashMap<(usize, String), (Option<String>, Instant)>,
+    /// Sequential insert workers that devices are hashed to; batches are
+    /// written as independent tasks when empty
+    workers: Vec<mpsc::Sender<WorkerBatch>>,
+    options: WriteOptions,
+    staging_flush_interval: Duration,
+    load: WriterLoad,
+}
+
+impl Writer {
+    async fn run(mut self) {
+        let mut interval = tokio::time::interval(self.batch_interval);
+        let mut staging_interval = tokio::time::interval(self.staging_flush_interval);
+        let staging = self.options.insert_mode == InsertMode::Staging;
+
+        loop {
+            let due = self.due_routes();
+            let has_due = !due.is_empty();
+            tokio::select! {
+                // Under the block policy, a full buffer leaves records in the channel
+                message = self.rx.recv(), if self.accepting() => match message {
+                    Some(message) => {
+                        for message in self.pipeline.run(message) {
+                            self.enqueue(message);
+                        }
+                    }
+                    None => break,
+                },
+                _ = interval.tick() => self.flush_all = true,
+                _ = staging_interval.tick(), if staging => {
+                    for client in self.router.clients() {
+                        let client = client.clone();
+                        tokio::spawn(async move { flush_staging(&client).await });
+                    }
+                }
+                (route, slot) = acquire_slot(due, self.released.clone()), if has_due => {
+                    if let Some(key) = self.next_flush(route) {
+                        self.flush(key, slot).await;
+                    }
+                }
+            }
+            self.flush_dead_letters();
+            self.load.set_buffered(self.buffered);
+        }
+
+        // Flush whatever is still pending, then wait for outstanding batches
+        self.flush_all = true;
+        loop {
+            let due = self.due_routes();
+            if due.is_empty() {
+                break;
+            }
+            let (route, slot) = acquire_slot(due, self.released.clone()).await;
+            if let Some(key) = self.next_flush(route) {
+                self.flush(key, slot).await;
+            }
+        }
+        self.load.set_buffered(self.buffered);
+        self.finish_dead_letters().await;
+        for in_flight in self.router.in_flights() {
+            let _ = in_flight.acquire_many(self.max_in_flight as u32).await;
+        }
+
+        if staging {
+            for client in self.router.clients() {
+                flush_staging(client).await;
+            }
+        }
+    }
+
+    fn accepting(&self) -> bool {
+        self.buffered < self.max_buffered || self.overflow_policy != OverflowPolicy::Block
+    }
+
+    /// Queue a record in its database and table's bucket, behind earlier records from the same device,
+    /// applying the overflow policy when the buffer is full
+    fn enqueue(&mut self, message: ParsedMessage) {
+        if self.buffered >= self.max_buffered {
+            let total = stats::increment(&COUNTERS.buffer_overflows);
+            if total == 1 || total.is_multiple_of(1000) {
+                warn!(
+                    "Writer buffer full at {} records, applying {:?} ({} overflows so far)",
+                    self.buffered, self.overflow_policy, total
+                );
+            }
+
+            match self.overflow_policy {
+                OverflowPolicy::Block => {}
+                OverflowPolicy::DropOldest => {
+                    if let Some(evicted) = self.evict_oldest() {
+                        debug!("Evicted oldest buffered record: {:?}", evicted);
+                    }
+                }
+                OverflowPolicy::DropNewest => {
+                    debug!("Dropped record on full buffer: {:?}", message);
+                    return;
+                }
+                OverflowPolicy::DeadLetter => {
+                    let route = self.router.route(message.topic());
+                    if !self.dead_letters.push(route, &message, DeadLetterReason::BufferFull) {
+                        METRICS.dropped_dead_letters.inc();
+                        debug!("Dropped record on full dead-letter queue: {:?}", message);
+                    }
+                    return;
+                }
+            }
+        }
+
+        let table = match message.class_route() {
+            Some((base, class)) => db::class_table(base, Some(class)),
+            None => message.base_table().to_string(),
+        };
+        let route = self.router.route(message.topic());
+        let key = device_key(&message);
+
+        self.buckets
+            .entry((route, table))
+            .or_insert_with(FairQueue::new)
+            .push(key, self.next_seq, message);
+        self.next_seq += 1;
+        self.buffered += 1;
+    }
+
+    /// Write pending dead letters as one batch per database in the background,
+    /// unless the previous batch is still being written
+    fn flush_dead_letters(&mut self) {
+        if self.dead_letters.is_empty() || self.dead_letter_flush.as_ref().is_some_and(|flush| !flush.is_finished()) {
+            return;
+        }
+
+        let batches: Vec<_> = self
+            .dead_letters
+            .take_batches()
+            .into_iter()
+            .map(|(route, batch)| (self.router.client(route).clone(), batch))
+            .collect();
+        self.dead_letter_flush = Some(tokio::spawn(async move {
+            for (client, batch) in batches {
+                let batch = &batch;
+                if let Err(e) = client
+                    .with_retry(|client| async move { db::insert_batch(&*client, batch).await })
+                    .await
+                {
+                    error!("Failed to store {} dead letters: {:#}", batch.len(), e);
+                }
+            }
+        }));
+    }
+
+    /// Wait for the dead-letter batch being written, then write the rest
+    async fn finish_dead_letters(&mut self) {
+        if let Some(flush) = self.dead_letter_flush.take() {
+            let _ = flush.await;
+        }
+        self.flush_dead_letters();
+        if let Some(flush) = self.dead_letter_flush.take() {
+            let _ = flush.await;
+        }
+    }
+
+    /// Remove the earliest-arrived record across all buckets
+    fn evict_oldest(&mut self) -> Option<ParsedMessage> {
+        let evicted = evict_oldest(&mut self.buckets)?;
+        self.buffered -= 1;
+        Some(evicted)
+    }
+
+    /// Buckets due for a flush, largest first; clears `flush_all` once none are left
+    fn due_buckets(&mut self) -> Vec<BucketKey> {
+        let due = due_keys(&self.buckets, self.batch_size, self.flush_all);
+
+        if due.is_empty() {
+            self.flush_all = false;
+        }
+
+        due
+    }
+
+    /// Databases with a bucket due for a flush, with their in-flight slots,
+    /// ordered by their largest due bucket
+    fn due_routes(&mut self) -> Vec<(usize, Arc<Semaphore>)> {
+        let mut routes: Vec<(usize, Arc<Semaphore>)> = Vec::new();
+        for (route, _) in self.due_buckets() {
+            if !routes.iter().any(|(seen, _)| *seen == route) {
+                routes.push((route, self.router.in_flight(route).clone()));
+            }
+        }
+        routes
+    }
+
+    /// The largest bucket of a database due for a flush
+    fn next_flush(&mut self, route: usize) -> Option<BucketKey> {
+        self.due_buckets().into_iter().find(|(bucket_route, _)| *bucket_route == route)
+    }
+
+    /// Write up to one batch from a bucket in the background,
+    /// releasing its in-flight slot when done
+    async fn flush(&mut self, key: BucketKey, slot: InFlightSlot) {
+        let Some(bucket) = self.buckets.get_mut(&key) else {
+            return;
+        };
+
+        let mut batch = Vec::with_capacity(self.batch_size.min(bucket.len()));
+        while batch.len() < self.batch_size {
+            let Some(message) = bucket.pop() else {
+                break;
+            };
+            batch.push(message);
+        }
+        if bucket.is_empty() {
+            self.buckets.remove(&key);
+        }
+        self.buffered -= batch.len();
+
+        let (route, table) = &key;
+        let client = self.router.client(*route).clone();
+
+        // Create per-class tables the first time a class is seen. This runs inline
+        // so concurrent batches never race to create the same table.
+        if !self.class_tables.contains(&key) {
+            if let Some((base, class)) = batch.first().and_then(|message| message.class_route()) {
+                if let Err(e) = client
+                    .with_retry(|client| async move { db::ensure_class_table(&client, base, class).await })
+                    .await
+                {
+                    error!("Failed to insert batch into {}: {}", table, e);
+                    for message in &batch {
+                        store_dead_letter(&client, message, DeadLetterReason::InsertFailed(format!("{:#}", e))).await;
+                    }
+                    return;
+                }
+                self.class_tables.insert(key.clone());
+            }
+        }
+
+        if self.topic_hierarchy {
+            self.link_topics(*route, &mut batch).await;
+        }
+        if self.device_groups {
+            self.link_groups(*route, &mut batch).await;
+        }
+
+        debug!("Flushing {} records into {} on {}", batch.len(), table, self.router.name(*route));
+
+        if self.workers.is_empty() {
+            let options = self.options;
+            tokio::spawn(async move {
+                write_batch(&client, &batch, options).await;
+                drop(slot);
+            });
+            return;
+        }
+
+        // Split the batch by worker, keeping each device's records in order; the
+        // in-flight slot is released once every part has been written
+        let parts = partition_by_device(batch, self.workers.len());
+        let slot = Arc::new(slot);
+        for (worker, part) in self.workers.iter().zip(parts) {
+            if part.is_empty() {
+                continue;
+            }
+            let job = (client.clone(), part, slot.clone());
+            if let Err(mpsc::error::SendError((_, part, _))) = worker.send(job).await {
+                error!("Insert worker stopped, dropping {} records", part.len());
+            }
+        }
+    }
+
+    /// Point each record at the leaf of its topic in the topics hierarchy. A topic
+    /// that fails to intern leaves the reference empty rather than losing the record.
+    async fn link_topics(&mut self, route: usize, batch: &mut [ParsedMessage]) {
+        for message in batch {
+            let Some(topic) = message.topic().map(str::to_string) else {
+                continue;
+            };
+            let Some(topic_id) = message.topic_id_mut() else {
+                continue;
+            };
+
+            let key = (route, topic);
+            *topic_id = match self.topic_ids.get(&key) {
+                Some(&id) => Some(id),
+                None => match self
+                    .router
+                    .client(route)
+                    .with_retry(|client| {
+                        let topic = &key.1;
+                        async move { db::intern_topic(&client, topic).await }
+                    })
+                    .await
+                {
+                    Ok(id) => {
+                        self.topic_ids.insert(key, id);
+                        Some(id)
+                    }
+                    Err(e) => {
+                        warn!("{:#}", e);
+                        None
+                    }
+                },
+            };
+        }
+    }
+
+    /// Stamp records with their device's group from the device_groups registry.
+    /// A failed lookup leaves the group empty without caching the miss.
+    async fn link_groups(&mut self, route: usize, batch: &mut [ParsedMessage]) {
+        for message in batch {
+            let Some(device_id) = message.device_id().map(str::to_string) else {
+                continue;
+            };
+            let Some(group_id) = message.group_id_mut() else {
+                continue;
+            };
+
+            let key = (route, device_id);
+            *group_id = match self.group_ids.get(&key) {
+                Some((group, looked_up)) if looked_up.elapsed() < DEVICE_GROUP_TTL => group.clone(),
+                _ => match self
+                    .router
+                    .client(route)
+                    .with_retry(|client| {
+                        let device_id = &key.1;
+                        async move { db::lookup_device_group(&client, device_id).await }
+                    })
+                    .await
+                {
+                    Ok(group) => {
+                        self.group_ids.insert(key, (group.clone(), Instant::now()));
+                        group
+                    }
+                    Err(e) => {
+                        warn!("{:#}", e);
+                        None
+                    }
+                },
+            };
+        }
+    }
+}
+
+/// Buckets due for a flush, largest first: full buckets at any time, and
+/// every non-empty bucket once the interval has fired (`flush_all`)
+fn due_keys<T>(buckets: &HashMap<BucketKey, FairQueue<T>>, batch_size: usize, flush_all: bool) -> Vec<BucketKey> {
+    let mut due: Vec<(&BucketKey, usize)> = buckets
+        .iter()
+        .filter(|(_, bucket)| bucket.len() >= batch_size || (flush_all && !bucket.is_empty()))
+        .map(|(key, bucket)| (key, bucket.len()))
+        .collect();
+    due.sort_by(|(a_key, a), (b_key, b)| b.cmp(a).then_with(|| a_key.cmp(b_key)));
+    due.into_iter().map(|(key, _)| key.clone()).collect()
+}
+
+/// Remove the earliest-arrived item across all buckets, dropping its bucket when emptied
+fn evict_oldest<T>(buckets: &mut HashMap<BucketKey, FairQueue<T>>) -> Option<T> {
+    let key = buckets
+        .iter()
+        .filter_map(|(key, bucket)| Some((bucket.oldest_seq()?, key)))
+        .min()
+        .map(|(_, key)| key.clone())?;
+
+    let bucket = buckets.get_mut(&key)?;
+    let evicted = bucket.pop_oldest()?;
+    if bucket.is_empty() {
+        buckets.remove(&key);
+    }
+
+    Some(evicted)
+}
+
+/// Split a batch into one part per worker by a hash of each record's device,
+/// keeping each device's records in their original order
+fn partition_by_device(batch: Vec<ParsedMessage>, workers: usize) -> Vec<Vec<ParsedMessage>> {
+    let mut parts: Vec<Vec<ParsedMessage>> = vec![Vec::new(); workers];
+    for message in batch {
+        let mut hasher = DefaultHasher::new();
+        device_key(&message).hash(&mut hasher);
+        parts[(hasher.finish() % workers as u64) as usize].push(message);
+    }
+
+    parts
+}
+
+/// Key that a record is ordered and served fairly by: its device, or the topic
+/// for raw reads, which carry no device id but whose topic usually identifies the sender
+fn device_key(message: &ParsedMessage) -> String {
+    match message {
+        ParsedMessage::SocketRead(read) => read.topic.clone(),
+        other => other.device_id().unwrap_or_default().to_string(),
+    }
+}
+
+/// Insert a batch in one statement, or COPY it into the staging table when it
+/// holds only unclassed sensor readings in staging mode. One bad record fails
+/// the whole statement, so fall back to inserting one at a time into the durable
+/// tables to dead-letter only the records that are rejected. Device states then
+/// refresh device_states_current, and states and health device_snapshots, when
+/// enabled.
+async fn write_batch(client: &Arc<ReconnectingClient>, batch: &[ParsedMessage], options: WriteOptions) {
+    let staged = options.insert_mode == InsertMode::Staging
+        && batch
+            .iter()
+            .all(|message| matches!(message, ParsedMessage::SensorReading(reading) if reading.device_class.is_none()));
+    let sink = PostgresSink::new(client.clone());
+    let written = if staged {
+        client
+            .with_retry(|client| async move { db::copy_to_staging(&client, batch).await })
+            .await
+            .map(|_| ())
+    } else {
+        sink.write_batch(batch).await
+    };
+
+    if let Err(e) = written {
+        warn!("Batch insert failed, retrying records individually: {:#}", e);
+        for message in batch {
+            if let Err(e) = sink.write(message).await {
+                error!("Failed to insert message: {}", e);
+                store_dead_letter(client, message, DeadLetterReason::InsertFailed(format!("{:#}", e))).await;
+            }
+        }
+    }
+
+    if options.device_states_current {
+        for message in batch {
+            let ParsedMessage::DeviceState(state) = message else {
+                continue;
+            };
+            if let Err(e) = client
+                .with_retry(|client| async move { state.upsert_current(&client).await })
+                .await
+            {
+                error!("{:#}", e);
+            }
+        }
+    }
+
+    if options.device_snapshots {
+        for message in batch {
+            let snapshot = match message {
+                ParsedMessage::DeviceState(state) => DeviceSnapshot::from(state),
+                ParsedMessage::DeviceHealth(health) => DeviceSnapshot::from(health),
+                _ => continue,
+            };
+            let snapshot = &snapshot;
+            if let Err(e) = client
+                .with_retry(|client| async move { snapshot.upsert(&client).await })
+                .await
+            {
+                error!("{:#}", e);
+            }
+        }
+    }
+}
+
+/// Move staged sensor readings into sensor_readings, logging failures; rows
+/// left behind are moved on the next attempt
+async fn flush_staging(client: &ReconnectingClient) {
+    if let Err(e) = client
+        .with_retry(|client| async move { db::flush_staging(&client).await })
+        .await
+    {
+        error!("Failed to flush staged sensor readings: {:#}", e);
+    }
+}
+
+/// Keep a record that failed to insert in the dead_letters table
+async fn store_dead_letter(client: &ReconnectingClient, message: &ParsedMessage, reason: DeadLetterReason) {
+    let dead_letter = &dead_letter(message, reason);
+    if let Err(e) = client
+        .with_retry(|client| async move { dead_letter.insert(&*client).await })
+        .await
+    {
+        error!("Failed to store dead letter: {}", e);
+    }
+}
+
+/// A record kept as a dead letter under the given reason, as JSON tagged with its `type`
+fn dead_letter(message: &ParsedMessage, reason: DeadLetterReason) -> DeadLetter {
+    let record = serde_json::to_string(message).unwrap_or_else(|_| format!("{:?}", message));
+    DeadLetter::new(message.topic().map(|topic| topic.to_string()), record, reason)
+}
+
+/// Dead letters waiting to be written to their databases, refusing more once
+/// `capacity` are pending so an unreachable database cannot grow them unbounded
+struct DeadLetterQueue {
+    pending: Vec<(usize, ParsedMessage)>,
+    capacity: usize,
+}
+
+impl DeadLetterQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            capacity,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queue a record as a dead letter for a database; false when the queue is full
+    fn push(&mut self, route: usize, message: &ParsedMessage, reason: DeadLetterReason) -> bool {
+        if self.pending.len() >= self.capacity {
+            return false;
+        }
+        self.pending.push((route, ParsedMessage::DeadLetter(dead_letter(message, reason))));
+        true
+    }
+
+    /// Take every pending dead letter, grouped by database
+    fn take_batches(&mut self) -> HashMap<usize, Vec<ParsedMessage>> {
+        let mut batches: HashMap<usize, Vec<ParsedMessage>> = HashMap::new();
+        for (route, dead_letter) in self.pending.drain(..) {
+            batches.entry(route).or_default().push(dead_letter);
+        }
+        batches
+    }
+}
+
+/// Per-key FIFO queues served round-robin, one item per key per turn. Items
+/// carry their arrival sequence so the oldest can be evicted.
+struct FairQueue<T> {
+    queues: HashMap<String, VecDeque<(u64, T)>>,
+    /// Keys with pending items, in service order
+    order: VecDeque<String>,
+    len: usize,
+}
+
+impl<T> FairQueue<T> {
+    fn new() -> Self {
+        Self {
+            queues: HashMap::new(),
+            order: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, key: String, seq: u64, item: T) {
+        let queue = self.queues.entry(key.clone()).or_default();
+        if queue.is_empty() {
+            self.order.push_back(key);
+        }
+        queue.push_back((seq, item));
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let key = self.order.pop_front()?;
+        let queue = self.queues.get_mut(&key)?;
+        let (_, item) = queue.pop_front()?;
+
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        } else {
+            self.order.push_back(key);
+        }
+
+        self.len -= 1;
+        Some(item)
+    }
+
+    /// Arrival sequence of the oldest item, if any
+    fn oldest_seq(&self) -> Option<u64> {
+        self.queues.values().filter_map(|queue| queue.front()).map(|(seq, _)| *seq).min()
+    }
+
+    /// Remove the oldest item regardless of whose turn it is
+    fn pop_oldest(&mut self) -> Option<T> {
+        let key = self
+            .queues
+            .iter()
+            .filter_map(|(key, queue)| Some((queue.front()?.0, key)))
+            .min()
+            .map(|(_, key)| key.clone())?;
+
+        let queue = self.queues.get_mut(&key)?;
+        let (_, item) = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&key);
+            self.order.retain(|k| *k != key);
+        }
+
+        self.len -= 1;
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::db::SensorReading;
+
+    #[test]
+    fn flooding_device_does_not_starve_another() {
+        let mut queue = FairQueue::new();
+        for seq in 0..100 {
+            queue.push("busy".to_string(), seq, format!("busy-{}", seq));
+        }
+        queue.push("quiet".to_string(), 100, "quiet-0".to_string());
+        queue.push("quiet".to_string(), 101, "quiet-1".to_string());
+
+        let served: Vec<String> = (0..4).filter_map(|_| queue.pop()).collect();
+
+        assert_eq!(served, ["busy-0", "quiet-0", "busy-1", "quiet-1"]);
+        assert_eq!(queue.len(), 98);
+    }
+
+    #[tokio::test]
+    async fn busy_database_does_not_hold_back_another() {
+        let released = Arc::new(Notify::new());
+        let busy = Arc::new(Semaphore::new(1));
+        let idle = Arc::new(Semaphore::new(1));
+        let (_, held) = acquire_slot(vec![(0, busy.clone())], released.clone()).await;
+
+        let (route, _slot) = acquire_slot(vec![(0, busy.clone()), (1, idle.clone())], released.clone()).await;
+        assert_eq!(route, 1);
+
+        // With both databases at their cap, the next batch waits for a slot to be released
+        let waiting = tokio::spawn(acquire_slot(vec![(0, busy.clone()), (1, idle.clone())], released.clone()));
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        drop(held);
+        let (route, _) = tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        assert_eq!(route, 0);
+    }
+
+    #[test]
+    fn largest_bucket_flushes_first_when_interval_fires() {
+        let mut buckets = HashMap::new();
+        for (table, size) in [("device_logs", 1), ("sensor_readings", 3), ("socket_reads", 2)] {
+            let bucket = buckets.entry((0, table.to_string())).or_insert_with(FairQueue::new);
+            for seq in 0..size {
+                bucket.push("d1".to_string(), seq, ());
+            }
+        }
+        let tables = |due: Vec<BucketKey>| due.into_iter().map(|(_, table)| table).collect::<Vec<_>>();
+
+        assert_eq!(tables(due_keys(&buckets, 10, false)), Vec::<String>::new());
+        assert_eq!(tables(due_keys(&buckets, 10, true)), ["sensor_readings", "socket_reads", "device_logs"]);
+        assert_eq!(tables(due_keys(&buckets, 3, false)), ["sensor_readings"]);
+    }
+
+    #[test]
+    fn drop_oldest_evicts_the_earliest_buffered_record() {
+        let mut buckets: HashMap<BucketKey, FairQueue<&str>> = HashMap::new();
+        let records = [
+            ("sensor_readings", "d1", "first"),
+            ("device_logs", "d2", "second"),
+            ("sensor_readings", "d2", "third"),
+        ];
+        for (seq, (table, device, record)) in records.into_iter().enumerate() {
+            let bucket = buckets.entry((0, table.to_string())).or_insert_with(FairQueue::new);
+            bucket.push(device.to_string(), seq as u64, record);
+        }
+
+        assert_eq!(evict_oldest(&mut buckets), Some("first"));
+        assert_eq!(evict_oldest(&mut buckets), Some("second"));
+        assert!(!buckets.contains_key(&(0, "device_logs".to_string())));
+        assert_eq!(evict_oldest(&mut buckets), Some("third"));
+        assert!(buckets.is_empty());
+        assert_eq!(evict_oldest(&mut buckets), None);
+    }
+
+    #[test]
+    fn pending_dead_letters_stay_capped_while_none_are_written() {
+        let mut queue = DeadLetterQueue::new(2);
+
+        // With the database unreachable nothing is taken, so records past the cap are refused
+        let queued: Vec<bool> = (0..5)
+            .map(|i| queue.push(0, &reading("d1", i as f64), DeadLetterReason::BufferFull))
+            .collect();
+        assert_eq!(queued, [true, true, false, false, false]);
+
+        let batches = queue.take_batches();
+        assert_eq!(batches.len(), 1);
+        assert!(batches[&0].iter().all(|record| matches!(record, ParsedMessage::DeadLetter(_))));
+        assert_eq!(batches[&0].len(), 2);
+        assert!(queue.is_empty());
+        assert!(queue.push(0, &reading("d1", 5.0), DeadLetterReason::BufferFull));
+    }
+
+    #[test]
+    fn dead_letter_keeps_the_record_as_json() {
+        let dead_letter = dead_letter(&reading("d1", 21.5), DeadLetterReason::BufferFull);
+
+        let record: serde_json::Value = serde_json::from_str(&dead_letter.record).unwrap();
+        assert_eq!(record["type"], "sensor_reading");
+        assert_eq!((record["device_id"].as_str(), record["value"].as_f64()), (Some("d1"), Some(21.5)));
+        assert_eq!(dead_letter.topic.as_deref(), Some("sensors/temp"));
+    }
+
+    fn reading(device_id: &str, value: f64) -> ParsedMessage {
+        let reading = SensorReading::new(device_id.to_string(), "sensors/temp".to_string(), value, Utc::now());
+        ParsedMessage::SensorReading(reading)
+    }
+
+    #[test]
+    fn device_records_keep_submission_order_on_one_worker() {
+        let batch = vec![reading("d1", 1.0), reading("d2", 10.0), reading("d1", 2.0), reading("d2", 20.0)];
+
+        let parts = partition_by_device(batch, 4);
+
+        let devices: Vec<Vec<(&str, f64)>> = parts
+            .iter()
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                part.iter()
+                    .map(|message| match message {
+                        ParsedMessage::SensorReading(reading) => (reading.device_id.as_str(), reading.value),
+                        _ => unreachable!(),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // Each device lands on a single worker in submission order, apart from the other device
+        assert_eq!(devices.len(), 2);
+        assert!(devices.contains(&vec![("d1", 1.0), ("d1", 2.0)]));
+        assert!(devices.contains(&vec![("d2", 10.0), ("d2", 20.0)]));
+    }
+
+    #[tokio::test]
+    async fn load_rises_as_the_buffer_fills_and_capacity_returns_after_a_flush() {
+        let load = WriterLoad::new(10, 0.5);
+        assert_eq!(load.load_level(), 0.0);
+
+        load.set_buffered(4);
+        assert_eq!(load.load_level(), 0.4);
+        load.set_buffered(10);
+        assert_eq!(load.load_level(), 1.0);
+
+        let waiting = tokio::spawn({
+            let load = load.clone();
+            async move { load.wait_for_capacity().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        load.set_buffered(2);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("capacity returns once the buffer drains")
+            .unwrap();
+    }
+}